@@ -0,0 +1,19 @@
+#!/bin/sh
+# Cargo runner: boots the kernel given as $1 in QEMU, with a disk image
+# next to it in the target directory, made (zeroed) on first use: fs.img,
+# or for test binaries (under deps/) fs-test.img, which they format, so
+# `cargo test` never touches fs.img.
+set -e
+
+kernel=$1
+dir=$(dirname "$kernel")
+case $kernel in
+*/deps/*) image=${dir%/deps}/fs-test.img ;;
+*) image=$dir/fs.img ;;
+esac
+[ -e "$image" ] || truncate -s 4M "$image"
+
+exec qemu-system-riscv64 -machine virt -nographic -bios none \
+    -drive file="$image",if=none,format=raw,id=disk \
+    -device virtio-blk-device,drive=disk \
+    -kernel "$kernel"
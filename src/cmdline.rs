@@ -0,0 +1,326 @@
+//! Kernel command line, from the device tree's `/chosen/bootargs`
+//! (QEMU's `-append`): whitespace-separated `name=value` words, values
+//! double-quoted if they have spaces, e.g.
+//!
+//! ```text
+//! log.level=debug sched.quantum=10ms init="/bin/sh -l"
+//! ```
+//!
+//! Parameters are declared with [`param!`](crate::param) wherever
+//! they're used, and found at boot in the `.params` link section.
+
+use core::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use crate::{fdt, info, utils::sync::SeqLock, warn};
+
+/// Type a parameter can have.
+pub trait ParamValue: Copy + Send + Sync + 'static {
+    /// Parses a value off the command line, `""` for a bare `name`.
+    fn parse(value: &'static str) -> Option<Self>;
+
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result;
+}
+
+impl ParamValue for bool {
+    fn parse(value: &'static str) -> Option<Self> {
+        match value {
+            "" | "1" | "y" | "yes" | "on" | "true" => Some(true),
+            "0" | "n" | "no" | "off" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+macro_rules! int_param_value {
+    ($($ty:ty),*) => {$(
+        impl ParamValue for $ty {
+            fn parse(value: &'static str) -> Option<Self> {
+                match value.strip_prefix("0x") {
+                    Some(hex) => <$ty>::from_str_radix(hex, 16).ok(),
+                    None => value.parse().ok(),
+                }
+            }
+
+            fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                Display::fmt(self, f)
+            }
+        }
+    )*};
+}
+
+int_param_value!(u32, u64, usize);
+
+impl ParamValue for &'static str {
+    fn parse(value: &'static str) -> Option<Self> {
+        Some(value)
+    }
+
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+/// Written with a unit, as `<n>s`, `<n>ms`, `<n>us` or `<n>ns`.
+impl ParamValue for Duration {
+    fn parse(value: &'static str) -> Option<Self> {
+        // "s" last, as it's a suffix of the others
+        let units = [
+            ("ns", 1),
+            ("us", 1_000),
+            ("ms", 1_000_000),
+            ("s", 1_000_000_000),
+        ];
+        let (digits, nanos_per_unit) = units
+            .into_iter()
+            .find_map(|(unit, nanos)| Some((value.strip_suffix(unit)?, nanos)))?;
+        let nanos = digits.parse::<u64>().ok()?.checked_mul(nanos_per_unit)?;
+        Some(Duration::from_nanos(nanos))
+    }
+
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let nanos = self.as_nanos();
+        let (count, unit) = [(1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "us")]
+            .into_iter()
+            .find(|&(nanos_per_unit, _)| nanos.is_multiple_of(nanos_per_unit))
+            .map_or((nanos, "ns"), |(nanos_per_unit, unit)| {
+                (nanos / nanos_per_unit, unit)
+            });
+        f.write_fmt(format_args!("{}{}", count, unit))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    Unknown,
+    InvalidValue,
+}
+
+/// Parameter of any type, as registered.
+pub trait AnyParam: Sync {
+    fn name(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    /// Parses and sets the value, as if given on the command line.
+    fn set_from_str(&self, value: &'static str) -> Result<(), ParamError>;
+    fn fmt_value(&self, f: &mut Formatter<'_>) -> core::fmt::Result;
+    fn fmt_default(&self, f: &mut Formatter<'_>) -> core::fmt::Result;
+}
+
+/// Kernel parameter, declared with [`param!`](crate::param). Can also be
+/// changed at runtime, e.g. by tests.
+pub struct Param<T: ParamValue> {
+    name: &'static str,
+    help: &'static str,
+    default: T,
+    value: SeqLock<T>,
+}
+
+impl<T: ParamValue> Param<T> {
+    pub const fn new(name: &'static str, default: T, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            value: SeqLock::new(name, default),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        self.value.read()
+    }
+
+    pub fn set(&self, value: T) {
+        self.value.set(value);
+    }
+
+    pub fn reset(&self) {
+        self.set(self.default);
+    }
+}
+
+impl<T: ParamValue> AnyParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn set_from_str(&self, value: &'static str) -> Result<(), ParamError> {
+        self.set(T::parse(value).ok_or(ParamError::InvalidValue)?);
+        Ok(())
+    }
+
+    fn fmt_value(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        self.get().fmt(f)
+    }
+
+    fn fmt_default(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        self.default.fmt(f)
+    }
+}
+
+/// Declares a kernel parameter: a static [`Param`] named `$ident`, set
+/// with `$name=<value>` on the command line.
+///
+/// ```ignore
+/// param!(pub QUANTUM: Duration = Duration::from_secs(2), "sched.quantum", "Time slice");
+/// ```
+#[macro_export]
+macro_rules! param {
+    ($(#[$attr:meta])* $vis:vis $ident:ident: $ty:ty = $default:expr, $name:literal, $help:literal) => {
+        $(#[$attr])*
+        $vis static $ident: $crate::cmdline::Param<$ty> =
+            $crate::cmdline::Param::new($name, $default, $help);
+
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".params")]
+            static REGISTRATION: &dyn $crate::cmdline::AnyParam = &$ident;
+        };
+    };
+}
+
+unsafe extern "C" {
+    static __params_start: u8;
+    static __params_end: u8;
+}
+
+/// Every parameter declared with [`param!`](crate::param).
+pub fn params() -> &'static [&'static dyn AnyParam] {
+    let start = &raw const __params_start as *const &'static dyn AnyParam;
+    let end = &raw const __params_end as *const &'static dyn AnyParam;
+    // SAFETY: the linker script gathers the `.params` sections, which only
+    // `param!` puts anything in, between these symbols
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
+
+pub fn find(name: &str) -> Option<&'static dyn AnyParam> {
+    params().iter().copied().find(|param| param.name() == name)
+}
+
+/// Splits `args` into words at whitespace, except within double quotes.
+fn words(args: &'static str) -> impl Iterator<Item = &'static str> {
+    let mut rest = args;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (word, after) = rest.split_at(end);
+        rest = after;
+        Some(word)
+    })
+}
+
+/// Splits a word into its name and value, without the value's quotes.
+fn name_and_value(word: &'static str) -> (&'static str, &'static str) {
+    let (name, value) = word.split_once('=').unwrap_or((word, ""));
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    (name, value)
+}
+
+/// Sets `name` to `value`, parsed as its type.
+pub fn set(name: &str, value: &'static str) -> Result<(), ParamError> {
+    find(name).ok_or(ParamError::Unknown)?.set_from_str(value)
+}
+
+/// Sets the parameters given in `args`, warning about the rest.
+pub fn parse(args: &'static str) {
+    for (name, value) in words(args).map(name_and_value) {
+        match set(name, value) {
+            Ok(()) => {}
+            Err(ParamError::Unknown) => warn!("unknown parameter {:?}", name),
+            Err(ParamError::InvalidValue) => {
+                warn!("invalid value {:?} for parameter {}", value, name)
+            }
+        }
+    }
+}
+
+/// Sets the parameters given in the device tree's boot arguments.
+pub fn init() {
+    if let Some(args) = fdt::get().and_then(|fdt| fdt.bootargs()) {
+        parse(args);
+    }
+}
+
+/// Logs every parameter with its value.
+pub fn print_params() {
+    struct Value(&'static dyn AnyParam, bool);
+
+    impl Display for Value {
+        fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+            match self.1 {
+                true => self.0.fmt_default(f),
+                false => self.0.fmt_value(f),
+            }
+        }
+    }
+
+    for &param in params() {
+        info!(
+            "{}={} (default {}): {}",
+            param.name(),
+            Value(param, false),
+            Value(param, true),
+            param.help()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::param!(TEST_COUNT: u32 = 3, "test.count", "Parameter for tests");
+    crate::param!(TEST_NAME: &'static str = "", "test.name", "Parameter for tests");
+    crate::param!(TEST_FLAG: bool = false, "test.flag", "Parameter for tests");
+    crate::param!(TEST_DELAY: Duration = Duration::ZERO, "test.delay", "Parameter for tests");
+
+    #[test_case]
+    pub fn cmdline_parses_params() {
+        assert!(find("test.count").is_some(), "params should be registered");
+        assert!(find("log.level").is_some());
+
+        parse(r#"test.count=0x10 test.name="two words" test.flag  test.delay=15ms"#);
+        assert_eq!(TEST_COUNT.get(), 16);
+        assert_eq!(TEST_NAME.get(), "two words");
+        assert!(TEST_FLAG.get());
+        assert_eq!(TEST_DELAY.get(), Duration::from_millis(15));
+
+        assert_eq!(set("test.count", "many"), Err(ParamError::InvalidValue));
+        assert_eq!(set("test.delay", "15"), Err(ParamError::InvalidValue));
+        assert_eq!(set("test.nonexistent", "1"), Err(ParamError::Unknown));
+        assert_eq!(TEST_COUNT.get(), 16, "invalid values should be ignored");
+
+        find("test.flag").unwrap().set_from_str("off").unwrap();
+        assert!(!TEST_FLAG.get());
+
+        TEST_COUNT.reset();
+        assert_eq!(TEST_COUNT.get(), 3);
+        TEST_NAME.reset();
+        TEST_FLAG.reset();
+        TEST_DELAY.reset();
+    }
+}
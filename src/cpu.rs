@@ -0,0 +1,92 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::utils::cells::PerCpu;
+
+pub const MAX_HARTS: usize = 8;
+
+/// Hart-local bookkeeping, akin to xv6's `struct cpu`.
+#[derive(Debug)]
+pub struct Cpu {
+    /// Depth of [`crate::irq::push_off`] nesting.
+    noff: AtomicUsize,
+    /// Were interrupts enabled before the outermost `push_off`?
+    intena: AtomicBool,
+    /// Depth of nested trap handlers currently running.
+    trap_depth: AtomicUsize,
+    /// Should the running process be preempted on the way out of the trap?
+    need_resched: AtomicBool,
+}
+
+impl Cpu {
+    pub const fn new() -> Self {
+        Self {
+            noff: AtomicUsize::new(0),
+            intena: AtomicBool::new(false),
+            trap_depth: AtomicUsize::new(0),
+            need_resched: AtomicBool::new(false),
+        }
+    }
+
+    pub fn noff(&self) -> usize {
+        self.noff.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn push_off(&self, was_enabled: bool) {
+        if self.noff.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.intena.store(was_enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether interrupts should be re-enabled.
+    pub(crate) fn pop_off(&self) -> bool {
+        let noff = self.noff.load(Ordering::Relaxed);
+        assert!(noff > 0, "pop_off without matching push_off");
+        self.noff.store(noff - 1, Ordering::Relaxed);
+        noff == 1 && self.intena.load(Ordering::Relaxed)
+    }
+
+    pub fn enter_trap(&self) {
+        self.trap_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn leave_trap(&self) {
+        self.trap_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Are we running a trap handler (i.e. in interrupt context)?
+    pub fn in_trap(&self) -> bool {
+        self.trap_depth.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn set_need_resched(&self) {
+        self.need_resched.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears the flag, returning whether it was set.
+    pub fn take_need_resched(&self) -> bool {
+        self.need_resched.swap(false, Ordering::Relaxed)
+    }
+}
+
+static CPUS: PerCpu<Cpu> = PerCpu::new([const { Cpu::new() }; MAX_HARTS]);
+
+/// Id of the hart we're running on.
+pub fn id() -> usize {
+    let id: usize;
+    unsafe { asm!("csrr {}, mhartid", out(reg) id) };
+    id
+}
+
+/// Cycles elapsed on this hart, for profiling.
+pub fn cycles() -> u64 {
+    let cycles: u64;
+    unsafe { asm!("csrr {}, mcycle", out(reg) cycles) };
+    cycles
+}
+
+pub fn current() -> &'static Cpu {
+    CPUS.current()
+}
@@ -0,0 +1,399 @@
+//! Driver model: drivers register with the [`Driver`] trait, and are
+//! bound to the device tree nodes whose `compatible` they list. Bound
+//! devices get a [`DeviceClass`] and, for character and block devices,
+//! a major/minor device number.
+
+use core::fmt::Display;
+
+use crate::{
+    fdt::{self, Node, Region},
+    info,
+    io::{goldfish_rtc, sifive_test, uart},
+    irq::plic,
+    timer,
+    utils::{collections::ArrayVec, sync::SpinLock},
+    virtio, warn,
+};
+
+pub const MAX_DRIVERS: usize = 16;
+pub const MAX_DEVICES: usize = 32;
+
+/// Major numbers, as on Linux where there's one.
+pub const MAJOR_TTY: u16 = 4;
+pub const MAJOR_RTC: u16 = 253;
+pub const MAJOR_VIRTIO_BLK: u16 = 254;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    /// Read and written as a stream, e.g. a UART.
+    Char { major: u16 },
+    /// Read and written in blocks, e.g. a disk.
+    Block { major: u16 },
+    /// Only used by the kernel itself, e.g. an interrupt controller.
+    Platform,
+}
+
+/// Device number: `major` tells the driver, `minor` the instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Display for DeviceNumber {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.major, self.minor))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The node has no usable `reg`.
+    MissingRegs,
+    /// The node has no usable `interrupts`.
+    MissingIrq,
+    /// Nothing's actually there, e.g. an empty virtio-mmio slot.
+    NotPresent,
+    /// Present, but not in a way the driver supports.
+    Unsupported,
+}
+
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// `compatible` strings of the nodes this driver handles.
+    fn compatible(&self) -> &'static [&'static str];
+
+    fn class(&self) -> DeviceClass;
+
+    /// Takes over the device at `node`, e.g. mapping its registers.
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError>;
+
+    /// Lets go of a device probed earlier.
+    fn remove(&self, _device: &Device) {}
+}
+
+/// A device bound to its driver.
+#[derive(Clone, Copy)]
+pub struct Device {
+    /// Device tree node name, e.g. `serial@10000000`.
+    pub name: &'static str,
+    pub driver: &'static dyn Driver,
+    pub class: DeviceClass,
+    pub number: Option<DeviceNumber>,
+    pub regs: Option<Region>,
+    pub irq: Option<u32>,
+}
+
+impl core::fmt::Debug for Device {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Device")
+            .field("name", &self.name)
+            .field("driver", &self.driver.name())
+            .field("class", &self.class)
+            .field("number", &self.number)
+            .field("regs", &self.regs)
+            .field("irq", &self.irq)
+            .finish()
+    }
+}
+
+static DRIVERS: SpinLock<ArrayVec<&'static dyn Driver, MAX_DRIVERS>> =
+    SpinLock::new("DRIVERS", ArrayVec::new());
+static DEVICES: SpinLock<ArrayVec<Device, MAX_DEVICES>> = SpinLock::new("DEVICES", ArrayVec::new());
+
+/// Drivers for the devices the kernel itself needs, in probe order:
+/// interrupt controllers before the devices interrupting through them.
+fn builtin_drivers() -> [&'static dyn Driver; 6] {
+    [
+        &timer::CLINT_DRIVER,
+        &plic::DRIVER,
+        &uart::DRIVER,
+        &goldfish_rtc::DRIVER,
+        &sifive_test::DRIVER,
+        &virtio::blk::DRIVER,
+    ]
+}
+
+/// Registers `driver`, binding it to matching devices already in the tree.
+/// A driver past the first [`MAX_DRIVERS`] is left out, devices and all,
+/// with a warning.
+pub fn register_driver(driver: &'static dyn Driver) {
+    let pushed = DRIVERS.lock().try_push(driver);
+    if pushed.is_err() {
+        warn!("{}: no room past {} drivers", driver.name(), MAX_DRIVERS);
+        return;
+    }
+    if let Some(fdt) = fdt::get() {
+        fdt.for_each_node(|node| {
+            if driver
+                .compatible()
+                .iter()
+                .any(|name| node.is_compatible(name))
+            {
+                bind(driver, &node);
+            }
+        });
+    }
+}
+
+/// Registers the built-in drivers, logging every device bound.
+pub fn init() {
+    if fdt::get().is_none() {
+        warn!("no device tree, assuming QEMU virt's devices");
+    }
+    for driver in builtin_drivers() {
+        register_driver(driver);
+    }
+}
+
+/// Lowest minor number no device of `class` has, if it has numbers.
+/// Those of removed devices get reused.
+fn number_for(devices: &[Device], class: DeviceClass) -> Option<DeviceNumber> {
+    let major = match class {
+        DeviceClass::Char { major } | DeviceClass::Block { major } => major,
+        DeviceClass::Platform => return None,
+    };
+    let taken = |minor| {
+        devices
+            .iter()
+            .any(|device| device.class == class && device.number.map(|n| n.minor) == Some(minor))
+    };
+    let minor = (0..=u16::MAX).find(|&minor| !taken(minor))?;
+    Some(DeviceNumber { major, minor })
+}
+
+fn bind(driver: &'static dyn Driver, node: &Node<'static>) {
+    if DEVICES
+        .lock()
+        .iter()
+        .any(|device| device.name == node.name())
+    {
+        return;
+    }
+    match driver.probe(node) {
+        Ok(()) => {}
+        Err(ProbeError::NotPresent) => return,
+        Err(error) => {
+            warn!(
+                "{}: {} failed to probe: {:?}",
+                node.name(),
+                driver.name(),
+                error
+            );
+            return;
+        }
+    }
+    let mut devices = DEVICES.lock();
+    let device = Device {
+        name: node.name(),
+        driver,
+        class: driver.class(),
+        number: number_for(&devices, driver.class()),
+        regs: node.reg().next(),
+        irq: node.interrupts().next(),
+    };
+    let pushed = devices.try_push(device);
+    drop(devices);
+    match pushed {
+        Ok(()) => log_bound(&device),
+        Err(device) => {
+            warn!("{}: no room past {} devices", node.name(), MAX_DEVICES);
+            driver.remove(&device);
+        }
+    }
+}
+
+fn log_bound(device: &Device) {
+    let kind = match device.class {
+        DeviceClass::Char { .. } => "char",
+        DeviceClass::Block { .. } => "block",
+        DeviceClass::Platform => "platform",
+    };
+    let base = device.regs.map_or(0, |regs| regs.base);
+    match (device.number, device.irq) {
+        (Some(number), Some(irq)) => info!(
+            "{} ({} {}) at {:#x} irq {}: {}",
+            device.name,
+            kind,
+            number,
+            base,
+            irq,
+            device.driver.name()
+        ),
+        (Some(number), None) => info!(
+            "{} ({} {}) at {:#x}: {}",
+            device.name,
+            kind,
+            number,
+            base,
+            device.driver.name()
+        ),
+        (None, _) => info!(
+            "{} ({}) at {:#x}: {}",
+            device.name,
+            kind,
+            base,
+            device.driver.name()
+        ),
+    }
+}
+
+/// Unbinds the device named `name`, returning whether there was one.
+pub fn remove(name: &str) -> bool {
+    let mut devices = DEVICES.lock();
+    let Some(index) = devices.iter().position(|device| device.name == name) else {
+        return false;
+    };
+    let device = devices.remove(index);
+    drop(devices);
+    device.driver.remove(&device);
+    true
+}
+
+/// Runs `f` on every bound device, in binding order.
+pub fn for_each_device(f: impl FnMut(&Device)) {
+    DEVICES.lock().iter().for_each(f);
+}
+
+pub fn find_device(number: DeviceNumber) -> Option<Device> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.number == Some(number))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[test_case]
+    pub fn dev_binds_builtin_drivers() {
+        let mut bound = ArrayVec::<&str, MAX_DEVICES>::new();
+        for_each_device(|device| bound.push(device.driver.name()));
+        for driver in builtin_drivers() {
+            assert!(
+                bound.contains(&driver.name()),
+                "{} should be bound",
+                driver.name()
+            );
+        }
+
+        let tty = find_device(DeviceNumber {
+            major: MAJOR_TTY,
+            minor: 0,
+        })
+        .expect("the UART should be tty 4:0");
+        assert_eq!(tty.driver.name(), uart::DRIVER.name());
+        assert_eq!(tty.irq, Some(uart::irq()));
+    }
+
+    struct TestDriver;
+
+    static REMOVED: AtomicBool = AtomicBool::new(false);
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn compatible(&self) -> &'static [&'static str] {
+            &["google,goldfish-rtc"]
+        }
+
+        fn class(&self) -> DeviceClass {
+            DeviceClass::Char { major: 1 }
+        }
+
+        fn probe(&self, _node: &Node<'static>) -> Result<(), ProbeError> {
+            Ok(())
+        }
+
+        fn remove(&self, _device: &Device) {
+            REMOVED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn driver_of(name: &str) -> Option<&'static str> {
+        let mut driver = None;
+        for_each_device(|device| {
+            if device.name == name {
+                driver = Some(device.driver.name());
+            }
+        });
+        driver
+    }
+
+    fn test_device(name: &'static str, minor: u16) -> Device {
+        Device {
+            name,
+            driver: &TestDriver,
+            class: DeviceClass::Char { major: 1 },
+            number: Some(DeviceNumber { major: 1, minor }),
+            regs: None,
+            irq: None,
+        }
+    }
+
+    fn assert_numbers_unique() {
+        let mut numbers = ArrayVec::<DeviceNumber, MAX_DEVICES>::new();
+        for_each_device(|device| {
+            if let Some(number) = device.number {
+                assert!(!numbers.contains(&number), "{:?} given out twice", number);
+                numbers.push(number);
+            }
+        });
+    }
+
+    #[test_case]
+    pub fn dev_numbers_and_removal() {
+        let devices = [test_device("a", 0)];
+        assert_eq!(
+            number_for(&devices, DeviceClass::Char { major: 1 }),
+            Some(DeviceNumber { major: 1, minor: 1 })
+        );
+        assert_eq!(
+            number_for(&devices, DeviceClass::Block { major: 1 }),
+            Some(DeviceNumber { major: 1, minor: 0 })
+        );
+        assert_eq!(number_for(&devices, DeviceClass::Platform), None);
+        // "b" was removed from between the two
+        let devices = [test_device("a", 0), test_device("c", 2)];
+        assert_eq!(
+            number_for(&devices, DeviceClass::Char { major: 1 }),
+            Some(DeviceNumber { major: 1, minor: 1 }),
+            "the lowest free minor should be reused"
+        );
+
+        let rtc = fdt::find_compatible(goldfish_rtc::COMPATIBLE).unwrap();
+        bind(&TestDriver, &rtc);
+        let mut bound = 0;
+        for_each_device(|device| bound += (device.name == rtc.name()) as usize);
+        assert_eq!(bound, 1, "devices should only be bound once");
+        assert!(!remove("nonexistent"));
+
+        // Hand the RTC over to the test driver, along with two devices
+        // nothing drives, unbinding the first before binding the last
+        let fw_cfg = fdt::find_compatible(&["qemu,fw-cfg-mmio"]).unwrap();
+        let flash = fdt::find_compatible(&["cfi-flash"]).unwrap();
+        assert!(remove(rtc.name()));
+        bind(&TestDriver, &rtc);
+        bind(&TestDriver, &fw_cfg);
+        assert_eq!(driver_of(rtc.name()), Some(TestDriver.name()));
+        assert!(remove(rtc.name()));
+        assert!(
+            REMOVED.load(Ordering::Relaxed),
+            "the driver should let go of it"
+        );
+        assert_eq!(driver_of(rtc.name()), None);
+        bind(&TestDriver, &flash);
+        assert_numbers_unique();
+        assert!(remove(fw_cfg.name()) && remove(flash.name()));
+        bind(&goldfish_rtc::DRIVER, &rtc);
+        assert_eq!(driver_of(rtc.name()), Some(goldfish_rtc::DRIVER.name()));
+        assert_numbers_unique();
+    }
+}
@@ -0,0 +1,491 @@
+//! Flattened device tree parsing, as handed over by the firmware (or
+//! QEMU) in `a1` at boot. See the Devicetree Specification, chapters 2
+//! and 5. Drivers find their devices here rather than hardcoding them.
+
+use core::{fmt::Debug, slice, str};
+
+use crate::utils::cells::Once;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+/// Blobs must be readable by a version 16 parser, as v17 ones are.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Defaults for `#address-cells` and `#size-cells`.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic(u32),
+    BadVersion(u32),
+    Truncated,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    str::from_utf8(&bytes[..len]).ok()
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+/// Reads a number spread over `cells` big-endian cells; at most 2 fit.
+fn read_cells(data: &[u8], cells: u32) -> Option<u64> {
+    match cells {
+        0 => Some(0),
+        1 => read_u32(data, 0).map(u64::from),
+        2 => Some(u64::from_be_bytes(data.get(..8)?.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Range of a device's registers, or of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub base: usize,
+    pub size: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'fdt> {
+    structs: &'fdt [u8],
+    strings: &'fdt [u8],
+}
+
+impl<'fdt> Fdt<'fdt> {
+    pub fn new(data: &'fdt [u8]) -> Result<Self, FdtError> {
+        let header = |field: usize| read_u32(data, field * 4).ok_or(FdtError::Truncated);
+        let magic = header(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let last_comp_version = header(6)?;
+        if last_comp_version > FDT_LAST_COMP_VERSION {
+            return Err(FdtError::BadVersion(last_comp_version));
+        }
+        let block = |offset: u32, size: u32| {
+            let start = offset as usize;
+            data.get(start..start + size as usize)
+                .ok_or(FdtError::Truncated)
+        };
+        Ok(Self {
+            structs: block(header(2)?, header(9)?)?,
+            strings: block(header(3)?, header(8)?)?,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to a device tree blob that stays valid and
+    /// unmodified for `'fdt`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = unsafe { slice::from_raw_parts(ptr, FDT_HEADER_SIZE) };
+        let magic = read_u32(header, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = read_u32(header, 4).ok_or(FdtError::Truncated)?;
+        Self::new(unsafe { slice::from_raw_parts(ptr, total_size as usize) })
+    }
+
+    pub fn root(&self) -> Node<'fdt> {
+        let mut offset = 0;
+        let name = match self.next_token(&mut offset) {
+            Some(Token::BeginNode(name)) => name,
+            _ => {
+                offset = self.structs.len();
+                ""
+            }
+        };
+        Node {
+            fdt: *self,
+            name,
+            offset,
+            address_cells: DEFAULT_ADDRESS_CELLS,
+            size_cells: DEFAULT_SIZE_CELLS,
+        }
+    }
+
+    /// First node, in depth-first order, compatible with any of `compatible`.
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'fdt>> {
+        fn search<'fdt>(node: Node<'fdt>, compatible: &[&str]) -> Option<Node<'fdt>> {
+            if compatible.iter().any(|name| node.is_compatible(name)) {
+                return Some(node);
+            }
+            node.children().find_map(|child| search(child, compatible))
+        }
+        search(self.root(), compatible)
+    }
+
+    /// Runs `f` on every node, in depth-first order.
+    pub fn for_each_node(&self, mut f: impl FnMut(Node<'fdt>)) {
+        fn walk<'fdt>(node: Node<'fdt>, f: &mut impl FnMut(Node<'fdt>)) {
+            f(node);
+            node.children().for_each(|child| walk(child, f));
+        }
+        walk(self.root(), &mut f);
+    }
+
+    /// RAM, from the `memory` nodes.
+    pub fn memory(&self) -> impl Iterator<Item = Region> + use<'fdt> {
+        self.root()
+            .children()
+            .filter(|node| node.device_type() == Some("memory"))
+            .flat_map(|node| node.reg())
+    }
+
+    /// Number of harts, from the `cpu` nodes under `/cpus`.
+    pub fn hart_count(&self) -> usize {
+        self.find_node("/cpus").map_or(0, |cpus| {
+            cpus.children()
+                .filter(|node| node.device_type() == Some("cpu"))
+                .count()
+        })
+    }
+
+    /// `mtime` ticks per second, from `/cpus/timebase-frequency`.
+    pub fn timebase_frequency(&self) -> Option<u64> {
+        self.find_node("/cpus")?
+            .property("timebase-frequency")?
+            .as_u64()
+    }
+
+    /// Kernel command line, from `/chosen/bootargs`.
+    pub fn bootargs(&self) -> Option<&'fdt str> {
+        self.find_node("/chosen")?.property("bootargs")?.as_str()
+    }
+
+    /// Looks up a node by its absolute path, e.g. `/cpus/cpu@0`. Unit
+    /// addresses may be left out when unambiguous, e.g. `/cpus/cpu`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'fdt>> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(self.root(), |node, component| node.child(component))
+    }
+
+    fn next_token(&self, offset: &mut usize) -> Option<Token<'fdt>> {
+        loop {
+            let token = read_u32(self.structs, *offset)?;
+            *offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(self.structs, *offset)?;
+                    *offset = align4(*offset + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = read_u32(self.structs, *offset)? as usize;
+                    let name_offset = read_u32(self.structs, *offset + 4)? as usize;
+                    let start = *offset + 8;
+                    let value = self.structs.get(start..start + len)?;
+                    *offset = align4(start + len);
+                    return Some(Token::Prop(Property {
+                        name: read_str(self.strings, name_offset)?,
+                        value,
+                    }));
+                }
+                FDT_NOP => continue,
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Debug for Fdt<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "Fdt({} bytes of structs, {} of strings)",
+            self.structs.len(),
+            self.strings.len()
+        ))
+    }
+}
+
+enum Token<'fdt> {
+    BeginNode(&'fdt str),
+    EndNode,
+    Prop(Property<'fdt>),
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'fdt> {
+    fdt: Fdt<'fdt>,
+    name: &'fdt str,
+    /// Offset of the first token after the node's name.
+    offset: usize,
+    /// Cells per address and size in `reg`, as set by the parent.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'fdt> Node<'fdt> {
+    /// Full name, including the unit address (e.g. `cpu@0`).
+    pub fn name(&self) -> &'fdt str {
+        self.name
+    }
+
+    /// Name without the unit address (e.g. `cpu`).
+    pub fn base_name(&self) -> &'fdt str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = Property<'fdt>> + use<'fdt> {
+        let fdt = self.fdt;
+        let mut offset = self.offset;
+        core::iter::from_fn(move || match fdt.next_token(&mut offset)? {
+            Token::Prop(property) => Some(property),
+            // Properties always come before children
+            Token::BeginNode(_) | Token::EndNode => None,
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'fdt>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    fn cells(&self, name: &str, default: u32) -> u32 {
+        self.property(name)
+            .and_then(|property| property.as_u32())
+            .unwrap_or(default)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'fdt>> + use<'fdt> {
+        let fdt = self.fdt;
+        let mut offset = self.offset;
+        let address_cells = self.cells("#address-cells", DEFAULT_ADDRESS_CELLS);
+        let size_cells = self.cells("#size-cells", DEFAULT_SIZE_CELLS);
+        core::iter::from_fn(move || {
+            loop {
+                match fdt.next_token(&mut offset)? {
+                    Token::Prop(_) => continue,
+                    Token::EndNode => return None,
+                    Token::BeginNode(name) => {
+                        let child = Node {
+                            fdt,
+                            name,
+                            offset,
+                            address_cells,
+                            size_cells,
+                        };
+                        skip_subtree(&fdt, &mut offset)?;
+                        return Some(child);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Finds a child by full name, or by base name if `name` has no unit address.
+    pub fn child(&self, name: &str) -> Option<Node<'fdt>> {
+        self.children()
+            .find(|child| child.name == name || (!name.contains('@') && child.base_name() == name))
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'fdt str> + use<'fdt> {
+        self.property("compatible")
+            .into_iter()
+            .flat_map(|property| property.as_str_list())
+    }
+
+    pub fn is_compatible(&self, name: &str) -> bool {
+        self.compatible().any(|compatible| compatible == name)
+    }
+
+    pub fn device_type(&self) -> Option<&'fdt str> {
+        self.property("device_type")?.as_str()
+    }
+
+    /// Register ranges, as (address, size) pairs sized by the parent's
+    /// `#address-cells` and `#size-cells`.
+    pub fn reg(&self) -> impl Iterator<Item = Region> + use<'fdt> {
+        let (address_cells, size_cells) = (self.address_cells, self.size_cells);
+        let entry_len = (address_cells + size_cells) as usize * 4;
+        let value = self
+            .property("reg")
+            .map_or(&[][..], |property| property.value);
+        value
+            .chunks_exact(entry_len.max(1))
+            .filter_map(move |entry| {
+                let (address, size) = entry.split_at(address_cells as usize * 4);
+                Some(Region {
+                    base: read_cells(address, address_cells)? as usize,
+                    size: read_cells(size, size_cells)? as usize,
+                })
+            })
+    }
+
+    /// Interrupt numbers, assuming one cell per interrupt as with the PLIC.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> + use<'fdt> {
+        self.property("interrupts")
+            .into_iter()
+            .flat_map(|property| property.cells())
+    }
+}
+
+/// Moves `offset` past the end of the node whose name was just read.
+fn skip_subtree(fdt: &Fdt<'_>, offset: &mut usize) -> Option<()> {
+    let mut depth = 1;
+    while depth > 0 {
+        match fdt.next_token(offset)? {
+            Token::BeginNode(_) => depth += 1,
+            Token::EndNode => depth -= 1,
+            Token::Prop(_) => {}
+        }
+    }
+    Some(())
+}
+
+impl Debug for Node<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("Node({:?})", self.name))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Property<'fdt> {
+    pub name: &'fdt str,
+    pub value: &'fdt [u8],
+}
+
+impl<'fdt> Property<'fdt> {
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.value.len() {
+            4 => read_u32(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// Reads a value stored either as one or two cells.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => read_u32(self.value, 0).map(u64::from),
+            8 => Some(u64::from_be_bytes(self.value.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'fdt str> {
+        read_str(self.value, 0)
+    }
+
+    /// Reads a list of NUL-terminated strings, e.g. `compatible`.
+    pub fn as_str_list(&self) -> impl Iterator<Item = &'fdt str> + use<'fdt> {
+        self.value
+            .split(|&byte| byte == 0)
+            .filter(|bytes| !bytes.is_empty())
+            .filter_map(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = u32> + use<'fdt> {
+        self.value
+            .chunks_exact(4)
+            .filter_map(|cell| Some(u32::from_be_bytes(cell.try_into().ok()?)))
+    }
+}
+
+impl Debug for Property<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("Property({:?}, {:?})", self.name, self.value))
+    }
+}
+
+static FDT: Once<Fdt<'static>> = Once::new();
+
+/// Keeps the device tree found at boot for [`get`], unless it isn't
+/// a valid one.
+///
+/// # Safety
+///
+/// See [`Fdt::from_ptr`].
+pub unsafe fn init(ptr: *const u8) -> Result<(), FdtError> {
+    let fdt = unsafe { Fdt::from_ptr(ptr) }?;
+    let _ = FDT.set(fdt);
+    Ok(())
+}
+
+/// The device tree passed at boot, if any.
+pub fn get() -> Option<&'static Fdt<'static>> {
+    FDT.get()
+}
+
+/// [`Fdt::find_compatible`] in the device tree passed at boot.
+pub fn find_compatible(compatible: &[&str]) -> Option<Node<'static>> {
+    get()?.find_compatible(compatible)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn fdt_rejects_garbage() {
+        let garbage = [0u8; FDT_HEADER_SIZE];
+        assert!(matches!(Fdt::new(&garbage), Err(FdtError::BadMagic(0))));
+        assert!(matches!(
+            Fdt::new(&FDT_MAGIC.to_be_bytes()),
+            Err(FdtError::Truncated)
+        ));
+    }
+
+    #[test_case]
+    pub fn fdt_finds_nodes() {
+        let fdt = get().expect("QEMU should pass a device tree");
+        assert!(fdt.root().property("compatible").is_some());
+        let cpus = fdt.find_node("/cpus").expect("/cpus should exist");
+        assert!(cpus.property("timebase-frequency").is_some());
+        let cpu = fdt
+            .find_node("/cpus/cpu@0")
+            .expect("/cpus/cpu@0 should exist");
+        assert_eq!(cpu.base_name(), "cpu");
+        assert_eq!(
+            fdt.find_node("/cpus/cpu").map(|node| node.name()),
+            Some("cpu@0"),
+            "unit addresses should be optional"
+        );
+        assert!(fdt.find_node("/nonexistent").is_none());
+    }
+
+    #[test_case]
+    pub fn fdt_discovers_hardware() {
+        let fdt = get().unwrap();
+        let ram = fdt.memory().next().expect("there should be some RAM");
+        assert_eq!(ram.base, 0x8000_0000);
+        assert!(ram.size >= 8 << 20);
+        assert!(fdt.hart_count() >= 1);
+        assert_eq!(fdt.timebase_frequency(), Some(10_000_000));
+
+        let uart = fdt
+            .find_compatible(&["ns16550a"])
+            .expect("QEMU virt has a 16550");
+        assert!(uart.is_compatible("ns16550a"));
+        assert_eq!(
+            uart.reg().next(),
+            Some(Region {
+                base: 0x1000_0000,
+                size: 0x100
+            })
+        );
+        assert_eq!(uart.interrupts().next(), Some(10));
+        assert!(fdt.find_compatible(&["nonexistent"]).is_none());
+
+        let mut nodes = 0;
+        fdt.for_each_node(|_| nodes += 1);
+        assert!(nodes > 10);
+    }
+}
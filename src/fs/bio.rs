@@ -0,0 +1,314 @@
+//! Buffer cache, as in xv6: copies of recently used disk blocks, so that
+//! they're read once, and so that processes sharing a block see the same
+//! copy, taking turns through its sleeping lock.
+//!
+//! [`bread`] gets a block locked, [`bwrite`] writes it back, and dropping
+//! (or [`brelse`]ing) it unlocks it. Blocks nobody references get reused
+//! least recently used first.
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use super::BSIZE;
+use crate::{
+    dev::{self, DeviceNumber},
+    utils::sync::{Mutex, MutexGuard, SpinLock},
+    virtio::blk,
+};
+
+/// Buffers in the cache: enough for the blocks of a few operations.
+pub const NBUF: usize = 30;
+
+/// A block's contents, behind its sleeping lock.
+pub struct BufData {
+    /// Whether `data` was read from the disk yet.
+    valid: bool,
+    data: [u8; BSIZE],
+}
+
+/// Which block a buffer holds, behind the cache lock.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    dev: Option<DeviceNumber>,
+    blockno: u32,
+    refcnt: usize,
+    /// When last released, for LRU replacement.
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BcacheStats {
+    /// Blocks found in the cache.
+    pub hits: u64,
+    /// Blocks that had to get a buffer, and then be read from the disk.
+    pub misses: u64,
+}
+
+struct Cache {
+    entries: [Entry; NBUF],
+    /// Counts releases, to stamp `last_used` with.
+    clock: u64,
+    stats: BcacheStats,
+}
+
+static CACHE: SpinLock<Cache> = SpinLock::new(
+    "BCACHE",
+    Cache {
+        entries: [Entry {
+            dev: None,
+            blockno: 0,
+            refcnt: 0,
+            last_used: 0,
+        }; NBUF],
+        clock: 0,
+        stats: BcacheStats { hits: 0, misses: 0 },
+    },
+);
+static BUFS: [Mutex<BufData>; NBUF] = [const {
+    Mutex::new(
+        "BUF",
+        BufData {
+            valid: false,
+            data: [0; BSIZE],
+        },
+    )
+}; NBUF];
+
+/// Cached block, locked by its holder until dropped.
+pub struct Buf {
+    index: usize,
+    dev: DeviceNumber,
+    blockno: u32,
+    /// Released before the reference, see [`Drop`].
+    data: ManuallyDrop<MutexGuard<'static, BufData>>,
+}
+
+impl Buf {
+    pub fn dev(&self) -> DeviceNumber {
+        self.dev
+    }
+
+    pub fn blockno(&self) -> u32 {
+        self.blockno
+    }
+}
+
+impl Deref for Buf {
+    type Target = [u8; BSIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data.data
+    }
+}
+
+impl DerefMut for Buf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data.data
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        // SAFETY: never used again
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        release(self.index);
+    }
+}
+
+/// Drops a reference to buffer `index`.
+fn release(index: usize) {
+    let mut cache = CACHE.lock();
+    cache.clock += 1;
+    let clock = cache.clock;
+    let entry = &mut cache.entries[index];
+    entry.refcnt -= 1;
+    if entry.refcnt == 0 {
+        entry.last_used = clock;
+    }
+}
+
+/// Locks the buffer for `blockno` on `dev`, recycling the least
+/// recently used one if it isn't cached.
+fn bget(dev: DeviceNumber, blockno: u32) -> Buf {
+    let mut cache = CACHE.lock();
+    let cached = cache
+        .entries
+        .iter()
+        .position(|entry| entry.dev == Some(dev) && entry.blockno == blockno);
+    if let Some(index) = cached {
+        cache.entries[index].refcnt += 1;
+        cache.stats.hits += 1;
+        drop(cache);
+        // May sleep, so not under the cache lock
+        let data = BUFS[index].lock();
+        return Buf {
+            index,
+            dev,
+            blockno,
+            data: ManuallyDrop::new(data),
+        };
+    }
+
+    let (index, entry) = cache
+        .entries
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, entry)| entry.refcnt == 0)
+        .min_by_key(|(_, entry)| entry.last_used)
+        .expect("bget: no buffers");
+    *entry = Entry {
+        dev: Some(dev),
+        blockno,
+        refcnt: 1,
+        last_used: 0,
+    };
+    cache.stats.misses += 1;
+    // Nobody references it, so nobody holds it: lock it before anyone
+    // looking for the new block can, and before it looks valid to them
+    let mut data = BUFS[index]
+        .try_lock()
+        .expect("unreferenced buffers should be unlocked");
+    data.valid = false;
+    drop(cache);
+    Buf {
+        index,
+        dev,
+        blockno,
+        data: ManuallyDrop::new(data),
+    }
+}
+
+/// Disk errors are fatal, as in xv6.
+fn disk_rw(buf: &mut Buf, write: bool) {
+    let block = buf.blockno as u64;
+    let result = match (buf.dev.major, buf.dev.minor) {
+        (dev::MAJOR_VIRTIO_BLK, 0) => match write {
+            true => blk::write_block(block, &buf.data.data),
+            false => blk::read_block(block, &mut buf.data.data),
+        },
+        _ => Err(blk::BlkError::NoDevice),
+    };
+    if let Err(error) = result {
+        panic!(
+            "{} block {} on {}: {:?}",
+            if write { "writing" } else { "reading" },
+            block,
+            buf.dev,
+            error
+        );
+    }
+}
+
+/// Returns block `blockno` of `dev`, locked.
+pub fn bread(dev: DeviceNumber, blockno: u32) -> Buf {
+    let mut buf = bget(dev, blockno);
+    if !buf.data.valid {
+        disk_rw(&mut buf, false);
+        buf.data.valid = true;
+    }
+    buf
+}
+
+/// Writes `buf` back to the disk.
+pub fn bwrite(buf: &mut Buf) {
+    disk_rw(buf, true);
+}
+
+/// Unlocks `buf`, like dropping it.
+pub fn brelse(buf: Buf) {
+    drop(buf);
+}
+
+/// Keeps `buf` cached even once released, e.g. until the log has
+/// written it back.
+pub fn bpin(buf: &Buf) {
+    CACHE.lock().entries[buf.index].refcnt += 1;
+}
+
+/// Undoes [`bpin`].
+pub fn bunpin(buf: &Buf) {
+    release(buf.index);
+}
+
+pub fn stats() -> BcacheStats {
+    CACHE.lock().stats
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{
+        fs::ROOT_DEV,
+        proc::{self, PROCESSES},
+        test::TEST_QUANTA,
+    };
+
+    /// Blocks at the end of the disk, out of the way of anything else.
+    fn scratch_block(n: u32) -> u32 {
+        let blocks = blk::capacity().unwrap() / blk::SECTORS_PER_BLOCK;
+        (blocks - 1) as u32 - n
+    }
+
+    #[test_case]
+    pub fn bio_caches_blocks() {
+        let block = scratch_block(0);
+        let before = stats();
+        let saved = bread(ROOT_DEV, block)[0];
+        let mut buf = bread(ROOT_DEV, block);
+        assert_eq!(stats().hits, before.hits + 1);
+        buf[0] = saved.wrapping_add(1);
+        bwrite(&mut buf);
+        brelse(buf);
+
+        // Enough other blocks to recycle every buffer
+        for n in 1..=NBUF as u32 {
+            bread(ROOT_DEV, scratch_block(n));
+        }
+        let misses = stats().misses;
+        let mut buf = bread(ROOT_DEV, block);
+        assert_eq!(stats().misses, misses + 1, "it should have been evicted");
+        assert_eq!(buf[0], saved.wrapping_add(1), "it should have been written");
+        buf[0] = saved;
+        bwrite(&mut buf);
+        drop(buf);
+
+        bread(ROOT_DEV, scratch_block(NBUF as u32));
+        assert_eq!(
+            stats().misses,
+            misses + 1,
+            "recently used blocks should stay cached"
+        );
+    }
+
+    static HOLDING: AtomicBool = AtomicBool::new(false);
+    static SAW_HOLDER: AtomicBool = AtomicBool::new(false);
+
+    fn holder() {
+        let _buf = bread(ROOT_DEV, scratch_block(0));
+        HOLDING.store(true, Ordering::SeqCst);
+        proc::yield_self();
+        proc::yield_self();
+        HOLDING.store(false, Ordering::SeqCst);
+    }
+
+    fn waiter() {
+        proc::yield_self();
+        let _buf = bread(ROOT_DEV, scratch_block(0));
+        SAW_HOLDER.store(HOLDING.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    #[test_case]
+    pub fn bio_locks_shared_blocks() {
+        PROCESSES.create(holder);
+        PROCESSES.create(waiter);
+        proc::run_until_exit(TEST_QUANTA);
+        assert!(
+            !SAW_HOLDER.load(Ordering::SeqCst),
+            "a block should only be locked by one process at a time"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Free-block bitmap: a bit per block of the disk, set if it's in use.
+//! Blocks up to the first data block are marked as in use by
+//! [`mkfs`](super::mkfs), and never freed.
+
+use super::{BPB, FsError, bio::bread, log::log_write, superblock};
+use crate::dev::DeviceNumber;
+
+/// Zeroes block `blockno`, within an operation.
+fn bzero(dev: DeviceNumber, blockno: u32) {
+    let mut buf = bread(dev, blockno);
+    buf.fill(0);
+    log_write(&buf);
+}
+
+/// Allocates a zeroed block, within an operation.
+pub fn balloc(dev: DeviceNumber) -> Result<u32, FsError> {
+    let sb = superblock();
+    for base in (0..sb.size).step_by(BPB as usize) {
+        let mut buf = bread(dev, sb.bblock(base));
+        for bit in 0..BPB.min(sb.size - base) {
+            let (byte, mask) = ((bit / 8) as usize, 1 << (bit % 8));
+            if buf[byte] & mask == 0 {
+                buf[byte] |= mask;
+                log_write(&buf);
+                drop(buf);
+                bzero(dev, base + bit);
+                return Ok(base + bit);
+            }
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+/// Frees block `blockno`, within an operation.
+pub fn bfree(dev: DeviceNumber, blockno: u32) {
+    let mut buf = bread(dev, superblock().bblock(blockno));
+    let bit = blockno % BPB;
+    let (byte, mask) = ((bit / 8) as usize, 1 << (bit % 8));
+    assert!(buf[byte] & mask != 0, "bfree: block {} is free", blockno);
+    buf[byte] &= !mask;
+    log_write(&buf);
+}
+
+/// Blocks not in use.
+pub fn free_blocks(dev: DeviceNumber) -> u32 {
+    let sb = superblock();
+    let mut free = 0;
+    for base in (0..sb.size).step_by(BPB as usize) {
+        let buf = bread(dev, sb.bblock(base));
+        let bits = BPB.min(sb.size - base);
+        free += (0..bits)
+            .filter(|bit| buf[(bit / 8) as usize] & (1 << (bit % 8)) == 0)
+            .count() as u32;
+    }
+    free
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{ROOT_DEV, begin_op, end_op};
+
+    #[test_case]
+    pub fn bitmap_allocates_and_frees() {
+        let free = free_blocks(ROOT_DEV);
+        begin_op();
+        let a = balloc(ROOT_DEV).unwrap();
+        let b = balloc(ROOT_DEV).unwrap();
+        end_op();
+        assert_ne!(a, b);
+        assert!(a >= superblock().size - superblock().nblocks);
+        assert!(bread(ROOT_DEV, a).iter().all(|&byte| byte == 0));
+        assert_eq!(free_blocks(ROOT_DEV), free - 2);
+
+        begin_op();
+        bfree(ROOT_DEV, a);
+        bfree(ROOT_DEV, b);
+        end_op();
+        assert_eq!(free_blocks(ROOT_DEV), free);
+    }
+}
@@ -0,0 +1,289 @@
+//! Directories: files of [`Dirent`]s, free where `inum` is 0. Each has
+//! `.` and `..` entries, the root's `..` being itself.
+//!
+//! Paths are looked up from the root directory, there being no working
+//! directory yet, a locked directory at a time. Like anything that may
+//! drop an [`Inode`], lookups happen within operations.
+
+use super::{
+    Dirent, FsError, ROOT_DEV, ROOTINO,
+    inode::{FileType, Inode, InodeGuard, ialloc, iget},
+    read_at, write_at,
+};
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+impl InodeGuard<'_> {
+    /// Entry at `offset`, which has to be within the directory.
+    fn dirent(&self, offset: u32) -> Result<Dirent, FsError> {
+        let mut raw = [0; DIRENT_SIZE as usize];
+        match self.read(offset, &mut raw)? {
+            n if n == raw.len() => Ok(read_at(&raw, 0)),
+            _ => panic!("directory {} cut short", self.inode().inum()),
+        }
+    }
+
+    /// Looks `name` up in the directory, returning its inode and the
+    /// offset of its entry.
+    pub fn lookup(&self, name: &str) -> Result<(Inode, u32), FsError> {
+        if self.file_type() != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        for offset in (0..self.size).step_by(DIRENT_SIZE as usize) {
+            let dirent = self.dirent(offset)?;
+            if dirent.inum != 0 && dirent.name() == name.as_bytes() {
+                return Ok((iget(self.inode().dev(), dirent.inum as u32), offset));
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// Adds an entry for `name`, within an operation.
+    pub fn link(&mut self, name: &str, inum: u32) -> Result<(), FsError> {
+        let dirent = Dirent::new(name, inum)?;
+        if self.lookup(name).is_ok() {
+            return Err(FsError::Exists);
+        }
+        let mut offset = self.size;
+        for free in (0..self.size).step_by(DIRENT_SIZE as usize) {
+            if self.dirent(free)?.inum == 0 {
+                offset = free;
+                break;
+            }
+        }
+        let mut raw = [0; DIRENT_SIZE as usize];
+        write_at(&mut raw, 0, dirent);
+        match self.write(offset, &raw)? {
+            n if n == raw.len() => Ok(()),
+            _ => Err(FsError::NoSpace),
+        }
+    }
+
+    /// Whether the directory has no entries but `.` and `..`.
+    pub fn is_dir_empty(&self) -> Result<bool, FsError> {
+        for offset in (2 * DIRENT_SIZE..self.size).step_by(DIRENT_SIZE as usize) {
+            if self.dirent(offset)?.inum != 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Splits the first element off `path`, returning it and the rest, both
+/// without leading slashes. None if `path` has no elements.
+///
+/// ```text
+/// skip_elem("a/bb/c") = Some(("a", "bb/c"))
+/// skip_elem("///a//bb") = Some(("a", "bb"))
+/// skip_elem("a") = Some(("a", ""))
+/// skip_elem("") = skip_elem("////") = None
+/// ```
+fn skip_elem(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+    let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+    Some((name, rest.trim_start_matches('/')))
+}
+
+/// Looks `path` up, or if `parent`, its parent directory and last element.
+fn namex(path: &str, parent: bool) -> Result<(Inode, &str), FsError> {
+    let mut inode = iget(ROOT_DEV, ROOTINO);
+    let mut path = path;
+    while let Some((name, rest)) = skip_elem(path) {
+        let guard = inode.lock();
+        if guard.file_type() != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if parent && skip_elem(rest).is_none() {
+            drop(guard);
+            return Ok((inode, name));
+        }
+        let (next, _) = guard.lookup(name)?;
+        drop(guard);
+        inode = next;
+        path = rest;
+    }
+    match parent {
+        // The root has no parent
+        true => Err(FsError::Invalid),
+        false => Ok((inode, "")),
+    }
+}
+
+/// Looks `path` up, within an operation.
+pub fn namei(path: &str) -> Result<Inode, FsError> {
+    namex(path, false).map(|(inode, _)| inode)
+}
+
+/// Looks the parent directory of `path` up, within an operation,
+/// returning it and the last element of `path`.
+pub fn nameiparent(path: &str) -> Result<(Inode, &str), FsError> {
+    namex(path, true)
+}
+
+/// Creates `path`, within an operation, or returns it if it's already a
+/// file, and one is asked for.
+pub fn create(path: &str, kind: FileType, major: u16, minor: u16) -> Result<Inode, FsError> {
+    let (dir, name) = nameiparent(path)?;
+    let mut dir_guard = dir.lock();
+    match dir_guard.lookup(name) {
+        Ok((inode, _)) => {
+            drop(dir_guard);
+            let existing = inode.lock().file_type();
+            return match (kind, existing) {
+                (FileType::File, FileType::File | FileType::Device) => Ok(inode),
+                _ => Err(FsError::Exists),
+            };
+        }
+        Err(FsError::NotFound) => (),
+        Err(error) => return Err(error),
+    }
+
+    let inode = ialloc(dir.dev(), kind)?;
+    let mut guard = inode.lock();
+    guard.major = major;
+    guard.minor = minor;
+    guard.nlink = 1;
+    guard.update();
+    let linked = (|| {
+        if kind == FileType::Dir {
+            guard.link(".", inode.inum())?;
+            guard.link("..", dir.inum())?;
+        }
+        dir_guard.link(name, inode.inum())
+    })();
+    if let Err(error) = linked {
+        // Freed once dropped
+        guard.nlink = 0;
+        guard.update();
+        return Err(error);
+    }
+    if kind == FileType::Dir {
+        // For the new `..`
+        dir_guard.nlink += 1;
+        dir_guard.update();
+    }
+    drop(guard);
+    Ok(inode)
+}
+
+/// Removes the entry for `path`, within an operation, freeing its inode
+/// once unused if it was the last link. Directories have to be empty.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (dir, name) = nameiparent(path)?;
+    if name == "." || name == ".." {
+        return Err(FsError::Invalid);
+    }
+    let mut dir_guard = dir.lock();
+    let (inode, offset) = dir_guard.lookup(name)?;
+    let mut guard = inode.lock();
+    assert!(
+        guard.nlink > 0,
+        "unlink: inode {} has no links",
+        inode.inum()
+    );
+    let is_dir = guard.file_type() == FileType::Dir;
+    if is_dir && !guard.is_dir_empty()? {
+        return Err(FsError::NotEmpty);
+    }
+
+    let mut raw = [0; DIRENT_SIZE as usize];
+    write_at(&mut raw, 0, Dirent::EMPTY);
+    dir_guard.write(offset, &raw)?;
+    if is_dir {
+        // For its `..`
+        dir_guard.nlink -= 1;
+        dir_guard.update();
+    }
+    drop(dir_guard);
+    guard.nlink -= 1;
+    guard.update();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{begin_op, bitmap, end_op};
+
+    #[test_case]
+    pub fn dir_splits_paths() {
+        assert_eq!(skip_elem("a/bb/c"), Some(("a", "bb/c")));
+        assert_eq!(skip_elem("///a//bb"), Some(("a", "bb")));
+        assert_eq!(skip_elem("a/"), Some(("a", "")));
+        assert_eq!(skip_elem("////"), None);
+        assert_eq!(skip_elem(""), None);
+    }
+
+    #[test_case]
+    pub fn dir_entries_hold_16_bit_inums() {
+        assert_eq!(Dirent::new("a", u16::MAX as u32).unwrap().inum, u16::MAX);
+        assert_eq!(
+            Dirent::new("a", u16::MAX as u32 + 1).err(),
+            Some(FsError::Invalid)
+        );
+    }
+
+    #[test_case]
+    pub fn dir_creates_and_looks_up_paths() {
+        let free = bitmap::free_blocks(ROOT_DEV);
+        begin_op();
+        let dir = create("/dir-test", FileType::Dir, 0, 0).unwrap();
+        let file = create("/dir-test/file", FileType::File, 0, 0).unwrap();
+        file.lock().write(0, b"hello").unwrap();
+        end_op();
+
+        begin_op();
+        let found = namei("//dir-test/./file").unwrap();
+        assert_eq!(found.inum(), file.inum());
+        let mut data = [0; 8];
+        assert_eq!(found.lock().read(0, &mut data), Ok(5));
+        assert_eq!(&data[..5], b"hello");
+        assert_eq!(namei("/dir-test/..").unwrap().inum(), ROOTINO);
+        assert_eq!(namei("/").unwrap().inum(), ROOTINO);
+        assert_eq!(namei("/dir-test/nope").err(), Some(FsError::NotFound));
+        assert_eq!(namei("/dir-test/file/x").err(), Some(FsError::NotDir));
+
+        let (parent, name) = nameiparent("/dir-test/file").unwrap();
+        assert_eq!((parent.inum(), name), (dir.inum(), "file"));
+        assert_eq!(dir.lock().stat().nlink, 1);
+        assert_eq!(namei("/").unwrap().lock().nlink, 2, "for `..` of /dir-test");
+
+        // An existing file is opened, anything else is in the way
+        assert_eq!(
+            create("/dir-test/file", FileType::File, 0, 0).map(|inode| inode.inum()),
+            Ok(file.inum())
+        );
+        assert_eq!(
+            create("/dir-test", FileType::Dir, 0, 0).err(),
+            Some(FsError::Exists)
+        );
+        assert_eq!(
+            create("/dir-test/fifteen-letters", FileType::File, 0, 0).err(),
+            Some(FsError::NameTooLong)
+        );
+        drop((found, parent));
+        end_op();
+
+        begin_op();
+        assert_eq!(unlink("/dir-test"), Err(FsError::NotEmpty));
+        assert_eq!(unlink("/dir-test/.."), Err(FsError::Invalid));
+        unlink("/dir-test/file").unwrap();
+        unlink("/dir-test").unwrap();
+        assert_eq!(namei("/dir-test").err(), Some(FsError::NotFound));
+        drop((file, dir));
+        end_op();
+        assert_eq!(namei_root_nlink(), 1);
+        assert_eq!(bitmap::free_blocks(ROOT_DEV), free);
+    }
+
+    fn namei_root_nlink() -> u16 {
+        begin_op();
+        let nlink = namei("/").unwrap().lock().nlink;
+        end_op();
+        nlink
+    }
+}
@@ -0,0 +1,516 @@
+//! Inodes, as in xv6: the table of those in use, and their contents.
+//!
+//! [`Inode`]s are references into the table, counted so that an inode
+//! stays put while anything refers to it, and freed on disk once the last
+//! reference to one without links goes: dropping an `Inode` may write, so
+//! happens within an operation. Its fields are only read from the disk,
+//! and only changed, with the inode locked through [`Inode::lock`].
+//!
+//! An inode's contents are its [`NDIRECT`] direct blocks, then the
+//! [`NINDIRECT`] listed in its indirect block, allocated as they're
+//! written.
+
+use core::ops::{Deref, DerefMut};
+
+use super::{
+    BSIZE, DInode, FsError, MAXFILE, NDIRECT, NINDIRECT, NINODE,
+    bio::bread,
+    bitmap::{balloc, bfree},
+    log::log_write,
+    read_at, superblock, write_at,
+};
+use crate::{
+    dev::DeviceNumber,
+    utils::sync::{Mutex, MutexGuard, SpinLock},
+};
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Dir = 1,
+    File = 2,
+    Device = 3,
+}
+
+impl FileType {
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        match raw {
+            1 => Some(Self::Dir),
+            2 => Some(Self::File),
+            3 => Some(Self::Device),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub dev: DeviceNumber,
+    pub ino: u32,
+    pub kind: FileType,
+    pub nlink: u16,
+    pub size: u32,
+}
+
+/// An inode's copy of its fields, behind its sleeping lock.
+pub struct InodeData {
+    /// Whether `dinode` was read from the disk yet.
+    valid: bool,
+    dinode: DInode,
+}
+
+/// Which inode a table slot holds, behind the table lock.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    dev: Option<DeviceNumber>,
+    inum: u32,
+    refcnt: usize,
+}
+
+static ITABLE: SpinLock<[Entry; NINODE]> = SpinLock::new(
+    "ITABLE",
+    [Entry {
+        dev: None,
+        inum: 0,
+        refcnt: 0,
+    }; NINODE],
+);
+static INODES: [Mutex<InodeData>; NINODE] = [const {
+    Mutex::new(
+        "INODE",
+        InodeData {
+            valid: false,
+            dinode: DInode::EMPTY,
+        },
+    )
+}; NINODE];
+
+/// Reference to an inode in the table.
+#[derive(Debug)]
+pub struct Inode {
+    index: usize,
+    dev: DeviceNumber,
+    inum: u32,
+}
+
+impl Inode {
+    pub fn dev(&self) -> DeviceNumber {
+        self.dev
+    }
+
+    pub fn inum(&self) -> u32 {
+        self.inum
+    }
+
+    /// Locks the inode, reading it from the disk if needed.
+    pub fn lock(&self) -> InodeGuard<'_> {
+        let mut data = INODES[self.index].lock();
+        if !data.valid {
+            let buf = bread(self.dev, superblock().iblock(self.inum));
+            data.dinode = read_at(&*buf, dinode_offset(self.inum));
+            data.valid = true;
+            assert!(data.dinode.kind != 0, "ilock: inode {} is free", self.inum);
+        }
+        InodeGuard { inode: self, data }
+    }
+}
+
+impl Clone for Inode {
+    fn clone(&self) -> Self {
+        ITABLE.lock()[self.index].refcnt += 1;
+        Self {
+            index: self.index,
+            dev: self.dev,
+            inum: self.inum,
+        }
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        let mut table = ITABLE.lock();
+        if table[self.index].refcnt == 1 {
+            // No other reference, so nobody holds the lock
+            let data = INODES[self.index]
+                .try_lock()
+                .expect("unreferenced inodes should be unlocked");
+            if data.valid && data.dinode.nlink == 0 {
+                // Nor can anyone find it, without links: free it
+                drop(table);
+                let mut inode = InodeGuard { inode: self, data };
+                inode.truncate();
+                inode.kind = 0;
+                inode.update();
+                inode.data.valid = false;
+                drop(inode);
+                table = ITABLE.lock();
+            }
+        }
+        table[self.index].refcnt -= 1;
+    }
+}
+
+/// Offset of inode `inum` in its block.
+fn dinode_offset(inum: u32) -> usize {
+    (inum % super::IPB) as usize * size_of::<DInode>()
+}
+
+/// Returns a reference to inode `inum` of `dev`, without locking it or
+/// reading it from the disk.
+pub fn iget(dev: DeviceNumber, inum: u32) -> Inode {
+    let mut table = ITABLE.lock();
+    let cached = table
+        .iter()
+        .position(|entry| entry.refcnt > 0 && entry.dev == Some(dev) && entry.inum == inum);
+    let index = match cached {
+        Some(index) => {
+            table[index].refcnt += 1;
+            index
+        }
+        None => {
+            let index = table
+                .iter()
+                .position(|entry| entry.refcnt == 0)
+                .expect("iget: no inodes");
+            table[index] = Entry {
+                dev: Some(dev),
+                inum,
+                refcnt: 1,
+            };
+            INODES[index]
+                .try_lock()
+                .expect("unreferenced inodes should be unlocked")
+                .valid = false;
+            index
+        }
+    };
+    Inode { index, dev, inum }
+}
+
+/// Allocates an inode of type `kind` on `dev`, within an operation.
+pub fn ialloc(dev: DeviceNumber, kind: FileType) -> Result<Inode, FsError> {
+    let sb = superblock();
+    for inum in 1..sb.ninodes {
+        let mut buf = bread(dev, sb.iblock(inum));
+        let offset = dinode_offset(inum);
+        if read_at::<DInode>(&*buf, offset).kind == 0 {
+            let dinode = DInode {
+                kind: kind as u16,
+                ..DInode::EMPTY
+            };
+            write_at(&mut *buf, offset, dinode);
+            log_write(&buf);
+            drop(buf);
+            return Ok(iget(dev, inum));
+        }
+    }
+    Err(FsError::NoInodes)
+}
+
+/// Locked inode, whose fields are those of [`DInode`].
+pub struct InodeGuard<'inode> {
+    inode: &'inode Inode,
+    data: MutexGuard<'static, InodeData>,
+}
+
+impl Deref for InodeGuard<'_> {
+    type Target = DInode;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data.dinode
+    }
+}
+
+impl DerefMut for InodeGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data.dinode
+    }
+}
+
+impl InodeGuard<'_> {
+    pub fn inode(&self) -> &Inode {
+        self.inode
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_raw(self.kind).expect("locked inodes should be in use")
+    }
+
+    /// Writes the fields back, within an operation. Needed after each
+    /// change to them.
+    pub fn update(&mut self) {
+        let (dev, inum) = (self.inode.dev, self.inode.inum);
+        let mut buf = bread(dev, superblock().iblock(inum));
+        write_at(&mut *buf, dinode_offset(inum), self.data.dinode);
+        log_write(&buf);
+    }
+
+    /// Frees the contents, within an operation.
+    pub fn truncate(&mut self) {
+        let dev = self.inode.dev;
+        for addr in self.addrs[..NDIRECT].iter_mut().filter(|addr| **addr != 0) {
+            bfree(dev, *addr);
+            *addr = 0;
+        }
+        if self.addrs[NDIRECT] != 0 {
+            let buf = bread(dev, self.addrs[NDIRECT]);
+            for i in 0..NINDIRECT {
+                let addr: u32 = read_at(&*buf, i * size_of::<u32>());
+                if addr != 0 {
+                    bfree(dev, addr);
+                }
+            }
+            drop(buf);
+            bfree(dev, self.addrs[NDIRECT]);
+            self.addrs[NDIRECT] = 0;
+        }
+        self.size = 0;
+        self.update();
+    }
+
+    /// Disk block for block `bn` of the contents, allocated if there's
+    /// none yet.
+    fn bmap(&mut self, bn: usize) -> Result<u32, FsError> {
+        let dev = self.inode.dev;
+        if bn < NDIRECT {
+            if self.addrs[bn] == 0 {
+                self.addrs[bn] = balloc(dev)?;
+            }
+            return Ok(self.addrs[bn]);
+        }
+        let bn = bn - NDIRECT;
+        if bn >= NINDIRECT {
+            return Err(FsError::TooBig);
+        }
+        if self.addrs[NDIRECT] == 0 {
+            self.addrs[NDIRECT] = balloc(dev)?;
+        }
+        let mut buf = bread(dev, self.addrs[NDIRECT]);
+        let offset = bn * size_of::<u32>();
+        let mut addr: u32 = read_at(&*buf, offset);
+        if addr == 0 {
+            addr = balloc(dev)?;
+            write_at(&mut *buf, offset, addr);
+            log_write(&buf);
+        }
+        Ok(addr)
+    }
+
+    /// Disk block for block `bn` of the contents, or None if there's none
+    /// yet, i.e. a hole reading as zeroes. Unlike [`Self::bmap`], fine
+    /// outside operations.
+    fn bmap_read(&self, bn: usize) -> Result<Option<u32>, FsError> {
+        let addr = match bn.checked_sub(NDIRECT) {
+            None => self.addrs[bn],
+            Some(bn) if bn >= NINDIRECT => return Err(FsError::TooBig),
+            Some(_) if self.addrs[NDIRECT] == 0 => 0,
+            Some(bn) => read_at(
+                &*bread(self.inode.dev, self.addrs[NDIRECT]),
+                bn * size_of::<u32>(),
+            ),
+        };
+        Ok(Some(addr).filter(|&addr| addr != 0))
+    }
+
+    /// Reads from `offset` into `dst`, as much as there is. Returns how
+    /// much that was.
+    pub fn read(&self, offset: u32, dst: &mut [u8]) -> Result<usize, FsError> {
+        let size = self.size as usize;
+        let offset = offset as usize;
+        if offset > size {
+            return Ok(0);
+        }
+        let n = dst.len().min(size - offset);
+        let mut done = 0;
+        while done < n {
+            let pos = offset + done;
+            let start = pos % BSIZE;
+            let len = (n - done).min(BSIZE - start);
+            let dst = &mut dst[done..done + len];
+            match self.bmap_read(pos / BSIZE)? {
+                Some(blockno) => {
+                    dst.copy_from_slice(&bread(self.inode.dev, blockno)[start..start + len])
+                }
+                None => dst.fill(0),
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    /// Writes `src` from `offset` on, within an operation, extending the
+    /// file if it goes past the end. Returns how much was written, all of
+    /// it unless the disk is full.
+    pub fn write(&mut self, offset: u32, src: &[u8]) -> Result<usize, FsError> {
+        let offset = offset as usize;
+        if offset > self.size as usize {
+            return Err(FsError::Invalid);
+        }
+        if offset + src.len() > MAXFILE * BSIZE {
+            return Err(FsError::TooBig);
+        }
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < src.len() {
+            let pos = offset + done;
+            let blockno = match self.bmap(pos / BSIZE) {
+                Ok(blockno) => blockno,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            };
+            let mut buf = bread(self.inode.dev, blockno);
+            let start = pos % BSIZE;
+            let len = (src.len() - done).min(BSIZE - start);
+            buf[start..start + len].copy_from_slice(&src[done..done + len]);
+            log_write(&buf);
+            done += len;
+        }
+        self.size = self.size.max((offset + done) as u32);
+        // Even if the size didn't change, `bmap` may have added blocks
+        self.update();
+        match (done, result) {
+            (0, Err(error)) => Err(error),
+            _ => Ok(done),
+        }
+    }
+
+    pub fn stat(&self) -> Stat {
+        Stat {
+            dev: self.inode.dev,
+            ino: self.inode.inum,
+            kind: self.file_type(),
+            nlink: self.nlink,
+            size: self.size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{ROOT_DEV, begin_op, bitmap, end_op};
+
+    #[test_case]
+    pub fn inode_maps_direct_and_indirect_blocks() {
+        let free = bitmap::free_blocks(ROOT_DEV);
+        begin_op();
+        let inode = ialloc(ROOT_DEV, FileType::File).unwrap();
+        inode.lock().nlink = 1;
+        inode.lock().update();
+        end_op();
+
+        // A block at a time, as an operation only writes so many
+        let mut chunk = [0; 512];
+        for bn in 0..=NDIRECT as u32 {
+            begin_op();
+            let mut guard = inode.lock();
+            for (i, offset) in (bn * BSIZE as u32..)
+                .step_by(chunk.len())
+                .take(8)
+                .enumerate()
+            {
+                chunk.fill((bn + i as u32) as u8);
+                assert_eq!(guard.write(offset, &chunk), Ok(chunk.len()));
+            }
+            drop(guard);
+            end_op();
+        }
+        let mut guard = inode.lock();
+        assert_eq!(guard.size as usize, (NDIRECT + 1) * BSIZE);
+        assert!(
+            guard.addrs[NDIRECT] != 0,
+            "the last block should be indirect"
+        );
+        // Data blocks, and the indirect block
+        assert_eq!(bitmap::free_blocks(ROOT_DEV), free - NDIRECT as u32 - 2);
+
+        for bn in [0, NDIRECT as u32 - 1, NDIRECT as u32] {
+            assert_eq!(
+                guard.read(bn * BSIZE as u32 + 1000, &mut chunk),
+                Ok(chunk.len())
+            );
+            assert!(chunk.iter().all(|&byte| byte == (bn + 1) as u8));
+        }
+        let end = guard.size;
+        assert_eq!(guard.read(end - 10, &mut chunk), Ok(10));
+        assert_eq!(guard.read(end + 1, &mut chunk), Ok(0));
+        assert_eq!(guard.write(end + 1, &chunk), Err(FsError::Invalid));
+        assert_eq!(
+            guard.write((MAXFILE * BSIZE) as u32, &chunk),
+            Err(FsError::TooBig)
+        );
+        drop(guard);
+
+        // Freed with the last reference, once unlinked
+        begin_op();
+        let mut guard = inode.lock();
+        guard.nlink = 0;
+        guard.update();
+        drop(guard);
+        drop(inode);
+        end_op();
+        assert_eq!(bitmap::free_blocks(ROOT_DEV), free);
+    }
+
+    #[test_case]
+    pub fn inode_reads_holes_as_zeroes() {
+        begin_op();
+        let inode = ialloc(ROOT_DEV, FileType::File).unwrap();
+        let mut guard = inode.lock();
+        guard.nlink = 1;
+        // Past the direct blocks, with none allocated
+        guard.size = ((NDIRECT + 1) * BSIZE) as u32;
+        guard.update();
+        drop(guard);
+        end_op();
+
+        // Outside an operation, so reads mustn't allocate
+        let free = bitmap::free_blocks(ROOT_DEV);
+        let mut chunk = [0xFF; 512];
+        let guard = inode.lock();
+        for offset in [0, (NDIRECT * BSIZE) as u32] {
+            assert_eq!(guard.read(offset, &mut chunk), Ok(chunk.len()));
+            assert!(chunk.iter().all(|&byte| byte == 0));
+        }
+        drop(guard);
+        assert_eq!(bitmap::free_blocks(ROOT_DEV), free);
+
+        begin_op();
+        let mut guard = inode.lock();
+        guard.nlink = 0;
+        guard.update();
+        drop(guard);
+        drop(inode);
+        end_op();
+    }
+
+    #[test_case]
+    pub fn inode_references_are_shared() {
+        begin_op();
+        let inode = ialloc(ROOT_DEV, FileType::File).unwrap();
+        let inum = inode.inum();
+        let other = iget(ROOT_DEV, inum);
+        let mut guard = other.lock();
+        guard.nlink = 1;
+        guard.update();
+        drop(guard);
+        assert_eq!(inode.lock().nlink, 1, "both should share one copy");
+        let clone = inode.clone();
+        drop(inode);
+        drop(other);
+        assert_eq!(clone.lock().stat().kind, FileType::File);
+
+        clone.lock().nlink = 0;
+        drop(clone);
+        end_op();
+        begin_op();
+        let reused = ialloc(ROOT_DEV, FileType::File).unwrap();
+        assert_eq!(reused.inum(), inum, "freed inodes should be reused");
+        // Read, so it's known to have no links
+        reused.lock();
+        drop(reused);
+        end_op();
+    }
+}
@@ -0,0 +1,303 @@
+//! Write-ahead log, as in xv6. Operations bracket their writes with
+//! [`begin_op`] and [`end_op`], and use [`log_write`] instead of
+//! [`bwrite`]. Once no operation is left running, the blocks they wrote
+//! are committed together: copied to the log, which the header then
+//! marks as complete, and only then written to their home locations. A
+//! crash before the header is written loses the whole transaction; one
+//! after it is made up for by replaying the log on mount.
+//!
+//! Blocks stay pinned in the cache until written to their homes, so
+//! uncommitted writes are never lost to eviction, nor written early.
+
+use super::{
+    BSIZE, FsError, LOGSIZE, MAXOPBLOCKS, OnDisk, ROOT_DEV, SuperBlock,
+    bio::{self, Buf, bread, bwrite},
+    read_at, write_at,
+};
+use crate::{
+    dev::DeviceNumber,
+    proc,
+    utils::sync::{SpinLock, WaitQueue},
+};
+
+/// First log block: which blocks the rest hold copies of.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct LogHeader {
+    /// Blocks logged, 0 when there's nothing to replay.
+    n: u32,
+    /// Home locations of the logged blocks.
+    block: [u32; LOGSIZE],
+}
+
+unsafe impl OnDisk for LogHeader {}
+
+const _: () = assert!(size_of::<LogHeader>() <= BSIZE);
+
+struct Log {
+    dev: DeviceNumber,
+    /// Block of the header.
+    start: u32,
+    /// Blocks, header included.
+    size: u32,
+    /// Blocks in the file system, which logged blocks' homes are within.
+    fs_size: u32,
+    /// Operations running.
+    outstanding: usize,
+    committing: bool,
+    header: LogHeader,
+    commits: u64,
+    /// Processes waiting for a commit to end, or for room in the log.
+    waiters: WaitQueue,
+}
+
+static LOG: SpinLock<Log> = SpinLock::new(
+    "LOG",
+    Log {
+        dev: ROOT_DEV,
+        start: 0,
+        size: 0,
+        fs_size: 0,
+        outstanding: 0,
+        committing: false,
+        header: LogHeader {
+            n: 0,
+            block: [0; LOGSIZE],
+        },
+        commits: 0,
+        waiters: WaitQueue::new(),
+    },
+);
+
+/// Sets the log up on mount, replaying it if a crash left it complete.
+pub(super) fn init(dev: DeviceNumber, sb: &SuperBlock) -> Result<(), FsError> {
+    let mut log = LOG.lock();
+    log.dev = dev;
+    log.start = sb.logstart;
+    log.size = sb.nlog;
+    log.fs_size = sb.size;
+    drop(log);
+    recover()
+}
+
+fn read_head(dev: DeviceNumber, start: u32) -> LogHeader {
+    read_at(&*bread(dev, start), 0)
+}
+
+/// Writes the header, committing the transaction if it lists blocks.
+fn write_head(dev: DeviceNumber, start: u32, header: &LogHeader) {
+    let mut buf = bread(dev, start);
+    write_at(&mut *buf, 0, *header);
+    bwrite(&mut buf);
+}
+
+/// Copies the logged blocks from the cache into the log.
+fn write_log(dev: DeviceNumber, start: u32, header: &LogHeader) {
+    for (i, &block) in header.block[..header.n as usize].iter().enumerate() {
+        let mut to = bread(dev, start + 1 + i as u32);
+        let from = bread(dev, block);
+        to.copy_from_slice(&*from);
+        bwrite(&mut to);
+    }
+}
+
+/// Copies the logged blocks from the log to their home locations.
+fn install_trans(dev: DeviceNumber, start: u32, header: &LogHeader, recovering: bool) {
+    for (i, &block) in header.block[..header.n as usize].iter().enumerate() {
+        let from = bread(dev, start + 1 + i as u32);
+        let mut to = bread(dev, block);
+        to.copy_from_slice(&*from);
+        bwrite(&mut to);
+        if !recovering {
+            bio::bunpin(&to);
+        }
+    }
+}
+
+/// Replays the log, if it holds a committed transaction. Fails with
+/// [`FsError::Corrupt`] if the header lists more blocks than fit, or
+/// homes outside the file system or inside the log itself.
+pub(super) fn recover() -> Result<(), FsError> {
+    let (dev, start, size, fs_size) = {
+        let log = LOG.lock();
+        (log.dev, log.start, log.size, log.fs_size)
+    };
+    let header = read_head(dev, start);
+    if header.n as usize > LOGSIZE || header.n >= size {
+        return Err(FsError::Corrupt);
+    }
+    let in_log = |block: u32| block >= start && block - start < size;
+    if header.block[..header.n as usize]
+        .iter()
+        .any(|&block| block >= fs_size || in_log(block))
+    {
+        return Err(FsError::Corrupt);
+    }
+    install_trans(dev, start, &header, true);
+    write_head(dev, start, &LogHeader { n: 0, ..header });
+    Ok(())
+}
+
+fn commit(dev: DeviceNumber, start: u32, header: &LogHeader) {
+    if header.n > 0 {
+        write_log(dev, start, header);
+        // The commit point
+        write_head(dev, start, header);
+        install_trans(dev, start, header, false);
+        write_head(dev, start, &LogHeader { n: 0, ..*header });
+    }
+}
+
+/// Starts a file system operation, waiting until the log has room for
+/// as much as it may write.
+pub fn begin_op() {
+    let blockable = proc::blockable_pid();
+    let mut log = LOG.lock();
+    loop {
+        let reserved = log.header.n as usize + (log.outstanding + 1) * MAXOPBLOCKS;
+        if !log.committing && reserved <= LOGSIZE {
+            log.outstanding += 1;
+            return;
+        }
+        let me = blockable.expect("begin_op: can't wait for the log outside a process");
+        log.waiters.push(me);
+        proc::block(log);
+        log = LOG.lock();
+        log.waiters.remove(me);
+    }
+}
+
+/// Ends a file system operation, committing if it was the last running.
+pub fn end_op() {
+    let mut log = LOG.lock();
+    assert!(log.outstanding > 0, "end_op: no operation running");
+    assert!(!log.committing, "end_op: committing");
+    log.outstanding -= 1;
+    if log.outstanding > 0 {
+        // Its reservation may be what a waiting `begin_op` needs
+        log.waiters.wake_all();
+        return;
+    }
+    log.committing = true;
+    let (dev, start, header) = (log.dev, log.start, log.header);
+    drop(log);
+
+    // Nothing else touches the header while committing
+    commit(dev, start, &header);
+
+    let mut log = LOG.lock();
+    log.header.n = 0;
+    log.committing = false;
+    log.commits += 1;
+    log.waiters.wake_all();
+}
+
+/// Records that `buf` was modified, to be written when the operation
+/// commits. Replaces [`bwrite`] within operations.
+pub fn log_write(buf: &Buf) {
+    let mut log = LOG.lock();
+    let n = log.header.n as usize;
+    assert!(
+        n < LOGSIZE && n + 1 < log.size as usize,
+        "log_write: transaction too big"
+    );
+    assert!(log.outstanding > 0, "log_write: outside of an operation");
+    // Absorbs repeated writes to a block
+    if !log.header.block[..n].contains(&buf.blockno()) {
+        log.header.block[n] = buf.blockno();
+        log.header.n += 1;
+        bio::bpin(buf);
+    }
+}
+
+/// Transactions committed since boot.
+pub fn commits() -> u64 {
+    LOG.lock().commits
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        fs::{self, FileType, bitmap},
+        proc::PROCESSES,
+        test::TEST_QUANTA,
+    };
+
+    #[test_case]
+    pub fn log_recovers_committed_transactions() {
+        let (dev, start) = {
+            let log = LOG.lock();
+            (log.dev, log.start)
+        };
+        // A free block, so nothing else minds it being written
+        begin_op();
+        let home = bitmap::balloc(dev).unwrap();
+        end_op();
+
+        // As if a crash came right after the commit point
+        let mut logged = bread(dev, start + 1);
+        logged.fill(0xAB);
+        bwrite(&mut logged);
+        drop(logged);
+        let mut header = LogHeader {
+            n: 1,
+            block: [0; LOGSIZE],
+        };
+        header.block[0] = home;
+        write_head(dev, start, &header);
+
+        recover().unwrap();
+        assert!(bread(dev, home).iter().all(|&byte| byte == 0xAB));
+        assert_eq!(read_head(dev, start).n, 0, "the log should be emptied");
+
+        // A header listing more blocks than the log holds
+        header.n = LOGSIZE as u32 + 1;
+        write_head(dev, start, &header);
+        assert_eq!(recover(), Err(FsError::Corrupt));
+        // Or homes past the end of the file system, or in the log
+        let fs_size = LOG.lock().fs_size;
+        for bad in [fs_size, u32::MAX, start, start + 1] {
+            header.n = 1;
+            header.block[0] = bad;
+            write_head(dev, start, &header);
+            assert_eq!(recover(), Err(FsError::Corrupt), "home {}", bad);
+        }
+        header.n = 0;
+        write_head(dev, start, &header);
+
+        begin_op();
+        bitmap::bfree(dev, home);
+        end_op();
+    }
+
+    const NAMES: [&str; 3] = ["/group-a", "/group-b", "/group-c"];
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    fn create_file() {
+        begin_op();
+        // Let every other process begin theirs
+        proc::yield_self();
+        let name = NAMES[NEXT.fetch_add(1, Ordering::SeqCst)];
+        drop(fs::create(name, FileType::File, 0, 0).unwrap());
+        end_op();
+    }
+
+    #[test_case]
+    pub fn log_commits_concurrent_operations_together() {
+        let before = commits();
+        for _ in NAMES {
+            PROCESSES.create(create_file);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(commits(), before + 1, "operations should commit as a group");
+
+        begin_op();
+        for name in NAMES {
+            fs::unlink(name).unwrap();
+        }
+        end_op();
+    }
+}
@@ -0,0 +1,93 @@
+//! Formatting, as xv6's `mkfs` does on the host: there's no such tool
+//! here, so the kernel formats the disk itself when booted with
+//! `fs.format`, with an empty root directory. Writes straight to the
+//! disk, bypassing the log, so only runs before mounting.
+
+use super::{
+    BPB, DInode, Dirent, FSMAGIC, IPB, LOGSIZE, NDIRECT, ROOTINO, SUPERBLOCK_BLOCK, SuperBlock,
+    bio::{bread, bwrite},
+    inode::FileType,
+    write_at,
+};
+use crate::dev::DeviceNumber;
+
+/// Inodes a new file system has room for.
+pub const NINODES: u32 = 200;
+
+// Directory entries hold 16-bit inode numbers
+const _: () = assert!(NINODES <= u16::MAX as u32);
+
+/// Lays a new file system out on the first `size` blocks of `dev`.
+pub fn mkfs(dev: DeviceNumber, size: u32) {
+    let nlog = LOGSIZE as u32 + 1;
+    let ninodeblocks = NINODES / IPB + 1;
+    let nbitmap = size / BPB + 1;
+    let logstart = SUPERBLOCK_BLOCK + 1;
+    let inodestart = logstart + nlog;
+    let bmapstart = inodestart + ninodeblocks;
+    let nmeta = bmapstart + nbitmap;
+    assert!(nmeta < size, "mkfs: {} blocks is too small", size);
+    let sb = SuperBlock {
+        magic: FSMAGIC,
+        size,
+        nblocks: size - nmeta,
+        ninodes: NINODES,
+        nlog,
+        logstart,
+        inodestart,
+        bmapstart,
+    };
+
+    // An empty log, no inodes in use and no blocks allocated, so far
+    for blockno in 0..nmeta {
+        let mut buf = bread(dev, blockno);
+        buf.fill(0);
+        if blockno == SUPERBLOCK_BLOCK {
+            write_at(&mut *buf, 0, sb);
+        }
+        bwrite(&mut buf);
+    }
+
+    // The root directory, in the first data block
+    let root_block = nmeta;
+    let mut buf = bread(dev, root_block);
+    buf.fill(0);
+    let entries = [
+        Dirent::new(".", ROOTINO).unwrap(),
+        Dirent::new("..", ROOTINO).unwrap(),
+    ];
+    for (i, dirent) in entries.into_iter().enumerate() {
+        write_at(&mut *buf, i * size_of::<Dirent>(), dirent);
+    }
+    bwrite(&mut buf);
+    drop(buf);
+
+    let mut buf = bread(dev, sb.iblock(ROOTINO));
+    let mut addrs = [0; NDIRECT + 1];
+    addrs[0] = root_block;
+    let root = DInode {
+        kind: FileType::Dir as u16,
+        major: 0,
+        minor: 0,
+        nlink: 1,
+        size: (entries.len() * size_of::<Dirent>()) as u32,
+        addrs,
+    };
+    write_at(
+        &mut *buf,
+        (ROOTINO % IPB) as usize * size_of::<DInode>(),
+        root,
+    );
+    bwrite(&mut buf);
+    drop(buf);
+
+    // Metadata and the root directory are in use
+    let used = root_block + 1;
+    for base in (0..used).step_by(BPB as usize) {
+        let mut buf = bread(dev, sb.bblock(base));
+        for bit in 0..(used - base).min(BPB) {
+            buf[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+        bwrite(&mut buf);
+    }
+}
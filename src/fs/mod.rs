@@ -0,0 +1,334 @@
+//! File system, layered as in xv6. From the bottom up:
+//!
+//! - [`bio`]: cache of disk blocks, each locked by one process at a time.
+//! - [`log`]: groups the writes of operations into transactions, so a
+//!   crash leaves every operation either done or not at all.
+//! - [`bitmap`]: allocation of data blocks.
+//! - [`inode`]: files, as the blocks they're made of.
+//! - [`dir`]: directories, and path lookup.
+//!
+//! On disk, in 4KiB blocks:
+//!
+//! ```text
+//! [ boot | superblock | log header | log ... | inodes ... | bitmap ... | data ... ]
+//! ```
+
+use crate::{
+    dev::{self, DeviceNumber},
+    info, param,
+    utils::cells::Once,
+    virtio::blk,
+    warn,
+};
+
+pub mod bio;
+pub mod bitmap;
+pub mod dir;
+pub mod inode;
+pub mod log;
+pub mod mkfs;
+
+pub use dir::{create, namei, nameiparent, unlink};
+pub use inode::{FileType, Inode, InodeGuard, Stat};
+pub use log::{begin_op, end_op};
+
+/// Size of the blocks the file system reads and writes.
+pub const BSIZE: usize = blk::BLOCK_SIZE;
+
+/// Disk the file system lives on.
+pub const ROOT_DEV: DeviceNumber = DeviceNumber {
+    major: dev::MAJOR_VIRTIO_BLK,
+    minor: 0,
+};
+
+pub const FSMAGIC: u32 = 0x1020_3040;
+/// Block the superblock is in, after the boot block.
+pub const SUPERBLOCK_BLOCK: u32 = 1;
+/// Inode of the root directory.
+pub const ROOTINO: u32 = 1;
+/// Blocks an inode maps directly, the next one mapping the rest.
+pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
+/// Largest file, in blocks.
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+/// Longest name in a directory.
+pub const DIRSIZ: usize = 14;
+/// Most blocks an operation may write.
+pub const MAXOPBLOCKS: usize = 10;
+/// Most blocks a transaction may write: those of a few operations.
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
+/// Inodes in use at once, in memory.
+pub const NINODE: usize = 50;
+/// Inodes per block.
+pub const IPB: u32 = (BSIZE / size_of::<DInode>()) as u32;
+/// Bitmap bits per block.
+pub const BPB: u32 = (BSIZE * 8) as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    Exists,
+    NotDir,
+    /// Unlinking a directory with entries.
+    NotEmpty,
+    NameTooLong,
+    /// Out of data blocks.
+    NoSpace,
+    NoInodes,
+    /// Past [`MAXFILE`] blocks.
+    TooBig,
+    /// E.g. writing past the end of a file, or unlinking `.`.
+    Invalid,
+    /// On-disk structures that don't add up, e.g. a superblock larger
+    /// than the disk, or a log header listing more blocks than the log
+    /// has.
+    Corrupt,
+}
+
+/// Structure stored in blocks as is.
+///
+/// # Safety
+///
+/// Must be `repr(C)` without padding, and valid for any bytes.
+pub unsafe trait OnDisk: Copy {}
+
+/// Block numbers, as in indirect blocks.
+unsafe impl OnDisk for u32 {}
+
+/// Reads a `T` from `bytes` at `offset`.
+pub fn read_at<T: OnDisk>(bytes: &[u8], offset: usize) -> T {
+    assert!(offset + size_of::<T>() <= bytes.len());
+    // SAFETY: in bounds, and any bytes make a valid `T`
+    unsafe { (bytes.as_ptr().add(offset) as *const T).read_unaligned() }
+}
+
+/// Writes `value` into `bytes` at `offset`.
+pub fn write_at<T: OnDisk>(bytes: &mut [u8], offset: usize, value: T) {
+    assert!(offset + size_of::<T>() <= bytes.len());
+    // SAFETY: in bounds
+    unsafe { (bytes.as_mut_ptr().add(offset) as *mut T).write_unaligned(value) }
+}
+
+/// Where everything is on disk.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SuperBlock {
+    pub magic: u32,
+    /// In blocks.
+    pub size: u32,
+    pub nblocks: u32,
+    pub ninodes: u32,
+    /// Log blocks, header included.
+    pub nlog: u32,
+    pub logstart: u32,
+    pub inodestart: u32,
+    pub bmapstart: u32,
+}
+
+unsafe impl OnDisk for SuperBlock {}
+
+impl SuperBlock {
+    /// Block inode `inum` is in.
+    pub fn iblock(&self, inum: u32) -> u32 {
+        inum / IPB + self.inodestart
+    }
+
+    /// Bitmap block with the bit of block `blockno`.
+    pub fn bblock(&self, blockno: u32) -> u32 {
+        blockno / BPB + self.bmapstart
+    }
+
+    /// Checks the layout adds up, as [`mkfs`](mkfs::mkfs) lays it out
+    /// after the superblock: the log, inodes, bitmap, then data blocks,
+    /// all on a disk of `disk_blocks`. Fails with [`FsError::Corrupt`].
+    pub fn check(&self, disk_blocks: u32) -> Result<(), FsError> {
+        // Wide enough that nothing overflows
+        let [logstart, nlog, inodestart, bmapstart, size] = [
+            self.logstart,
+            self.nlog,
+            self.inodestart,
+            self.bmapstart,
+            self.size,
+        ]
+        .map(u64::from);
+        let ninodeblocks = u64::from(self.ninodes / IPB + 1);
+        let nmeta = bmapstart + size / u64::from(BPB) + 1;
+        let fits = size <= u64::from(disk_blocks)
+            && logstart > u64::from(SUPERBLOCK_BLOCK)
+            && nlog >= 1
+            && logstart + nlog <= inodestart
+            && inodestart + ninodeblocks <= bmapstart
+            && nmeta + u64::from(self.nblocks) <= size;
+        match fits {
+            true => Ok(()),
+            false => Err(FsError::Corrupt),
+        }
+    }
+}
+
+/// Inode on disk.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DInode {
+    /// A [`FileType`], or 0 if free.
+    pub kind: u16,
+    /// Device, for [`FileType::Device`].
+    pub major: u16,
+    pub minor: u16,
+    /// Directory entries for it.
+    pub nlink: u16,
+    /// In bytes.
+    pub size: u32,
+    /// Data blocks: [`NDIRECT`] of them, then the indirect block.
+    pub addrs: [u32; NDIRECT + 1],
+}
+
+unsafe impl OnDisk for DInode {}
+
+impl DInode {
+    pub const EMPTY: Self = Self {
+        kind: 0,
+        major: 0,
+        minor: 0,
+        nlink: 0,
+        size: 0,
+        addrs: [0; NDIRECT + 1],
+    };
+}
+
+/// Directory entry: a directory is a file of these.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Dirent {
+    /// 0 if free.
+    pub inum: u16,
+    /// Padded with zeroes, unless [`DIRSIZ`] long.
+    pub name: [u8; DIRSIZ],
+}
+
+unsafe impl OnDisk for Dirent {}
+
+impl Dirent {
+    pub const EMPTY: Self = Self {
+        inum: 0,
+        name: [0; DIRSIZ],
+    };
+
+    pub fn new(name: &str, inum: u32) -> Result<Self, FsError> {
+        let mut dirent = Self {
+            inum: u16::try_from(inum).map_err(|_| FsError::Invalid)?,
+            name: [0; DIRSIZ],
+        };
+        dirent
+            .name
+            .get_mut(..name.len())
+            .ok_or(FsError::NameTooLong)?
+            .copy_from_slice(name.as_bytes());
+        Ok(dirent)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+}
+
+param!(
+    pub FORMAT: bool = false,
+    "fs.format",
+    "Formats the root disk at boot, erasing it"
+);
+
+static SUPERBLOCK: Once<SuperBlock> = Once::new();
+
+/// Superblock of the mounted file system.
+pub fn superblock() -> &'static SuperBlock {
+    SUPERBLOCK.get().expect("no file system mounted")
+}
+
+/// Mounts the root disk, formatting it first if `fs.format` is set, and
+/// recovering what the log holds. Disks without a file system are left
+/// alone, unmounted.
+pub fn init() {
+    let Some(sectors) = blk::capacity() else {
+        warn!("no root disk, no file system");
+        return;
+    };
+    let size = (sectors / blk::SECTORS_PER_BLOCK).min(u32::MAX as u64) as u32;
+    let read_superblock = || read_at::<SuperBlock>(&*bio::bread(ROOT_DEV, SUPERBLOCK_BLOCK), 0);
+    let mut sb = read_superblock();
+    if FORMAT.get() {
+        info!("formatting {} ({} blocks)", ROOT_DEV, size);
+        mkfs::mkfs(ROOT_DEV, size);
+        sb = read_superblock();
+    }
+    if sb.magic != FSMAGIC {
+        warn!(
+            "no file system on {}, not mounting it (fs.format formats it)",
+            ROOT_DEV
+        );
+        return;
+    }
+    if let Err(error) = sb.check(size) {
+        warn!(
+            "{}: superblock doesn't fit the disk ({:?}), not mounting it",
+            ROOT_DEV, error
+        );
+        return;
+    }
+    if let Err(error) = log::init(ROOT_DEV, &sb) {
+        warn!("{}: bad log ({:?}), not mounting it", ROOT_DEV, error);
+        return;
+    }
+    if SUPERBLOCK.set(sb).is_err() {
+        panic!("file system mounted twice");
+    }
+    info!(
+        "mounted {}: {} data blocks ({} free), {} inodes",
+        ROOT_DEV,
+        sb.nblocks,
+        bitmap::free_blocks(ROOT_DEV),
+        sb.ninodes
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn fs_superblock_checks() {
+        let sb = *SUPERBLOCK
+            .get()
+            .expect("tests should have mounted the disk");
+        let disk = sb.size;
+        assert_eq!(sb.check(disk), Ok(()));
+
+        let corrupted = [
+            SuperBlock {
+                size: disk + 1,
+                ..sb
+            },
+            SuperBlock {
+                logstart: SUPERBLOCK_BLOCK,
+                ..sb
+            },
+            SuperBlock { nlog: 0, ..sb },
+            SuperBlock {
+                nlog: u32::MAX,
+                ..sb
+            },
+            SuperBlock {
+                inodestart: sb.bmapstart,
+                ..sb
+            },
+            SuperBlock {
+                nblocks: sb.nblocks + 1,
+                ..sb
+            },
+        ];
+        for sb in corrupted {
+            assert_eq!(sb.check(disk), Err(FsError::Corrupt), "{:?}", sb);
+        }
+    }
+}
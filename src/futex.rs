@@ -0,0 +1,162 @@
+//! Fast user-space mutexes: processes synchronize through plain atomic
+//! words, and only enter the kernel to sleep on (or wake sleepers of) one
+//! under contention. See [`crate::ulib::sync`] for the locks built on top.
+
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    proc::{self, MAX_PROCESSES},
+    syscall::Errno,
+    timer::Instant,
+    utils::{
+        collections::ArrayVec,
+        sync::{SpinLock, WaitQueue},
+    },
+};
+
+/// Processes waiting on the word at `addr`.
+#[derive(Debug)]
+struct Futex {
+    /// Physical address: there's no paging, so every process agrees on it.
+    addr: usize,
+    waiters: WaitQueue,
+}
+
+/// Futexes with waiters. Each process waits on at most one, so there
+/// can't be more than [`MAX_PROCESSES`].
+static FUTEXES: SpinLock<ArrayVec<Futex, MAX_PROCESSES>> =
+    SpinLock::new("FUTEXES", ArrayVec::new());
+
+fn word(addr: usize) -> Result<&'static AtomicU32, Errno> {
+    if addr == 0 || !addr.is_multiple_of(align_of::<AtomicU32>()) {
+        return Err(Errno::Inval);
+    }
+    // SAFETY: the address was given to us as a futex word
+    Ok(unsafe { &*(addr as *const AtomicU32) })
+}
+
+/// Sleeps until woken by [`futex_wake`] on `addr`, as long as the word
+/// there still holds `expected`, or fails with [`Errno::Again`]. The check
+/// happens under the futex table's lock, so a wake-up racing with it
+/// can't be lost.
+pub fn futex_wait(addr: usize, expected: u32, timeout: Option<Duration>) -> Result<(), Errno> {
+    let word = word(addr)?;
+    let deadline = timeout.map(|timeout| Instant::now().saturating_add(timeout));
+    let mut futexes = FUTEXES.lock();
+    if word.load(Ordering::SeqCst) != expected {
+        return Err(Errno::Again);
+    }
+    let me = proc::current_pid();
+    let index = match futexes.iter().position(|futex| futex.addr == addr) {
+        Some(index) => index,
+        None => {
+            futexes.push(Futex {
+                addr,
+                waiters: WaitQueue::new(),
+            });
+            futexes.len() - 1
+        }
+    };
+    futexes[index].waiters.push(me);
+    proc::block_until(futexes, deadline);
+
+    // Wakers dequeue us, so still being queued means we timed out
+    let mut futexes = FUTEXES.lock();
+    let Some(index) = futexes.iter().position(|futex| futex.addr == addr) else {
+        return Ok(());
+    };
+    if !futexes[index].waiters.remove(me) {
+        return Ok(());
+    }
+    if futexes[index].waiters.is_empty() {
+        futexes.remove(index);
+    }
+    Err(Errno::TimedOut)
+}
+
+/// Wakes up to `count` processes waiting on `addr`, returning how many were.
+pub fn futex_wake(addr: usize, count: usize) -> usize {
+    let mut futexes = FUTEXES.lock();
+    let Some(index) = futexes.iter().position(|futex| futex.addr == addr) else {
+        return 0;
+    };
+    let mut woken = 0;
+    while woken < count && futexes[index].waiters.wake_one().is_some() {
+        woken += 1;
+    }
+    if futexes[index].waiters.is_empty() {
+        futexes.remove(index);
+    }
+    woken
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA};
+
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn addr(word: &AtomicU32) -> usize {
+        word.as_ptr() as usize
+    }
+
+    fn waiter() {
+        while WORD.load(Ordering::SeqCst) == 0 {
+            match futex_wait(addr(&WORD), 0, None) {
+                Ok(()) | Err(Errno::Again) => {}
+                Err(error) => panic!("unexpected futex_wait error: {:?}", error),
+            }
+        }
+        WOKEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn waker() {
+        // Let both waiters go to sleep first
+        proc::yield_self();
+        WORD.store(1, Ordering::SeqCst);
+        futex_wake(addr(&WORD), usize::MAX);
+    }
+
+    #[test_case]
+    pub fn futex_wait_wake() {
+        assert_eq!(
+            futex_wait(addr(&WORD), 1, None),
+            Err(Errno::Again),
+            "shouldn't sleep when the value differs"
+        );
+        assert_eq!(futex_wake(addr(&WORD), 1), 0, "nobody's waiting yet");
+
+        PROCESSES.create(waiter);
+        PROCESSES.create(waiter);
+        PROCESSES.create(waker);
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 2);
+        assert!(FUTEXES.lock().is_empty(), "no futex should be left over");
+    }
+
+    static TIMEOUT_WORD: AtomicU32 = AtomicU32::new(0);
+
+    fn timed_waiter() {
+        let timeout = Duration::from_micros(500);
+        let start = Instant::now();
+        assert_eq!(
+            futex_wait(addr(&TIMEOUT_WORD), 0, Some(timeout)),
+            Err(Errno::TimedOut)
+        );
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test_case]
+    pub fn futex_wait_timeout() {
+        PROCESSES.create(timed_waiter);
+        proc::run_until_exit(TEST_QUANTA);
+        assert!(FUTEXES.lock().is_empty(), "timed out waiters should leave");
+    }
+}
@@ -0,0 +1,376 @@
+//! Console device on top of the UART: received characters go through a
+//! line discipline into an input buffer, and output is queued and drained
+//! by transmit interrupts, so writers don't wait on the device.
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::uart::{self, Interrupt, Uart};
+use crate::{
+    irq,
+    proc::{self, PID},
+    syscall::Errno,
+    utils::{
+        collections::ArrayDeque,
+        sync::{SpinLock, WaitQueue},
+    },
+};
+
+pub const INPUT_SIZE: usize = 128;
+pub const OUTPUT_SIZE: usize = 1024;
+
+const fn ctrl(c: u8) -> u8 {
+    c - b'@'
+}
+
+const INTERRUPT: u8 = ctrl(b'C');
+const EOF: u8 = ctrl(b'D');
+const BACKSPACE: u8 = ctrl(b'H');
+const KILL_LINE: u8 = ctrl(b'U');
+const DELETE: u8 = 0x7F;
+
+/// Line being edited and lines ready to be read, as in xv6: indices only
+/// ever grow, wrapping around `buffer`, with `read <= committed <= edit`.
+#[derive(Debug)]
+struct Input {
+    buffer: [u8; INPUT_SIZE],
+    read: usize,
+    committed: usize,
+    edit: usize,
+    echo: bool,
+    /// Process ^C is delivered to.
+    foreground: Option<PID>,
+    readers: WaitQueue,
+}
+
+impl Input {
+    const fn new() -> Self {
+        Self {
+            buffer: [0; INPUT_SIZE],
+            read: 0,
+            committed: 0,
+            edit: 0,
+            echo: true,
+            foreground: None,
+            readers: WaitQueue::new(),
+        }
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        if self.echo {
+            write(bytes);
+        }
+    }
+
+    fn commit(&mut self) {
+        self.committed = self.edit;
+        self.readers.wake_all();
+    }
+
+    fn erase(&mut self) -> bool {
+        if self.edit == self.committed {
+            return false;
+        }
+        self.edit -= 1;
+        self.echo(b"\x08 \x08");
+        true
+    }
+
+    fn handle(&mut self, c: u8) {
+        match c {
+            INTERRUPT => {
+                self.echo(b"^C\n");
+                self.edit = self.committed;
+                if let Some(pid) = self.foreground {
+                    proc::interrupt(pid);
+                }
+            }
+            KILL_LINE => while self.erase() {},
+            BACKSPACE | DELETE => {
+                self.erase();
+            }
+            c if self.edit - self.read < INPUT_SIZE => {
+                let c = if c == b'\r' { b'\n' } else { c };
+                self.buffer[self.edit % INPUT_SIZE] = c;
+                self.edit += 1;
+                if c != EOF {
+                    self.echo(&[c]);
+                }
+                if c == b'\n' || c == EOF || self.edit - self.read == INPUT_SIZE {
+                    self.commit();
+                }
+            }
+            // Full: drop it
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Output {
+    buffer: ArrayDeque<u8, OUTPUT_SIZE>,
+    /// Processes waiting for room in `buffer`.
+    writers: WaitQueue,
+}
+
+impl Output {
+    /// Feeds the transmitter if it's ready, leaving the rest to its
+    /// interrupt.
+    fn start(&mut self) {
+        let mut uart = Uart;
+        if uart.is_tx_ready() {
+            // The FIFO is empty, so this never waits
+            for _ in 0..uart::FIFO_SIZE {
+                let Some(c) = self.buffer.pop_front() else {
+                    break;
+                };
+                uart.write_thr(c);
+            }
+            self.writers.wake_all();
+        }
+        uart.set_interrupts(true, !self.buffer.is_empty());
+    }
+}
+
+static INPUT: SpinLock<Input> = SpinLock::new("CONSOLE_INPUT", Input::new());
+static OUTPUT: SpinLock<Output> = SpinLock::new(
+    "CONSOLE_OUTPUT",
+    Output {
+        buffer: ArrayDeque::new(),
+        writers: WaitQueue::new(),
+    },
+);
+/// Until then, output goes straight to the UART.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the UART's line up and takes over its interrupts.
+pub fn init() {
+    let mut uart = Uart;
+    uart.init(uart::BAUD_RATE);
+    irq::register(uart::irq(), handle_irq);
+    uart.set_interrupts(true, false);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// The UART's interrupt handler, registered by [`init`].
+pub(crate) fn handle_irq() {
+    let mut uart = Uart;
+    while let Some(interrupt) = uart.pending_interrupt() {
+        match interrupt {
+            Interrupt::ReceivedData | Interrupt::CharacterTimeout | Interrupt::LineStatus => {
+                while let Some(c) = uart.read_char() {
+                    INPUT.lock().handle(c);
+                }
+            }
+            Interrupt::TransmitterEmpty => OUTPUT.lock().start(),
+            Interrupt::ModemStatus => {}
+        }
+    }
+}
+
+/// Runs `c` through the line discipline, as if it was typed.
+pub fn input(c: u8) {
+    INPUT.lock().handle(c);
+}
+
+/// Whether typed characters are echoed back.
+pub fn set_echo(enabled: bool) {
+    INPUT.lock().echo = enabled;
+}
+
+/// Sets the process ^C interrupts.
+pub fn set_foreground(pid: Option<PID>) {
+    INPUT.lock().foreground = pid;
+}
+
+/// Reads at most one line into `buf`, sleeping until one is typed.
+/// Returns 0 at end of file (^D at the start of a line), or fails with
+/// [`Errno::Intr`] if interrupted before reading anything.
+pub fn read(buf: &mut [u8]) -> Result<usize, Errno> {
+    let mut input = INPUT.lock();
+    let mut len = 0;
+    while len < buf.len() {
+        if input.read == input.committed {
+            if len > 0 {
+                break;
+            }
+            if proc::is_interrupted() {
+                return Err(Errno::Intr);
+            }
+            let me = proc::current_pid();
+            input.readers.push(me);
+            proc::block(input);
+            input = INPUT.lock();
+            input.readers.remove(me);
+            continue;
+        }
+        let c = input.buffer[input.read % INPUT_SIZE];
+        if c == EOF {
+            // Ends this read, then makes the next one return 0
+            if len == 0 {
+                input.read += 1;
+            }
+            break;
+        }
+        input.read += 1;
+        buf[len] = c;
+        len += 1;
+        if c == b'\n' {
+            break;
+        }
+    }
+    Ok(len)
+}
+
+/// Queues `bytes` for output. Only waits when the buffer is full: by
+/// sleeping if possible, else by feeding the UART itself.
+pub fn write(bytes: &[u8]) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        let mut uart = Uart;
+        bytes.iter().for_each(|&c| uart.write_char(c));
+        return;
+    }
+    let blockable = proc::blockable_pid();
+    let mut output = OUTPUT.lock();
+    for &c in bytes {
+        while output.buffer.is_full() {
+            match blockable {
+                Some(me) => {
+                    output.writers.push(me);
+                    proc::block(output);
+                    output = OUTPUT.lock();
+                    output.writers.remove(me);
+                }
+                None => {
+                    while !Uart.is_tx_ready() {
+                        spin_loop();
+                    }
+                    output.start();
+                }
+            }
+        }
+        output.buffer.push_back(c);
+    }
+    output.start();
+}
+
+/// Writes out everything queued, e.g. before shutting down.
+pub fn flush() {
+    let mut output = OUTPUT.lock();
+    let mut uart = Uart;
+    while let Some(c) = output.buffer.pop_front() {
+        uart.write_char(c);
+    }
+    output.writers.wake_all();
+}
+
+/// [`flush`], unless another hart (or this one, if panicking inside the
+/// console) is in the middle of writing. Returns whether it flushed.
+pub fn try_flush() -> bool {
+    let Ok(mut output) = OUTPUT.try_lock() else {
+        return false;
+    };
+    let mut uart = Uart;
+    while let Some(c) = output.buffer.pop_front() {
+        uart.write_char(c);
+    }
+    true
+}
+
+/// Writer for the console, for `core::fmt`.
+pub struct Console;
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{sync::atomic::AtomicBool, time::Duration};
+
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA, timer::Instant, ulib};
+
+    fn type_str(s: &[u8]) {
+        s.iter().for_each(|&c| input(c));
+    }
+
+    fn read_line<'buf>(buf: &'buf mut [u8]) -> &'buf [u8] {
+        let len = read(buf).unwrap();
+        &buf[..len]
+    }
+
+    #[test_case]
+    pub fn console_line_editing() {
+        set_echo(false);
+        let mut buf = [0; 16];
+        type_str(b"ab\x7fc\n");
+        assert_eq!(read_line(&mut buf), b"ac\n", "backspace erases");
+        type_str(b"xy\x15z\r");
+        assert_eq!(read_line(&mut buf), b"z\n", "^U kills the line");
+        type_str(b"q\x04");
+        assert_eq!(read_line(&mut buf), b"q", "^D ends the read");
+        assert_eq!(read_line(&mut buf), b"", "^D alone means EOF");
+        type_str(b"long line\n");
+        assert_eq!(read_line(&mut buf[..4]), b"long");
+        assert_eq!(read_line(&mut buf), b" line\n");
+        set_echo(true);
+    }
+
+    #[test_case]
+    pub fn console_rx_interrupt() {
+        set_echo(false);
+        // Anything still queued would loop back as input
+        flush();
+        let mut uart = Uart;
+        uart.set_loopback(true);
+        uart.write_str("typed\r");
+        let give_up = Instant::now() + Duration::from_millis(100);
+        let committed = || {
+            let input = INPUT.lock();
+            input.committed != input.read
+        };
+        irq::enable();
+        while !committed() && Instant::now() < give_up {}
+        irq::disable();
+        uart.set_loopback(false);
+        set_echo(true);
+        let mut buf = [0; 16];
+        assert!(committed(), "the line should come in through interrupts");
+        assert_eq!(read_line(&mut buf), b"typed\n");
+    }
+
+    static AFTER_READ: AtomicBool = AtomicBool::new(false);
+
+    fn reader() {
+        let mut buf = [0; 16];
+        let _ = ulib::read(0, &mut buf);
+        AFTER_READ.store(true, Ordering::SeqCst);
+    }
+
+    fn typist() {
+        // Let the reader go to sleep first
+        proc::yield_self();
+        input(INTERRUPT);
+    }
+
+    #[test_case]
+    pub fn console_interrupt() {
+        set_echo(false);
+        let pid = PROCESSES.create(reader);
+        set_foreground(Some(pid));
+        PROCESSES.create(typist);
+        proc::run_until_exit(TEST_QUANTA);
+        set_foreground(None);
+        set_echo(true);
+        assert!(
+            !AFTER_READ.load(Ordering::SeqCst),
+            "^C should terminate the reader"
+        );
+    }
+}
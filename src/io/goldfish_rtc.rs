@@ -0,0 +1,69 @@
+//! Goldfish real-time clock, as found on QEMU virt: nanoseconds since
+//! the Unix epoch, readable as two 32-bit halves.
+
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    dev::{self, DeviceClass, Driver, ProbeError},
+    fdt::Node,
+};
+
+pub const COMPATIBLE: &[&str] = &["google,goldfish-rtc"];
+/// Where QEMU virt maps its goldfish RTC, so the wall clock can still be
+/// read at boot without a device tree.
+const DEFAULT_RTC_BASE: usize = 0x0010_1000;
+/// Reading it latches `TIME_HIGH`; writing it sets the time.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+static RTC_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_RTC_BASE);
+
+pub struct RtcDriver;
+
+pub static DRIVER: RtcDriver = RtcDriver;
+
+impl Driver for RtcDriver {
+    fn name(&self) -> &'static str {
+        "goldfish-rtc"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Char {
+            major: dev::MAJOR_RTC,
+        }
+    }
+
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        RTC_BASE.store(regs.base, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (RTC_BASE.load(Ordering::Relaxed) + offset) as *mut u32
+}
+
+pub fn read() -> Duration {
+    let nanos = unsafe {
+        let low = reg(TIME_LOW).read_volatile();
+        let high = reg(TIME_HIGH).read_volatile();
+        (high as u64) << 32 | low as u64
+    };
+    Duration::from_nanos(nanos)
+}
+
+pub fn write(time: Duration) {
+    let nanos: u64 = time.as_nanos().try_into().unwrap_or(u64::MAX);
+    unsafe {
+        reg(TIME_HIGH).write_volatile((nanos >> 32) as u32);
+        reg(TIME_LOW).write_volatile(nanos as u32);
+    }
+}
@@ -0,0 +1,226 @@
+//! Kernel log buffer: the last [`KMSG_LINES`] lines printed, numbered in
+//! order, so output that scrolled off or came before the UART was ready
+//! can still be read back with `dmesg`, and the tail shown on panic.
+
+use core::fmt::Write;
+
+use super::{is_panicking, uart::Uart};
+use crate::{
+    syscall::Errno,
+    utils::{
+        collections::ArrayDeque,
+        sync::{SpinLock, SpinLockGuard},
+    },
+};
+
+pub const KMSG_LINES: usize = 128;
+/// Longer lines are truncated.
+pub const KMSG_LINE_LEN: usize = 120;
+/// Lines shown before a panic message.
+pub const PANIC_LINES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    seq: u64,
+    len: usize,
+    text: [u8; KMSG_LINE_LEN],
+}
+
+impl Line {
+    const EMPTY: Self = Self {
+        seq: 0,
+        len: 0,
+        text: [0; KMSG_LINE_LEN],
+    };
+
+    fn text(&self) -> &[u8] {
+        &self.text[..self.len]
+    }
+
+    /// Appends as much of `s` as fits, in whole characters.
+    fn push(&mut self, s: &str) {
+        let mut len = s.len().min(KMSG_LINE_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+    }
+}
+
+#[derive(Debug)]
+struct Kmsg {
+    lines: ArrayDeque<Line, KMSG_LINES>,
+    /// Line being printed, recorded once its newline is.
+    current: Line,
+    next_seq: u64,
+}
+
+impl Kmsg {
+    fn commit(&mut self) {
+        if self.lines.is_full() {
+            self.lines.pop_front();
+        }
+        self.current.seq = self.next_seq;
+        self.next_seq += 1;
+        self.lines.push_back(self.current);
+        self.current = Line::EMPTY;
+    }
+}
+
+static KMSG: SpinLock<Kmsg> = SpinLock::new(
+    "KMSG",
+    Kmsg {
+        lines: ArrayDeque::new(),
+        current: Line::EMPTY,
+        next_seq: 0,
+    },
+);
+
+/// Doesn't wait when panicking: the panic may have come from under the lock.
+fn lock() -> Option<SpinLockGuard<'static, Kmsg>> {
+    match is_panicking() {
+        true => KMSG.try_lock().ok(),
+        false => Some(KMSG.lock()),
+    }
+}
+
+/// Records printed text.
+pub(super) fn record(s: &str) {
+    let Some(mut kmsg) = lock() else {
+        return;
+    };
+    for part in s.split_inclusive('\n') {
+        match part.strip_suffix('\n') {
+            Some(line) => {
+                kmsg.current.push(line);
+                kmsg.commit();
+            }
+            None => kmsg.current.push(part),
+        }
+    }
+}
+
+/// Sequence number the next line will get.
+pub fn next_seq() -> u64 {
+    KMSG.lock().next_seq
+}
+
+/// Formats into a byte buffer, failing once it's full.
+pub(crate) struct SliceWriter<'buf> {
+    buf: &'buf mut [u8],
+    len: usize,
+}
+
+impl<'buf> SliceWriter<'buf> {
+    pub(crate) fn new(buf: &'buf mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Bytes written so far.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Copies the lines numbered `from` on into `buf`, as many whole ones as
+/// fit, each as `<seq> <text>\n`. Lines already overwritten are skipped,
+/// which shows as a gap in the numbers. Returns the bytes written, or
+/// fails with [`Errno::Inval`] if not even one line fits.
+pub fn read(from: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let kmsg = KMSG.lock();
+    let mut writer = SliceWriter::new(buf);
+    for line in kmsg.lines.iter().filter(|line| line.seq >= from) {
+        let len = writer.len();
+        // Lines only ever hold whole UTF-8 characters
+        let text = core::str::from_utf8(line.text()).unwrap_or("<invalid UTF-8>");
+        if writer
+            .write_fmt(format_args!("{} {}\n", line.seq, text))
+            .is_err()
+        {
+            writer.len = len;
+            if len == 0 {
+                return Err(Errno::Inval);
+            }
+            break;
+        }
+    }
+    Ok(writer.len())
+}
+
+/// Writes the last `count` lines straight to the UART, e.g. on panic.
+pub fn dump_tail(count: usize) {
+    let Some(kmsg) = lock() else {
+        return;
+    };
+    let mut uart = Uart;
+    uart.write_str("--- last kernel log lines ---\n");
+    let skip = kmsg.lines.len().saturating_sub(count);
+    for line in kmsg.lines.iter().skip(skip) {
+        line.text().iter().for_each(|&c| uart.write_char(c));
+        uart.write_char(b'\n');
+    }
+    uart.write_str("------------------------------\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq_of(line: &[u8]) -> u64 {
+        let digits = line.split(|&c| c == b' ').next().unwrap();
+        core::str::from_utf8(digits).unwrap().parse().unwrap()
+    }
+
+    #[test_case]
+    pub fn kmsg_records_lines() {
+        let mut buf = [0; 512];
+        let first = next_seq();
+        // Completes the test runner's line, which ends up in line 1
+        record("kmsg test line 1\nkmsg test ");
+        record("line 2\n");
+        assert_eq!(next_seq(), first + 2);
+
+        let len = read(first, &mut buf).unwrap();
+        let mut lines = buf[..len]
+            .split(|&c| c == b'\n')
+            .filter(|line| !line.is_empty());
+        let line1 = lines.next().unwrap();
+        let line2 = lines.next().unwrap();
+        assert!(lines.next().is_none());
+        assert_eq!((seq_of(line1), seq_of(line2)), (first, first + 1));
+        assert!(line1.ends_with(b"kmsg test line 1"));
+        assert!(line2.ends_with(b"kmsg test line 2"));
+
+        assert_eq!(
+            read(first, &mut buf[..4]),
+            Err(Errno::Inval),
+            "a line shouldn't be split"
+        );
+        assert_eq!(read(first + 2, &mut buf), Ok(0));
+    }
+
+    #[test_case]
+    pub fn kmsg_truncates_whole_characters() {
+        let mut line = Line::EMPTY;
+        for _ in 0..KMSG_LINE_LEN - 1 {
+            line.push("a");
+        }
+        // Two bytes, with room for one
+        line.push("é");
+        assert_eq!(line.len, KMSG_LINE_LEN - 1);
+        assert!(core::str::from_utf8(line.text()).is_ok());
+    }
+}
@@ -1,5 +1,14 @@
-use core::fmt::Arguments;
+use core::{
+    fmt::{Arguments, Write},
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
+use crate::{cpu, irq, timer::clock};
+
+pub mod console;
+pub mod goldfish_rtc;
+pub mod kmsg;
 pub mod sifive_test;
 pub mod uart;
 
@@ -14,9 +23,176 @@ macro_rules! println {
     ($($args:tt)*) => ($crate::print!("{}\n", format_args!($($args)*)));
 }
 
+/// Whether the next character printed starts a line, and so gets a timestamp.
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Records everything written into the kernel log buffer, see [`kmsg`].
+struct Recorded<W: Write>(W);
+
+impl<W: Write> Write for Recorded<W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        kmsg::record(s);
+        self.0.write_str(s)
+    }
+}
+
+/// Prefixes every line with the time since boot, as in `[    1.234567] `.
+struct Timestamped<W: Write>(W);
+
+impl<W: Write> Write for Timestamped<W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if AT_LINE_START.load(Ordering::Relaxed) {
+                let uptime = clock::uptime();
+                self.0.write_fmt(format_args!(
+                    "[{:5}.{:06}] ",
+                    uptime.as_secs(),
+                    uptime.subsec_micros()
+                ))?;
+            }
+            self.0.write_str(line)?;
+            AT_LINE_START.store(line.ends_with('\n'), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+const NO_HART: usize = usize::MAX;
+
+/// Hart in the middle of a `print!`, so that each comes out in one
+/// piece and [`AT_LINE_START`] matches what's on screen. Processes and
+/// traps alike hold it with interrupts off: a process can't sleep with
+/// it, so waiting for it always ends. Not a
+/// [`SpinLock`](crate::utils::sync::SpinLock): those report misuse by
+/// printing, and panic when re-acquired.
+static PRINT_OWNER: AtomicUsize = AtomicUsize::new(NO_HART);
+/// Set once panicking, after which printing bypasses every lock.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Holds [`PRINT_OWNER`], or finds this hart already does.
+pub(crate) enum PrintGuard {
+    Owner,
+    /// Taken by a `print!` nested in another, e.g. from a `Debug` impl,
+    /// which prints inline rather than deadlocking.
+    Nested,
+    /// Taken by a trap that interrupted this hart's own `print!`, e.g.
+    /// an exception inside it. Printing would land mid-line, so the
+    /// text only goes to [`kmsg`].
+    Trap,
+}
+
+/// Keeps other `print!`s out, e.g. while writing to the UART directly.
+pub(crate) fn lock_print() -> PrintGuard {
+    irq::push_off();
+    let me = cpu::id();
+    if PRINT_OWNER.load(Ordering::Relaxed) == me {
+        return match cpu::current().in_trap() {
+            true => PrintGuard::Trap,
+            false => PrintGuard::Nested,
+        };
+    }
+    while PRINT_OWNER
+        .compare_exchange_weak(NO_HART, me, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        spin_loop();
+    }
+    PrintGuard::Owner
+}
+
+impl Drop for PrintGuard {
+    fn drop(&mut self) {
+        if let PrintGuard::Owner = self {
+            PRINT_OWNER.store(NO_HART, Ordering::Release);
+        }
+        irq::pop_off();
+    }
+}
+
+/// Records into [`kmsg`] without printing.
+struct KmsgOnly;
+
+impl Write for KmsgOnly {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        kmsg::record(s);
+        Ok(())
+    }
+}
+
+/// Switches printing to the emergency writer, which polls the UART
+/// without taking any lock, so a panic anywhere (even inside `print!`)
+/// gets its message out. Whatever output was queued is written first,
+/// unless its lock is held.
+pub fn enter_panic_mode() {
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        console::try_flush();
+    }
+}
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    use core::fmt::Write;
-    let mut uart = crate::io::uart::Uart;
-    uart.write_fmt(args).unwrap();
+    if is_panicking() {
+        let _ = Timestamped(Recorded(uart::Uart)).write_fmt(args);
+        return;
+    }
+    let guard = lock_print();
+    match guard {
+        PrintGuard::Trap => KmsgOnly.write_fmt(args).unwrap(),
+        _ => Timestamped(Recorded(console::Console))
+            .write_fmt(args)
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proc::{self, PROCESSES},
+        test::TEST_QUANTA,
+    };
+
+    #[test_case]
+    pub fn print_lock_is_reentrant() {
+        let outer = lock_print();
+        assert!(matches!(outer, PrintGuard::Owner));
+        assert_eq!(PRINT_OWNER.load(Ordering::Relaxed), cpu::id());
+        let inner = lock_print();
+        assert!(matches!(inner, PrintGuard::Nested));
+        drop(inner);
+        assert_eq!(
+            PRINT_OWNER.load(Ordering::Relaxed),
+            cpu::id(),
+            "nested guards shouldn't release the lock"
+        );
+        drop(outer);
+        assert_eq!(PRINT_OWNER.load(Ordering::Relaxed), NO_HART);
+    }
+
+    static UNSLEEPABLE: AtomicBool = AtomicBool::new(false);
+
+    fn print_from_process() {
+        let guard = lock_print();
+        UNSLEEPABLE.store(
+            matches!(guard, PrintGuard::Owner)
+                && proc::blockable_pid().is_none()
+                && !irq::is_enabled(),
+            Ordering::Relaxed,
+        );
+    }
+
+    #[test_case]
+    pub fn print_lock_is_shared_with_processes() {
+        PROCESSES.create(print_from_process);
+        proc::run_until_exit(TEST_QUANTA);
+        assert!(
+            UNSLEEPABLE.load(Ordering::Relaxed),
+            "processes should take the traps' lock, and not sleep with it"
+        );
+        assert_eq!(PRINT_OWNER.load(Ordering::Relaxed), NO_HART);
+    }
 }
@@ -1,8 +1,44 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::println;
+use crate::{
+    dev::{DeviceClass, Driver, ProbeError},
+    fdt::Node,
+    println,
+};
 
-const ADDR: *mut u8 = 0x0010_0000 as *mut u8;
+pub const COMPATIBLE: &[&str] = &["sifive,test0", "sifive,test1"];
+/// Where QEMU virt maps its test finisher, so that a panic before
+/// probing can still shut down (and fail a test run).
+const DEFAULT_ADDR: usize = 0x0010_0000;
+
+static ADDR: AtomicUsize = AtomicUsize::new(DEFAULT_ADDR);
+
+pub struct TestDeviceDriver;
+
+pub static DRIVER: TestDeviceDriver = TestDeviceDriver;
+
+impl Driver for TestDeviceDriver {
+    fn name(&self) -> &'static str {
+        "sifive-test"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Platform
+    }
+
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        ADDR.store(regs.base, Ordering::Relaxed);
+        Ok(())
+    }
+}
 
 const EXIT_FAILURE: u32 = 0x00003333;
 const EXIT_SUCCESS: u32 = 0x00005555;
@@ -26,7 +62,7 @@ fn exit(code: u32) -> ! {
         asm!(
             "sw {}, 0({})",
             in(reg) code,
-            in(reg) ADDR
+            in(reg) ADDR.load(Ordering::Relaxed)
         );
     }
     println!("FAILED TO SHUTDOWN");
@@ -1,12 +1,183 @@
-pub struct Uart;
+//! NS16550A UART, as emulated by QEMU virt.
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    dev::{self, DeviceClass, Driver, ProbeError},
+    fdt::Node,
+};
+
+pub const COMPATIBLE: &[&str] = &["ns16550a", "ns16550"];
+/// QEMU virt's UART, used until probed from the device tree.
+const DEFAULT_UART_BASE: usize = 0x1000_0000;
+const DEFAULT_UART_IRQ: u32 = 10;
+pub const BAUD_RATE: u32 = 38_400;
+/// Input clock, divided down to the baud rate: that of the original PC's.
+const UART_CLOCK_HZ: u32 = 1_843_200;
+/// Bytes the transmitter takes at once when empty.
+pub const FIFO_SIZE: usize = 16;
+
+// Register offsets. Offsets 0 and 1 are DLL/DLM when LCR_DLAB is set.
+const RBR: usize = 0;
+const THR: usize = 0;
+const DLL: usize = 0;
+const IER: usize = 1;
+const DLM: usize = 1;
+const IIR: usize = 2;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX: u8 = 1 << 0;
+const IER_TX: u8 = 1 << 1;
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+/// 8 data bits, no parity, 1 stop bit.
+const LCR_8N1: u8 = 0b11;
+/// Divisor latch access.
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+
+static UART_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_UART_BASE);
+static UART_IRQ: AtomicU32 = AtomicU32::new(DEFAULT_UART_IRQ);
+
+pub struct UartDriver;
 
-const UART_BASE: usize = 0x1000_0000;
-const UART_THR: *mut u8 = UART_BASE as *mut u8;
+pub static DRIVER: UartDriver = UartDriver;
+
+impl Driver for UartDriver {
+    fn name(&self) -> &'static str {
+        "ns16550a"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Char {
+            major: dev::MAJOR_TTY,
+        }
+    }
+
+    /// Only takes the registers and interrupt: the console sets the line
+    /// up, as it's the UART's only user.
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        let irq = node.interrupts().next().ok_or(ProbeError::MissingIrq)?;
+        UART_BASE.store(regs.base, Ordering::Relaxed);
+        UART_IRQ.store(irq, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// PLIC source the UART interrupts on.
+pub fn irq() -> u32 {
+    UART_IRQ.load(Ordering::Relaxed)
+}
+
+fn read(reg: usize) -> u8 {
+    unsafe { ((UART_BASE.load(Ordering::Relaxed) + reg) as *const u8).read_volatile() }
+}
+
+fn write(reg: usize, value: u8) {
+    unsafe { ((UART_BASE.load(Ordering::Relaxed) + reg) as *mut u8).write_volatile(value) }
+}
+
+/// Cause of a UART interrupt, highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    LineStatus,
+    ReceivedData,
+    /// Received data has sat in the FIFO below its trigger level.
+    CharacterTimeout,
+    TransmitterEmpty,
+    ModemStatus,
+}
+
+pub struct Uart;
 
 impl Uart {
+    /// Sets the line up as 8N1 at `baud` with FIFOs on, and interrupts off.
+    pub fn init(&mut self, baud: u32) {
+        let divisor = (UART_CLOCK_HZ / (16 * baud)) as u16;
+        write(IER, 0);
+        write(LCR, LCR_DLAB);
+        write(DLL, divisor as u8);
+        write(DLM, (divisor >> 8) as u8);
+        write(LCR, LCR_8N1);
+        write(FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+    }
+
+    pub fn set_interrupts(&mut self, rx: bool, tx: bool) {
+        let mut ier = 0;
+        if rx {
+            ier |= IER_RX;
+        }
+        if tx {
+            ier |= IER_TX;
+        }
+        write(IER, ier);
+    }
+
+    /// Echoes whatever is sent back to the receiver, for testing.
+    pub fn set_loopback(&mut self, enabled: bool) {
+        let mcr = read(MCR);
+        write(
+            MCR,
+            if enabled {
+                mcr | MCR_LOOPBACK
+            } else {
+                mcr & !MCR_LOOPBACK
+            },
+        );
+    }
+
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let iir = read(IIR);
+        if iir & IIR_NONE_PENDING != 0 {
+            return None;
+        }
+        match iir & IIR_ID_MASK {
+            0b0110 => Some(Interrupt::LineStatus),
+            0b0100 => Some(Interrupt::ReceivedData),
+            0b1100 => Some(Interrupt::CharacterTimeout),
+            0b0010 => Some(Interrupt::TransmitterEmpty),
+            _ => Some(Interrupt::ModemStatus),
+        }
+    }
+
+    pub fn is_tx_ready(&self) -> bool {
+        read(LSR) & LSR_THR_EMPTY != 0
+    }
+
+    /// Writes `c` without checking the transmitter: once
+    /// [`Self::is_tx_ready`], up to [`FIFO_SIZE`] bytes fit.
+    pub fn write_thr(&mut self, c: u8) {
+        write(THR, c);
+    }
+
+    /// Writes `c` unless the transmitter is still busy.
+    pub fn try_write_char(&mut self, c: u8) -> bool {
+        if !self.is_tx_ready() {
+            return false;
+        }
+        write(THR, c);
+        true
+    }
+
     pub fn write_char(&mut self, c: u8) {
-        unsafe {
-            core::ptr::write_volatile(UART_THR, c);
+        while !self.try_write_char(c) {
+            spin_loop();
         }
     }
 
@@ -15,6 +186,13 @@ impl Uart {
             self.write_char(b);
         }
     }
+
+    pub fn read_char(&mut self) -> Option<u8> {
+        if read(LSR) & LSR_DATA_READY == 0 {
+            return None;
+        }
+        Some(read(RBR))
+    }
 }
 
 impl core::fmt::Write for Uart {
@@ -28,3 +206,63 @@ impl core::fmt::Write for Uart {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        sync::atomic::{AtomicU8, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{io::console, irq, timer::Instant};
+
+    #[test_case]
+    pub fn uart_loopback() {
+        let mut uart = Uart;
+        uart.set_interrupts(false, false);
+        uart.set_loopback(true);
+        uart.write_char(b'x');
+        let received = uart.read_char();
+        uart.set_loopback(false);
+        uart.set_interrupts(true, false);
+        assert_eq!(received, Some(b'x'));
+    }
+
+    static RECEIVED: AtomicU8 = AtomicU8::new(0);
+
+    fn receive() {
+        let mut uart = Uart;
+        while let Some(interrupt) = uart.pending_interrupt() {
+            if let Interrupt::ReceivedData | Interrupt::CharacterTimeout = interrupt {
+                while let Some(c) = uart.read_char() {
+                    RECEIVED.store(c, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Borrows the IRQ from the console, which owns it once booted.
+    #[test_case]
+    pub fn uart_rx_interrupt() {
+        // Nothing may be left for the console to send while we hold the IRQ
+        console::flush();
+        let mut uart = Uart;
+        irq::register(irq(), receive);
+        uart.set_loopback(true);
+        uart.write_char(b'y');
+        let give_up = Instant::now() + Duration::from_millis(100);
+        irq::enable();
+        while RECEIVED.load(Ordering::SeqCst) == 0 && Instant::now() < give_up {
+            spin_loop();
+        }
+        irq::disable();
+        uart.set_loopback(false);
+        irq::register(irq(), console::handle_irq);
+        assert_eq!(
+            RECEIVED.load(Ordering::SeqCst),
+            b'y',
+            "received data should interrupt through the PLIC"
+        );
+    }
+}
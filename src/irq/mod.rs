@@ -0,0 +1,87 @@
+use core::arch::asm;
+
+use crate::{cpu, utils::sync::SpinLock, warn};
+
+pub mod plic;
+
+const MSTATUS_MIE: u64 = 1 << 3;
+const MIE_MEIE: u64 = 1 << 11;
+
+pub fn setup(trapvec: unsafe extern "C" fn()) {
+    // Define trap handler
+    unsafe {
+        asm!(
+            "csrw mtvec, {}",
+            in(reg) trapvec as u64 & 0xFFFFFFFC,
+        );
+        // Device interrupts are masked by the PLIC until registered
+        asm!("csrs mie, {}", in(reg) MIE_MEIE);
+    }
+}
+
+/// Device interrupt handlers, by PLIC source number.
+static HANDLERS: SpinLock<[Option<fn()>; plic::MAX_IRQS]> =
+    SpinLock::new("IRQ_HANDLERS", [None; plic::MAX_IRQS]);
+
+/// Runs `handler` in interrupt context whenever device interrupt `irq`
+/// fires. Handlers mustn't block.
+pub fn register(irq: u32, handler: fn()) {
+    HANDLERS.lock()[irq as usize] = Some(handler);
+    plic::enable(irq);
+}
+
+pub fn unregister(irq: u32) {
+    plic::disable(irq);
+    HANDLERS.lock()[irq as usize] = None;
+}
+
+/// Handles every pending device interrupt.
+pub(crate) fn handle_external() {
+    while let Some(irq) = plic::claim() {
+        let handler = HANDLERS.lock().get(irq as usize).copied().flatten();
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("spurious irq {}", irq),
+        }
+        plic::complete(irq);
+    }
+}
+
+pub fn enable() {
+    unsafe {
+        asm!(
+            "csrs mstatus, {}",
+             in(reg) MSTATUS_MIE,
+        )
+    }
+}
+
+pub fn disable() {
+    unsafe {
+        asm!(
+            "csrc mstatus, {}",
+             in(reg) MSTATUS_MIE,
+        )
+    }
+}
+
+pub fn is_enabled() -> bool {
+    let mstatus: u64;
+    unsafe { asm!("csrr {}, mstatus", out(reg) mstatus) };
+    mstatus & MSTATUS_MIE != 0
+}
+
+/// Disables interrupts, remembering whether they were enabled
+/// so that the matching outermost [`pop_off`] can restore them.
+pub fn push_off() {
+    let was_enabled = is_enabled();
+    disable();
+    cpu::current().push_off(was_enabled);
+}
+
+pub fn pop_off() {
+    assert!(!is_enabled(), "pop_off with interrupts enabled");
+    if cpu::current().pop_off() {
+        enable();
+    }
+}
@@ -0,0 +1,95 @@
+//! Platform-level interrupt controller, which routes device interrupts
+//! to harts. Each hart gets the interrupts enabled in its M-mode context.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    cpu,
+    dev::{DeviceClass, Driver, ProbeError},
+    fdt::Node,
+};
+
+pub const COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+/// Where QEMU virt maps the PLIC, so device interrupts work even if
+/// probing never finds it.
+const DEFAULT_PLIC_BASE: usize = 0x0c00_0000;
+/// Interrupt sources handled. QEMU virt has more, but no devices past these.
+pub const MAX_IRQS: usize = 64;
+
+// Register offsets
+const PRIORITY: usize = 0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+static PLIC_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_PLIC_BASE);
+
+pub struct PlicDriver;
+
+pub static DRIVER: PlicDriver = PlicDriver;
+
+impl Driver for PlicDriver {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Platform
+    }
+
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        PLIC_BASE.store(regs.base, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Every hart has an M-mode then an S-mode context.
+fn context() -> usize {
+    2 * cpu::id()
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (PLIC_BASE.load(Ordering::Relaxed) + offset) as *mut u32
+}
+
+fn enable_word(irq: u32) -> *mut u32 {
+    reg(ENABLE + context() * ENABLE_STRIDE + irq as usize / 32 * 4)
+}
+
+/// Lets `irq` through to the current hart.
+pub fn enable(irq: u32) {
+    assert!((irq as usize) < MAX_IRQS && irq != 0, "invalid irq {}", irq);
+    unsafe {
+        reg(PRIORITY + irq as usize * 4).write_volatile(1);
+        let word = enable_word(irq);
+        word.write_volatile(word.read_volatile() | 1 << (irq % 32));
+        reg(THRESHOLD + context() * CONTEXT_STRIDE).write_volatile(0);
+    }
+}
+
+pub fn disable(irq: u32) {
+    unsafe {
+        let word = enable_word(irq);
+        word.write_volatile(word.read_volatile() & !(1 << (irq % 32)));
+    }
+}
+
+/// Takes the highest-priority pending interrupt, which stays masked
+/// until [`complete`]d.
+pub fn claim() -> Option<u32> {
+    match unsafe { reg(CLAIM + context() * CONTEXT_STRIDE).read_volatile() } {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+pub fn complete(irq: u32) {
+    unsafe { reg(CLAIM + context() * CONTEXT_STRIDE).write_volatile(irq) };
+}
@@ -4,11 +4,23 @@
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "_test_main"]
 
+pub mod cmdline;
+pub mod cpu;
+pub mod dev;
+pub mod fdt;
+pub mod fs;
+pub mod futex;
 pub mod io;
 pub mod irq;
+pub mod log;
 pub mod proc;
+pub mod syscall;
 pub mod timer;
+pub mod tracing;
+pub mod trap;
+pub mod ulib;
 pub mod utils;
+pub mod virtio;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,263 @@
+//! Leveled kernel logging. Every line says where it comes from:
+//!
+//! ```text
+//! [    1.234567] hart0 pid1 DEBUG proc: switching to pid 1
+//! ```
+//!
+//! Messages more verbose than [`STATIC_MAX_LEVEL`] are compiled out; the
+//! rest are filtered at runtime, per module (see [`set_module_level`]) or
+//! globally (see [`set_level`]).
+
+use core::fmt::{Arguments, Display};
+
+use crate::{
+    cmdline::ParamValue,
+    cpu, param, println, proc,
+    utils::{collections::ArrayVec, sync::SpinLock},
+    warn,
+};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Self; 5] = [
+        Self::Error,
+        Self::Warn,
+        Self::Info,
+        Self::Debug,
+        Self::Trace,
+    ];
+
+    /// Lowercase name, as on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.pad(match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        })
+    }
+}
+
+impl ParamValue for Level {
+    fn parse(value: &'static str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == value)
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Most verbose level compiled in, raised by the `log-debug` and
+/// `log-trace` features.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "log-trace") {
+    Level::Trace
+} else if cfg!(feature = "log-debug") {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+/// Module levels set at compile time, by the `log-trace-<module>` features.
+const STATIC_MODULE_LEVELS: &[(&str, Level)] = &[
+    #[cfg(feature = "log-trace-proc")]
+    ("poc_rxv6::proc", Level::Trace),
+    #[cfg(feature = "log-trace-trap")]
+    ("poc_rxv6::trap", Level::Trace),
+];
+
+pub const MAX_MODULE_LEVELS: usize = 16;
+
+param!(
+    LEVEL: Level = Level::Info,
+    "log.level",
+    "Most verbose level logged by modules without their own"
+);
+static MODULE_LEVELS: SpinLock<ArrayVec<(&'static str, Level), MAX_MODULE_LEVELS>> =
+    SpinLock::new("LOG_MODULE_LEVELS", ArrayVec::new());
+
+/// Sets the level of modules without their own.
+pub fn set_level(level: Level) {
+    LEVEL.set(level);
+}
+
+pub fn level() -> Level {
+    LEVEL.get()
+}
+
+/// Sets the level of `module` and its submodules, by path, e.g.
+/// `poc_rxv6::utils::sync`. Takes precedence over compile-time levels.
+/// Only [`MAX_MODULE_LEVELS`] modules can have one: any more keep the
+/// levels they had, and a warning says so.
+pub fn set_module_level(module: &'static str, level: Level) {
+    let mut levels = MODULE_LEVELS.lock();
+    if let Some(entry) = levels.iter_mut().find(|(prefix, _)| *prefix == module) {
+        entry.1 = level;
+    } else if levels.try_push((module, level)).is_err() {
+        // Logging looks the levels up
+        drop(levels);
+        warn!(
+            "no room for the level of {}, past {} modules",
+            module, MAX_MODULE_LEVELS
+        );
+    }
+}
+
+/// Goes back to the global and compile-time levels for `module`.
+pub fn clear_module_level(module: &'static str) {
+    let mut levels = MODULE_LEVELS.lock();
+    if let Some(index) = levels.iter().position(|(prefix, _)| *prefix == module) {
+        levels.remove(index);
+    }
+}
+
+/// Whether `prefix` is `module` or one of its parents.
+fn is_within(module: &str, prefix: &str) -> bool {
+    module
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Level of the most specific filter matching `module`.
+fn most_specific<'a>(
+    levels: impl Iterator<Item = &'a (&'static str, Level)>,
+    module: &str,
+) -> Option<Level> {
+    levels
+        .filter(|(prefix, _)| is_within(module, prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|&(_, level)| level)
+}
+
+/// Whether a message at `level` from `module` gets logged.
+pub fn enabled(level: Level, module: &str) -> bool {
+    let max = most_specific(MODULE_LEVELS.lock().iter(), module)
+        .or_else(|| most_specific(STATIC_MODULE_LEVELS.iter(), module))
+        .unwrap_or_else(self::level);
+    level <= max
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: Arguments) {
+    let module = module.strip_prefix("poc_rxv6::").unwrap_or(module);
+    match proc::try_current_pid() {
+        Some(pid) => println!(
+            "hart{} pid{} {:<5} {}: {}",
+            cpu::id(),
+            pid,
+            level,
+            module,
+            args
+        ),
+        None => println!("hart{} -    {:<5} {}: {}", cpu::id(), level, module, args),
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($args:tt)*) => {{
+        let level = $level;
+        if level <= $crate::log::STATIC_MAX_LEVEL
+            && $crate::log::enabled(level, module_path!())
+        {
+            $crate::log::_log(level, module_path!(), format_args!($($args)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($args:tt)*) => ($crate::log!($crate::log::Level::Error, $($args)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)*) => ($crate::log!($crate::log::Level::Warn, $($args)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)*) => ($crate::log!($crate::log::Level::Info, $($args)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)*) => ($crate::log!($crate::log::Level::Debug, $($args)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($args:tt)*) => ($crate::log!($crate::log::Level::Trace, $($args)*));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn log_module_filters() {
+        assert!(enabled(Level::Error, "poc_rxv6::proc"));
+        assert!(!enabled(Level::Trace, "poc_rxv6::timer"));
+
+        set_module_level("poc_rxv6::timer", Level::Trace);
+        set_module_level("poc_rxv6::timer::soft", Level::Warn);
+        assert!(enabled(Level::Trace, "poc_rxv6::timer"));
+        assert!(enabled(Level::Trace, "poc_rxv6::timer::clock"));
+        assert!(
+            !enabled(Level::Info, "poc_rxv6::timer::soft"),
+            "the most specific filter should win"
+        );
+        assert!(
+            !enabled(Level::Trace, "poc_rxv6::timers"),
+            "filters match whole path components"
+        );
+        clear_module_level("poc_rxv6::timer");
+        clear_module_level("poc_rxv6::timer::soft");
+
+        set_level(Level::Error);
+        assert!(!enabled(Level::Warn, "poc_rxv6::timer"));
+        set_level(Level::Info);
+        assert!(enabled(Level::Info, "poc_rxv6::timer"));
+    }
+
+    #[test_case]
+    pub fn log_module_levels_full() {
+        const MODULES: [&str; MAX_MODULE_LEVELS + 1] = [
+            "m0", "m1", "m2", "m3", "m4", "m5", "m6", "m7", "m8", "m9", "m10", "m11", "m12", "m13",
+            "m14", "m15", "m16",
+        ];
+        for module in MODULES {
+            set_module_level(module, Level::Trace);
+        }
+        assert!(enabled(Level::Trace, MODULES[MAX_MODULE_LEVELS - 1]));
+        assert!(
+            !enabled(Level::Trace, MODULES[MAX_MODULE_LEVELS]),
+            "levels past the table's capacity should be ignored"
+        );
+        for module in MODULES {
+            clear_module_level(module);
+        }
+    }
+}
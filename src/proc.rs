@@ -1,11 +1,17 @@
 use core::{
     arch::{asm, naked_asm},
-    mem::MaybeUninit,
-    ptr::addr_of_mut,
-    sync::atomic::{AtomicUsize, Ordering},
+    mem::{MaybeUninit, transmute},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
 };
 
-use crate::{irq, println, timer, utils::sync::RWCell};
+use crate::{
+    cpu, debug, irq, param,
+    timer::{self, Instant, TimerId},
+    trace,
+    tracing::{self, Event},
+    utils::sync::{SpinLock, SpinLockGuard, SpinRwLock, lockdep, rcu},
+};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -20,13 +26,16 @@ pub struct Context {
 
 impl Context {
     pub fn new(stack_end: *mut u8, entry: fn()) -> Self {
+        let mut a = [0; 8];
+        a[0] = entry as u64;
         Self {
-            ra: entry as u64,
-            sp: stack_end as u64,
+            ra: process_entry as unsafe extern "C" fn(usize) -> ! as u64,
+            // The ABI wants a 16-byte aligned stack
+            sp: stack_end as u64 & !0xF,
             gp: 0,
             t: [0; 7],
             s: [0; 12],
-            a: [0; 8],
+            a,
         }
     }
 
@@ -37,7 +46,9 @@ impl Context {
 
 unsafe extern "C" fn switch(from: *mut Context, to: *const Context) {
     assert_eq!(size_of::<Context>(), 8 * 30);
-    println!("CTX SWITCH ({:?} -> {:?})", from, to);
+    trace!("switch ({:?} -> {:?})", from, to);
+    tracing::tracepoint(Event::Switch, [from as u64, to as u64]);
+    rcu::quiescent_state();
     unsafe { _switch(from, to) };
 }
 
@@ -105,7 +116,7 @@ unsafe extern "C" fn _switch(from: *mut Context, to: *const Context) {
         "ld  a5, 27*8(a1)",
         "ld  a6, 28*8(a1)",
         "ld  a7, 29*8(a1)",
-        "ld  a1, 24*8(a1)",
+        "ld  a1, 23*8(a1)",
         "ret",
     );
 }
@@ -117,30 +128,37 @@ pub enum ProcessState {
     Free,
     Idle,
     Running,
-    Sleeping { start: u64, duration: u64 },
+    Sleeping { start: Instant, duration: Duration },
+    /// Waiting for a [`wake`], or until `deadline` if any.
+    Blocked {
+        deadline: Option<Instant>,
+    },
 }
 
-const MAX_PROCESSES: usize = 2;
+pub const MAX_PROCESSES: usize = 8;
 const PROCESS_STACK_SIZE: usize = 8 * 1024;
 
 #[unsafe(link_section = ".stack.processes")]
 static mut STACKS: [[u8; PROCESS_STACK_SIZE]; MAX_PROCESSES] =
     [[0; PROCESS_STACK_SIZE]; MAX_PROCESSES];
-static SCHEDULER_CONTEXT: RWCell<Context> = RWCell::new("SCHED_CTX", Context::zeroed());
-static CURRENT_PID: RWCell<PID> = RWCell::new("CPID", 0);
+static SCHEDULER_CONTEXT: SpinLock<Context> = SpinLock::new("SCHED_CTX", Context::zeroed());
+static CURRENT_PID: SpinLock<Option<PID>> = SpinLock::new("CPID", None);
 pub static PROCESSES: Processes = Processes::new();
 
 #[derive(Debug)]
 pub struct Process {
-    state: RWCell<ProcessState>,
-    context: RWCell<Context>,
+    state: SpinRwLock<ProcessState>,
+    context: SpinLock<Context>,
+    /// Set by [`interrupt`], e.g. on ^C.
+    interrupted: AtomicBool,
 }
 
 impl Process {
     pub const fn uninit() -> Self {
         Self {
-            state: RWCell::new("PROC_STATE", ProcessState::Free),
-            context: RWCell::new("PROC_CTX", Context::zeroed()),
+            state: SpinRwLock::new("PROC_STATE", ProcessState::Free),
+            context: SpinLock::new("PROC_CTX", Context::zeroed()),
+            interrupted: AtomicBool::new(false),
         }
     }
 
@@ -151,8 +169,9 @@ impl Process {
             ProcessState::Free,
             "tried to initialize an already initialized process"
         );
-        let sp = addr_of_mut!(stack[PROCESS_STACK_SIZE - 1]);
+        let sp = stack.as_mut_ptr_range().end;
         self.context.set(Context::new(sp, entry));
+        self.interrupted.store(false, Ordering::Relaxed);
         *state = ProcessState::Idle;
     }
 
@@ -165,11 +184,23 @@ impl Process {
             ProcessState::Free => false,
             ProcessState::Idle => true,
             ProcessState::Running => false,
-            ProcessState::Sleeping { start, duration } => timer::current_time() > start + duration,
+            ProcessState::Sleeping { start, duration } => start.elapsed() >= duration,
+            ProcessState::Blocked { deadline } => {
+                deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            }
         }
     }
 }
 
+/// First code run by every process: `entry` arrives in `a0`
+/// through the initial [`Context`].
+unsafe extern "C" fn process_entry(entry: usize) -> ! {
+    let entry: fn() = unsafe { transmute(entry) };
+    irq::enable();
+    entry();
+    exit();
+}
+
 pub struct Processes {
     buffer: [Process; MAX_PROCESSES],
     len: AtomicUsize,
@@ -198,6 +229,8 @@ impl Processes {
         self.len.fetch_add(1, core::sync::atomic::Ordering::Release);
         let stack = unsafe { &mut STACKS[pid] };
         process.init(stack, entry);
+        lockdep::forget_process(pid as PID);
+        restart_tick();
         return pid as PID;
     }
 
@@ -210,52 +243,371 @@ impl Processes {
     }
 }
 
-pub fn run_scheduler(quanta: u64) -> ! {
-    loop {
-        let pid = current_pid();
+/// Scheduler tick state, for tickless operation.
+struct Tick {
+    quantum: Duration,
+    /// Preempts the running process once its quantum is over.
+    timer: Option<TimerId>,
+    /// When the running process started going without a tick.
+    tickless_since: Option<Instant>,
+}
+
+static TICK: SpinLock<Tick> = SpinLock::new(
+    "TICK",
+    Tick {
+        quantum: Duration::ZERO,
+        timer: None,
+        tickless_since: None,
+    },
+);
+param!(
+    TICKLESS: bool = true,
+    "sched.tickless",
+    "Scheduling policy: only tick with several processes to run"
+);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICKS_AVOIDED: AtomicU64 = AtomicU64::new(0);
+static TICKLESS_RUNS: AtomicU64 = AtomicU64::new(0);
+static IDLE_ENTRIES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickStats {
+    /// Quanta that expired, each costing a timer interrupt.
+    pub ticks: u64,
+    /// Quanta that went by without a tick, while idle or with a
+    /// single runnable process.
+    pub ticks_avoided: u64,
+    /// Times a process was let run without a tick.
+    pub tickless_runs: u64,
+    /// Times the scheduler waited for an interrupt, with nothing to run.
+    pub idle_entries: u64,
+}
+
+pub fn tick_stats() -> TickStats {
+    TickStats {
+        ticks: TICKS.load(Ordering::Relaxed),
+        ticks_avoided: TICKS_AVOIDED.load(Ordering::Relaxed),
+        tickless_runs: TICKLESS_RUNS.load(Ordering::Relaxed),
+        idle_entries: IDLE_ENTRIES.load(Ordering::Relaxed),
+    }
+}
+
+/// When enabled (the default), the scheduler only sets a quantum timer
+/// if another process is waiting to run, and sleeps until the next
+/// timer when none is.
+pub fn set_tickless(enabled: bool) {
+    TICKLESS.set(enabled);
+}
+
+pub fn is_tickless() -> bool {
+    TICKLESS.get()
+}
+
+fn count_avoided_ticks(since: Instant, quantum: Duration) {
+    let avoided = since.elapsed().as_nanos() / quantum.as_nanos().max(1);
+    TICKS_AVOIDED.fetch_add(avoided as u64, Ordering::Relaxed);
+}
+
+fn start_tick(tick: &mut Tick) {
+    let deadline = Instant::now().saturating_add(tick.quantum);
+    tick.timer = Some(timer::add_timer(deadline, expire_quantum, 0));
+}
+
+/// Something else became runnable: if the running process was going
+/// without a tick, it now has to share.
+fn restart_tick() {
+    let mut tick = TICK.lock();
+    if let Some(since) = tick.tickless_since.take() {
+        count_avoided_ticks(since, tick.quantum);
+        start_tick(&mut tick);
+    }
+}
+
+fn expire_quantum(_: usize) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    TICK.lock().timer = None;
+    cpu::current().set_need_resched();
+}
+
+/// Nothing to run: sleeps until an interrupt, e.g. the next timer.
+fn idle(quantum: Duration) {
+    IDLE_ENTRIES.fetch_add(1, Ordering::Relaxed);
+    let since = Instant::now();
+    // Interrupts are off, but a pending one still ends the wait
+    wait_irq();
+    irq::enable();
+    irq::disable();
+    count_avoided_ticks(since, quantum);
+}
+
+/// Gives every runnable process one quantum, in PID order.
+fn schedule_round(quantum: Duration) {
+    // Let pending interrupts in, so an idle system can't deadlock
+    irq::enable();
+    irq::disable();
+    rcu::poll();
+    let runnable = (0..MAX_PROCESSES as PID)
+        .filter(|&pid| PROCESSES.get(pid).can_run())
+        .count();
+    tracing::tracepoint(Event::SchedRound, [runnable as u64, 0]);
+    if runnable == 0 && is_tickless() {
+        return idle(quantum);
+    }
+    for pid in 0..MAX_PROCESSES as PID {
         let process = PROCESSES.get(pid);
-        println!("PROC TEST PID {} ({:?})", pid, process.state);
+        if process.is_free() {
+            continue;
+        }
+        trace!("considering pid {} ({:?})", pid, process.state);
         if process.can_run() {
             process.state.set(ProcessState::Running);
-            println!("PROC START PID {}", pid);
-            timer::schedule(quanta);
+            CURRENT_PID.set(Some(pid));
+            debug!("running pid {}", pid);
+            cpu::current().take_need_resched();
+            {
+                let mut tick = TICK.lock();
+                tick.quantum = quantum;
+                if runnable == 1 && is_tickless() {
+                    TICKLESS_RUNS.fetch_add(1, Ordering::Relaxed);
+                    tick.tickless_since = Some(Instant::now());
+                } else {
+                    start_tick(&mut tick);
+                }
+            }
             let from = SCHEDULER_CONTEXT.get_mut_ptr();
             let to = process.context.get_ptr();
-            irq::enable();
+            tracing::tracepoint(Event::SchedIn, [pid, 0]);
             unsafe { switch(from, to) };
-            irq::disable();
-            println!("PROC END PID {} ({:?})", pid, process.state);
+            tracing::tracepoint(Event::SchedOut, [pid, 0]);
+            {
+                let mut tick = TICK.lock();
+                if let Some(timer) = tick.timer.take() {
+                    timer::cancel_timer(timer);
+                }
+                if let Some(since) = tick.tickless_since.take() {
+                    count_avoided_ticks(since, quantum);
+                }
+            }
+            CURRENT_PID.set(None);
+            debug!("pid {} stopped ({:?})", pid, process.state);
             let mut state = process.state.write();
             if let ProcessState::Running = *state {
                 *state = ProcessState::Idle;
             }
-        } else {
-            println!("PROC SKIP PID {}", pid);
         }
-        let process_count = PROCESSES.len() as u64;
-        CURRENT_PID.set((pid + 1) % process_count);
+    }
+}
+
+param!(
+    pub QUANTUM: Duration = Duration::from_secs(2),
+    "sched.quantum",
+    "Time each process runs before being preempted"
+);
+
+pub fn run_scheduler(quantum: Duration) -> ! {
+    loop {
+        schedule_round(quantum);
+    }
+}
+
+/// Runs the scheduler until every process has exited.
+pub fn run_until_exit(quantum: Duration) {
+    while PROCESSES.len() > 0 {
+        schedule_round(quantum);
     }
 }
 
 pub fn current_pid() -> PID {
+    try_current_pid().expect("not running inside a process")
+}
+
+/// PID of the running process, or [`None`] in the scheduler/boot context.
+pub fn try_current_pid() -> Option<PID> {
     CURRENT_PID.get()
 }
 
-pub fn sleep(duration: u64) {
-    PROCESSES.current().state.set(ProcessState::Sleeping {
-        start: timer::current_time(),
-        duration,
-    });
+/// PID of the running process, if it's allowed to block right now:
+/// not inside a trap handler, nor holding any spinlock.
+pub fn blockable_pid() -> Option<PID> {
+    let cpu = cpu::current();
+    if cpu.in_trap() || cpu.noff() > 0 {
+        return None;
+    }
+    try_current_pid()
+}
+
+pub fn sleep(duration: Duration) {
+    let start = Instant::now();
+    let pid = current_pid();
+    // Not preemptible until the wake-up timer is set
+    irq::push_off();
+    PROCESSES
+        .get(pid)
+        .state
+        .set(ProcessState::Sleeping { start, duration });
+    let timer = timer::add_timer(start.saturating_add(duration), end_sleep, pid as usize);
+    irq::pop_off();
     yield_self();
+    timer::cancel_timer(timer);
+}
+
+fn end_sleep(pid: usize) {
+    let mut state = PROCESSES.get(pid as PID).state.write();
+    if let ProcessState::Sleeping { .. } = *state {
+        *state = ProcessState::Idle;
+        drop(state);
+        restart_tick();
+    }
 }
 
 pub fn yield_self() {
+    assert_eq!(
+        cpu::current().noff(),
+        0,
+        "yielding while holding a spinlock"
+    );
+    let process = PROCESSES.current();
+    let from = process.context.get_mut_ptr();
+    let to = SCHEDULER_CONTEXT.get_ptr();
+    // The scheduler runs with interrupts off; restore ours on the way back
+    let was_enabled = irq::is_enabled();
+    irq::disable();
+    unsafe { switch(from, to) };
+    if was_enabled {
+        irq::enable();
+    }
+}
+
+/// Marks the current process as [`ProcessState::Blocked`] and releases
+/// `guard` before yielding. Since the state changes while `guard` is
+/// still held, a [`wake`] issued under the same lock can't be lost.
+pub fn block<T>(guard: SpinLockGuard<'_, T>) {
+    block_until(guard, None);
+}
+
+/// [`block`], but also giving up once `deadline` passes.
+/// Callers tell a timeout from a [`wake`] by their own bookkeeping,
+/// e.g. whether they're still queued.
+pub fn block_until<T>(guard: SpinLockGuard<'_, T>, deadline: Option<Instant>) {
+    let pid = current_pid();
+    PROCESSES
+        .get(pid)
+        .state
+        .set(ProcessState::Blocked { deadline });
+    let timer = deadline.map(|deadline| timer::add_timer(deadline, end_block, pid as usize));
+    drop(guard);
+    yield_self();
+    if let Some(timer) = timer {
+        timer::cancel_timer(timer);
+    }
+}
+
+fn end_block(pid: usize) {
+    wake(pid as PID);
+}
+
+/// Makes a [`ProcessState::Blocked`] process runnable again.
+pub fn wake(pid: PID) {
+    let mut state = PROCESSES.get(pid).state.write();
+    if let ProcessState::Blocked { .. } = *state {
+        *state = ProcessState::Idle;
+        drop(state);
+        restart_tick();
+    }
+}
+
+/// Interrupts `pid`, cutting short any sleep or wait. Interruptible
+/// waits then fail, and the process is terminated on its way back from
+/// the next system call.
+pub fn interrupt(pid: PID) {
+    PROCESSES
+        .get(pid)
+        .interrupted
+        .store(true, Ordering::Release);
+    end_sleep(pid as usize);
+    wake(pid);
+}
+
+/// Whether the current process has a pending interrupt.
+pub fn is_interrupted() -> bool {
+    try_current_pid().is_some_and(|pid| PROCESSES.get(pid).interrupted.load(Ordering::Acquire))
+}
+
+/// Clears the current process's pending interrupt, returning whether
+/// there was one.
+pub fn take_interrupt() -> bool {
+    try_current_pid()
+        .is_some_and(|pid| PROCESSES.get(pid).interrupted.swap(false, Ordering::AcqRel))
+}
+
+/// Terminates the current process, freeing its slot.
+pub fn exit() -> ! {
+    irq::disable();
     let process = PROCESSES.current();
+    process.state.set(ProcessState::Free);
+    PROCESSES.len.fetch_sub(1, Ordering::Release);
     let from = process.context.get_mut_ptr();
     let to = SCHEDULER_CONTEXT.get_ptr();
     unsafe { switch(from, to) };
+    unreachable!("exited process was resumed");
 }
 
 pub fn wait_irq() {
     unsafe { asm!("wfi") }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::TEST_QUANTA;
+
+    fn spin_for_quanta(quanta: u32) {
+        let start = Instant::now();
+        while start.elapsed() < TEST_QUANTA * quanta {}
+    }
+
+    fn spin_5_quanta() {
+        spin_for_quanta(5);
+    }
+
+    #[test_case]
+    pub fn tickless_single_process() {
+        let before = tick_stats();
+        PROCESSES.create(spin_5_quanta);
+        run_until_exit(TEST_QUANTA);
+        let after = tick_stats();
+        assert_eq!(
+            after.ticks, before.ticks,
+            "a lone process shouldn't be ticked"
+        );
+        assert!(after.tickless_runs > before.tickless_runs);
+        assert!(after.ticks_avoided >= before.ticks_avoided + 4);
+    }
+
+    #[test_case]
+    pub fn ticks_while_sharing() {
+        let before = tick_stats();
+        PROCESSES.create(spin_5_quanta);
+        PROCESSES.create(spin_5_quanta);
+        run_until_exit(TEST_QUANTA);
+        assert!(
+            tick_stats().ticks > before.ticks,
+            "processes sharing the hart should be preempted"
+        );
+    }
+
+    fn sleep_3_quanta() {
+        sleep(TEST_QUANTA * 3);
+    }
+
+    #[test_case]
+    pub fn tickless_idle() {
+        let before = tick_stats();
+        PROCESSES.create(sleep_3_quanta);
+        run_until_exit(TEST_QUANTA);
+        let after = tick_stats();
+        assert!(after.idle_entries > before.idle_entries);
+        assert!(after.ticks_avoided >= before.ticks_avoided + 2);
+        assert_eq!(after.ticks, before.ticks);
+    }
+}
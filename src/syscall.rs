@@ -0,0 +1,156 @@
+//! System call ABI, shared by the kernel and [`crate::ulib`]. The
+//! number goes in `a7` and arguments in `a0`-`a5`; the result comes
+//! back in `a0`, with [`Errno`]s encoded as negative values.
+
+use core::{slice, time::Duration};
+
+use crate::{
+    futex,
+    io::{console, kmsg},
+    timer::clock::{self, ClockId},
+    tracing::{self, Event},
+    trap::TrapFrame,
+};
+
+pub const SYS_FUTEX_WAIT: u64 = 1;
+pub const SYS_FUTEX_WAKE: u64 = 2;
+/// Times are passed as nanoseconds, which last until 2554.
+pub const SYS_CLOCK_GETTIME: u64 = 3;
+pub const SYS_UPTIME: u64 = 4;
+pub const SYS_SETTIMEOFDAY: u64 = 5;
+/// Only the console is open so far, as [`STDIN`], [`STDOUT`] and [`STDERR`].
+pub const SYS_READ: u64 = 6;
+pub const SYS_WRITE: u64 = 7;
+/// Reads the kernel log from a sequence number on, see [`kmsg::read`].
+pub const SYS_DMESG: u64 = 8;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Timeout argument, in nanoseconds, meaning "wait forever".
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// Interrupted, e.g. by ^C.
+    Intr = 4,
+    BadF = 9,
+    /// Try again, e.g. the futex no longer held the expected value.
+    Again = 11,
+    Inval = 22,
+    NoSys = 38,
+    TimedOut = 110,
+}
+
+impl Errno {
+    pub fn from_raw(raw: i64) -> Option<Self> {
+        match raw {
+            4 => Some(Self::Intr),
+            9 => Some(Self::BadF),
+            11 => Some(Self::Again),
+            22 => Some(Self::Inval),
+            38 => Some(Self::NoSys),
+            110 => Some(Self::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Highest errno value, so results up to `u64::MAX - MAX_ERRNO` are valid.
+const MAX_ERRNO: i64 = 4095;
+
+pub fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+pub fn decode(raw: u64) -> SyscallResult {
+    let signed = raw as i64;
+    if (-MAX_ERRNO..0).contains(&signed) {
+        Err(Errno::from_raw(-signed).expect("kernel returned an unknown errno"))
+    } else {
+        Ok(raw)
+    }
+}
+
+fn time_to_raw(time: Duration) -> SyscallResult {
+    time.as_nanos().try_into().map_err(|_| Errno::Inval)
+}
+
+pub fn timeout_from_raw(raw: u64) -> Option<Duration> {
+    match raw {
+        NO_TIMEOUT => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+pub fn timeout_to_raw(timeout: Option<Duration>) -> u64 {
+    match timeout {
+        None => NO_TIMEOUT,
+        Some(timeout) => timeout
+            .as_nanos()
+            .try_into()
+            .unwrap_or(NO_TIMEOUT - 1)
+            .min(NO_TIMEOUT - 1),
+    }
+}
+
+/// Runs the system call requested through `frame`, leaving the result in `a0`.
+pub(crate) fn dispatch(frame: &mut TrapFrame) {
+    let [a0, a1, a2, ..] = frame.a;
+    let number = frame.a[7];
+    tracing::tracepoint(Event::SyscallEnter, [number, a0]);
+    // SAFETY: there's no isolation yet, so processes are trusted with pointers
+    let buffer = || unsafe { slice::from_raw_parts_mut(a1 as *mut u8, a2 as usize) };
+    let result = match number {
+        SYS_FUTEX_WAIT => {
+            futex::futex_wait(a0 as usize, a1 as u32, timeout_from_raw(a2)).map(|()| 0)
+        }
+        SYS_FUTEX_WAKE => Ok(futex::futex_wake(a0 as usize, a1 as usize) as u64),
+        SYS_CLOCK_GETTIME => ClockId::from_raw(a0)
+            .ok_or(Errno::Inval)
+            .and_then(|clock| time_to_raw(clock::clock_gettime(clock))),
+        SYS_UPTIME => time_to_raw(clock::uptime()),
+        SYS_SETTIMEOFDAY => {
+            clock::set_realtime(Duration::from_nanos(a0));
+            Ok(0)
+        }
+        SYS_READ => match a0 {
+            STDIN => console::read(buffer()).map(|len| len as u64),
+            _ => Err(Errno::BadF),
+        },
+        SYS_WRITE => match a0 {
+            STDOUT | STDERR => {
+                let buffer = buffer();
+                console::write(buffer);
+                Ok(buffer.len() as u64)
+            }
+            _ => Err(Errno::BadF),
+        },
+        SYS_DMESG => kmsg::read(a0, buffer()).map(|len| len as u64),
+        _ => Err(Errno::NoSys),
+    };
+    frame.a[0] = encode(result);
+    tracing::tracepoint(Event::SyscallExit, [number, frame.a[0]]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn syscall_result_encoding() {
+        for result in [Ok(0), Ok(42), Err(Errno::Again), Err(Errno::TimedOut)] {
+            assert_eq!(decode(encode(result)), result);
+        }
+        assert_eq!(timeout_from_raw(timeout_to_raw(None)), None);
+        let timeout = Some(Duration::from_millis(5));
+        assert_eq!(timeout_from_raw(timeout_to_raw(timeout)), timeout);
+    }
+}
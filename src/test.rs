@@ -1,6 +1,15 @@
-use core::{any::type_name, arch::naked_asm, panic::PanicInfo};
+use core::{any::type_name, arch::naked_asm, panic::PanicInfo, time::Duration};
 
-use crate::{io, print, println, test_main};
+use crate::{cmdline, dev, fdt, fs, io, irq, param, print, println, test_main, timer, trap};
+
+/// Scheduler quantum for tests running processes.
+pub const TEST_QUANTA: Duration = Duration::from_millis(10);
+
+param!(
+    FILTER: &'static str = "",
+    "test.filter",
+    "Only runs the tests whose name contains this"
+);
 
 unsafe extern "C" {
     static mut __stack_size: u8;
@@ -15,25 +24,49 @@ pub unsafe extern "C" fn _entry() -> ! {
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn start() -> ! {
+pub extern "C" fn start(_hartid: usize, dtb: *const u8) -> ! {
+    unsafe { fdt::init(dtb) }.expect("QEMU should pass a valid device tree");
+    cmdline::init();
+    dev::init();
+    timer::init();
+    irq::setup(trap::_trapvec);
+    io::console::init();
+    // Tests start from an empty file system, on a scratch disk of their
+    // own (see scripts/qemu.sh)
+    fs::FORMAT.set(true);
+    fs::init();
     test_main();
+    io::console::flush();
     io::sifive_test::exit_success();
 }
 
 #[panic_handler]
 pub fn panic_handler(info: &PanicInfo) -> ! {
-    println!(" [failed]\n{}", info);
+    io::enter_panic_mode();
+    println!(" [failed]");
+    io::kmsg::dump_tail(io::kmsg::PANIC_LINES);
+    println!("{}", info);
     io::sifive_test::exit_error(1);
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    println!("Running {} tests:", tests.len());
-    for (i, test) in tests.iter().enumerate() {
-        print!("\t- {}/{}: {}...", i + 1, tests.len(), test.test_name());
+    let filter = FILTER.get();
+    let selected = tests
+        .iter()
+        .filter(|test| test.test_name().contains(filter))
+        .count();
+    println!("Running {} tests:", selected);
+    let tests = tests
+        .iter()
+        .filter(|test| test.test_name().contains(filter));
+    for (i, test) in tests.enumerate() {
+        print!("\t- {}/{}: {}...", i + 1, selected, test.test_name());
         test.run_test();
         println!(" [ok]");
     }
-    println!("all tests passed.")
+    println!("all tests passed.");
+    #[cfg(feature = "lock-stat")]
+    crate::utils::sync::lockstat::report();
 }
 
 pub trait Testable {
@@ -0,0 +1,141 @@
+//! Wall-clock time: the RTC is only read at boot (and written when the
+//! time is set), then `mtime` keeps time from there.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::Instant;
+use crate::{io::goldfish_rtc, utils::sync::SeqLock};
+
+/// Linux's clock ids, as used by `clock_gettime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    /// Time since the Unix epoch. Jumps when set.
+    Realtime = 0,
+    /// Time since boot. Never jumps.
+    Monotonic = 1,
+}
+
+impl ClockId {
+    pub fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Realtime),
+            1 => Some(Self::Monotonic),
+            _ => None,
+        }
+    }
+}
+
+/// Realtime clock reading at some `mtime` instant.
+#[derive(Debug, Clone, Copy)]
+struct Base {
+    at: Instant,
+    realtime: Duration,
+}
+
+static BASE: SeqLock<Base> = SeqLock::new(
+    "CLOCK_BASE",
+    Base {
+        at: Instant::from_cycles(0),
+        realtime: Duration::ZERO,
+    },
+);
+
+/// `mtime` at boot. It isn't reset across a kernel restart without a
+/// machine reset, so it may be well past 0 by then.
+static BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the RTC, anchoring the realtime clock to `mtime`, and notes
+/// when we booted.
+pub fn init() {
+    let now = Instant::now();
+    BOOT.store(now.as_cycles(), Ordering::Relaxed);
+    BASE.set(Base {
+        at: now,
+        realtime: goldfish_rtc::read(),
+    });
+}
+
+/// Time since [`init`].
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant::from_cycles(BOOT.load(Ordering::Relaxed)))
+}
+
+pub fn realtime() -> Duration {
+    let base = BASE.read();
+    base.realtime + base.at.elapsed()
+}
+
+pub fn clock_gettime(clock: ClockId) -> Duration {
+    match clock {
+        ClockId::Realtime => realtime(),
+        ClockId::Monotonic => uptime(),
+    }
+}
+
+/// Sets the realtime clock, and the RTC so it sticks across reboots.
+pub fn set_realtime(time: Duration) {
+    let mut base = BASE.write();
+    base.at = Instant::now();
+    base.realtime = time;
+    goldfish_rtc::write(time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ulib;
+
+    /// 2020-01-01T00:00:00Z, surely before any test run.
+    const RECENTLY: Duration = Duration::from_secs(1_577_836_800);
+
+    #[test_case]
+    pub fn clocks_advance() {
+        let realtime = clock_gettime(ClockId::Realtime);
+        let monotonic = clock_gettime(ClockId::Monotonic);
+        assert!(realtime > RECENTLY, "RTC should have been read at boot");
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(1) {}
+        assert!(clock_gettime(ClockId::Realtime) > realtime);
+        assert!(clock_gettime(ClockId::Monotonic) > monotonic);
+    }
+
+    #[test_case]
+    pub fn uptime_counts_from_boot() {
+        let boot = Instant::from_cycles(BOOT.load(Ordering::Relaxed));
+        assert!(
+            boot.as_cycles() > 0,
+            "mtime should have been recorded at boot"
+        );
+        let uptime = uptime();
+        assert!(uptime <= boot.elapsed());
+        assert!(uptime + Duration::from_millis(1) > boot.elapsed());
+    }
+
+    #[test_case]
+    pub fn set_realtime_jumps() {
+        let original = realtime();
+        let uptime_before = uptime();
+        set_realtime(RECENTLY);
+        let now = realtime();
+        assert!(now >= RECENTLY && now < RECENTLY + Duration::from_secs(1));
+        assert!(uptime() >= uptime_before, "monotonic time shouldn't jump");
+        assert!(goldfish_rtc::read() >= RECENTLY);
+        set_realtime(original + (uptime() - uptime_before));
+    }
+
+    #[test_case]
+    pub fn clock_syscalls() {
+        let before = uptime();
+        let monotonic = ulib::clock_gettime(ClockId::Monotonic);
+        assert!(monotonic >= before && ulib::uptime() >= monotonic);
+
+        let original = ulib::clock_gettime(ClockId::Realtime);
+        assert!(original > RECENTLY);
+        ulib::settimeofday(RECENTLY).unwrap();
+        assert!(ulib::clock_gettime(ClockId::Realtime) < RECENTLY + Duration::from_secs(1));
+        ulib::settimeofday(original + (uptime() - monotonic)).unwrap();
+    }
+}
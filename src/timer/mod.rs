@@ -0,0 +1,252 @@
+use core::{
+    arch::asm,
+    fmt::Debug,
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    cpu,
+    dev::{DeviceClass, Driver, ProbeError},
+    fdt::{self, Node},
+};
+
+pub mod clock;
+mod soft;
+
+pub(crate) use soft::run_expired;
+pub use soft::{MAX_TIMERS, TimerId, add_periodic_timer, add_timer, cancel_timer, next_deadline};
+
+pub const CLINT_COMPATIBLE: &[&str] = &["riscv,clint0", "sifive,clint0"];
+/// Where QEMU virt maps the CLINT, which `mtime` is read from before
+/// devices are probed.
+const DEFAULT_CLINT_BASE: usize = 0x0200_0000;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xBFF8;
+/// QEMU virt's `mtime` rate, for when `/cpus` lacks `timebase-frequency`.
+const DEFAULT_TIMEBASE_FREQ_HZ: u64 = 10_000_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static TIMEBASE_FREQ_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQ_HZ);
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(DEFAULT_CLINT_BASE);
+
+/// Driver for the CLINT, whose `mtime` and `mtimecmp` registers the
+/// timers run on.
+pub struct ClintDriver;
+
+pub static CLINT_DRIVER: ClintDriver = ClintDriver;
+
+impl Driver for ClintDriver {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        CLINT_COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Platform
+    }
+
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        CLINT_BASE.store(regs.base, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Finds the timebase frequency in the device tree, then reads the
+/// wall-clock time from the RTC.
+pub fn init() {
+    let frequency = fdt::get()
+        .and_then(|fdt| fdt.timebase_frequency())
+        .filter(|&frequency| frequency > 0);
+    if let Some(frequency) = frequency {
+        TIMEBASE_FREQ_HZ.store(frequency, Ordering::Relaxed);
+    }
+    clock::init();
+}
+
+fn mtime() -> *mut u64 {
+    (CLINT_BASE.load(Ordering::Relaxed) + MTIME_OFFSET) as *mut u64
+}
+
+/// This hart's comparator.
+fn mtimecmp() -> *mut u64 {
+    (CLINT_BASE.load(Ordering::Relaxed) + MTIMECMP_OFFSET + 8 * cpu::id()) as *mut u64
+}
+
+/// `mtime` ticks per second.
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQ_HZ.load(Ordering::Relaxed)
+}
+
+pub fn current_time() -> u64 {
+    unsafe { mtime().read_volatile() }
+}
+
+/// Converts `duration` to `mtime` cycles, rounding up so that deadlines
+/// are never early, and saturating on overflow.
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    let cycles = (duration.as_nanos() * timebase_frequency() as u128).div_ceil(NANOS_PER_SEC);
+    cycles.try_into().unwrap_or(u64::MAX)
+}
+
+/// Converts `mtime` cycles to a [`Duration`], rounding down.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let nanos = cycles as u128 * NANOS_PER_SEC / timebase_frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Point in time, as read from `mtime`. Monotonic, and never wraps in
+/// practice: a 64-bit counter at 10MHz lasts for millennia.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(current_time())
+    }
+
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Self(cycles)
+    }
+
+    pub const fn as_cycles(&self) -> u64 {
+        self.0
+    }
+
+    /// Time since `earlier`, or zero if it's actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(cycles_to_duration)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_cycles(duration)).map(Self)
+    }
+
+    /// Like [`Instant::checked_add`], but stopping at the end of time,
+    /// e.g. for deadlines that should then never pass.
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        Self(self.0.saturating_add(duration_to_cycles(duration)))
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_cycles(duration)).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Self::Output {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Self::Output {
+        self.duration_since(earlier)
+    }
+}
+
+impl Debug for Instant {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("Instant({:?})", cycles_to_duration(self.0)))
+    }
+}
+
+const MIE_MTIE: u64 = 1 << 7;
+
+/// Arms the timer interrupt to fire at `deadline`, or disarms it.
+/// Meant for [`soft`], which multiplexes every timer onto it.
+fn program(deadline: Option<Instant>) {
+    let Some(deadline) = deadline else {
+        return stop();
+    };
+    // Set `mtimecmp`
+    unsafe {
+        mtimecmp().write_volatile(deadline.as_cycles());
+    }
+
+    // Enable timer interrupts
+    unsafe {
+        asm!("csrs mie, {}", in(reg) MIE_MTIE);
+    }
+}
+
+/// Disarms the timer interrupt until the next timer is added.
+fn stop() {
+    unsafe {
+        asm!("csrc mie, {}", in(reg) MIE_MTIE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn timebase_from_device_tree() {
+        let expected = fdt::get().unwrap().timebase_frequency().unwrap();
+        // Something `init` has to replace
+        TIMEBASE_FREQ_HZ.store(expected + 1, Ordering::Relaxed);
+        init();
+        assert_eq!(timebase_frequency(), expected);
+    }
+
+    #[test_case]
+    pub fn duration_conversions() {
+        assert_eq!(
+            duration_to_cycles(Duration::from_secs(1)),
+            timebase_frequency()
+        );
+        assert_eq!(
+            cycles_to_duration(timebase_frequency()),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            duration_to_cycles(Duration::from_nanos(1)),
+            1,
+            "partial cycles should round up"
+        );
+        assert_eq!(duration_to_cycles(Duration::MAX), u64::MAX);
+    }
+
+    #[test_case]
+    pub fn instant_arithmetic() {
+        let start = Instant::from_cycles(1_000);
+        let later = start + Duration::from_millis(1);
+        assert_eq!(later - start, Duration::from_millis(1));
+        assert_eq!(start - later, Duration::ZERO, "should saturate at zero");
+        assert_eq!(start.checked_duration_since(later), None);
+        assert_eq!(start.checked_add(Duration::MAX), None);
+        assert_eq!(
+            start.saturating_add(Duration::MAX),
+            Instant::from_cycles(u64::MAX)
+        );
+        assert!(Instant::now().elapsed() < Duration::from_secs(1));
+    }
+}
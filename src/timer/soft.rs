@@ -0,0 +1,264 @@
+//! Software timers, multiplexed onto the single `mtimecmp` comparator:
+//! pending timers live in a min-heap by deadline, and the comparator is
+//! always set to the earliest one.
+
+use core::time::Duration;
+
+use super::Instant;
+use crate::utils::{collections::ArrayVec, sync::SpinLock};
+
+pub const MAX_TIMERS: usize = 32;
+
+/// Handle to cancel a timer with. Never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+#[derive(Debug)]
+struct Timer {
+    id: TimerId,
+    deadline: Instant,
+    period: Option<Duration>,
+    callback: fn(usize),
+    arg: usize,
+}
+
+#[derive(Debug)]
+struct TimerHeap {
+    timers: ArrayVec<Timer, MAX_TIMERS>,
+    next_id: u64,
+}
+
+impl TimerHeap {
+    const fn new() -> Self {
+        Self {
+            timers: ArrayVec::new(),
+            next_id: 0,
+        }
+    }
+
+    fn earliest(&self) -> Option<Instant> {
+        self.timers.first().map(|timer| timer.deadline)
+    }
+
+    fn push(&mut self, timer: Timer) {
+        assert!(self.timers.len() < MAX_TIMERS, "too many pending timers");
+        self.timers.push(timer);
+        self.sift_up(self.timers.len() - 1);
+    }
+
+    fn remove(&mut self, index: usize) -> Timer {
+        let last = self.timers.len() - 1;
+        self.timers.swap(index, last);
+        let timer = self.timers.pop().expect("heap shouldn't be empty");
+        if index < self.timers.len() {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+        timer
+    }
+
+    /// Pops the earliest timer, if its deadline is at or before `now`.
+    fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        match self.earliest() {
+            Some(deadline) if deadline <= now => Some(self.remove(0)),
+            _ => None,
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.timers[parent].deadline <= self.timers[index].deadline {
+                break;
+            }
+            self.timers.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut smallest = index;
+            for child in [2 * index + 1, 2 * index + 2] {
+                if child < self.timers.len()
+                    && self.timers[child].deadline < self.timers[smallest].deadline
+                {
+                    smallest = child;
+                }
+            }
+            if smallest == index {
+                break;
+            }
+            self.timers.swap(smallest, index);
+            index = smallest;
+        }
+    }
+}
+
+static TIMERS: SpinLock<TimerHeap> = SpinLock::new("TIMERS", TimerHeap::new());
+
+fn add(deadline: Instant, period: Option<Duration>, callback: fn(usize), arg: usize) -> TimerId {
+    let mut timers = TIMERS.lock();
+    let id = TimerId(timers.next_id);
+    timers.next_id += 1;
+    timers.push(Timer {
+        id,
+        deadline,
+        period,
+        callback,
+        arg,
+    });
+    super::program(timers.earliest());
+    id
+}
+
+/// Runs `callback(arg)` from the timer interrupt once `deadline` passes.
+/// Callbacks run in interrupt context, so they mustn't block.
+pub fn add_timer(deadline: Instant, callback: fn(usize), arg: usize) -> TimerId {
+    add(deadline, None, callback, arg)
+}
+
+/// Runs `callback(arg)` every `period`, until cancelled.
+pub fn add_periodic_timer(period: Duration, callback: fn(usize), arg: usize) -> TimerId {
+    assert!(!period.is_zero(), "periodic timer with a zero period");
+    add(
+        Instant::now().saturating_add(period),
+        Some(period),
+        callback,
+        arg,
+    )
+}
+
+/// Returns whether the timer was still pending.
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    let Some(index) = timers.timers.iter().position(|timer| timer.id == id) else {
+        return false;
+    };
+    timers.remove(index);
+    super::program(timers.earliest());
+    true
+}
+
+/// Deadline of the earliest pending timer.
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().earliest()
+}
+
+/// Runs the callbacks of every expired timer, re-arming periodic
+/// ones, then points the comparator at the next deadline.
+pub(crate) fn run_expired() {
+    let mut expired = ArrayVec::<(fn(usize), usize), MAX_TIMERS>::new();
+    {
+        let mut timers = TIMERS.lock();
+        let now = Instant::now();
+        while let Some(mut timer) = timers.pop_expired(now) {
+            expired.push((timer.callback, timer.arg));
+            if let Some(period) = timer.period {
+                // Skip missed periods rather than firing them in a burst
+                timer.deadline = timer
+                    .deadline
+                    .saturating_add(period)
+                    .max(now.saturating_add(period));
+                timers.push(timer);
+            }
+        }
+        super::program(timers.earliest());
+    }
+    // Outside the lock, since callbacks may add or cancel timers
+    for (callback, arg) in expired.iter() {
+        callback(*arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::irq;
+
+    fn noop(_: usize) {}
+
+    #[test_case]
+    pub fn timer_heap_ordering() {
+        let mut heap = TimerHeap::new();
+        for (id, cycles) in [(0, 5), (1, 1), (2, 4), (3, 2), (4, 3)] {
+            heap.push(Timer {
+                id: TimerId(id),
+                deadline: Instant::from_cycles(cycles),
+                period: None,
+                callback: noop,
+                arg: 0,
+            });
+        }
+        let index = heap
+            .timers
+            .iter()
+            .position(|timer| timer.id == TimerId(2))
+            .unwrap();
+        heap.remove(index);
+
+        assert!(heap.pop_expired(Instant::from_cycles(0)).is_none());
+        let mut order = ArrayVec::<u64, 4>::new();
+        while let Some(timer) = heap.pop_expired(Instant::from_cycles(u64::MAX)) {
+            order.push(timer.deadline.as_cycles());
+        }
+        assert_eq!(order.as_slice(), &[1, 2, 3, 5]);
+    }
+
+    static FIRED: SpinLock<ArrayVec<usize, 8>> = SpinLock::new("TEST_FIRED", ArrayVec::new());
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(arg: usize) {
+        FIRED.lock().push(arg);
+    }
+
+    fn tick(_: usize) {
+        TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Lets timer interrupts in until `done`, or `timeout` elapses.
+    fn wait_until(timeout: Duration, done: impl Fn() -> bool) {
+        let give_up = Instant::now() + timeout;
+        irq::enable();
+        while !done() && Instant::now() < give_up {}
+        irq::disable();
+    }
+
+    #[test_case]
+    pub fn timers_fire_in_deadline_order() {
+        let now = Instant::now();
+        let cancelled = add_timer(now + Duration::from_millis(2), record, 0);
+        for millis in [3, 1, 2] {
+            add_timer(now + Duration::from_millis(millis), record, millis as usize);
+        }
+        assert!(cancel_timer(cancelled));
+        assert!(
+            !cancel_timer(cancelled),
+            "timers can only be cancelled once"
+        );
+
+        wait_until(Duration::from_millis(500), || FIRED.lock().len() == 3);
+        assert_eq!(FIRED.lock().as_slice(), &[1, 2, 3]);
+        assert_eq!(next_deadline(), None);
+    }
+
+    #[test_case]
+    pub fn periodic_timer() {
+        let id = add_periodic_timer(Duration::from_millis(1), tick, 0);
+        wait_until(Duration::from_millis(500), || {
+            TICKS.load(Ordering::SeqCst) >= 3
+        });
+        assert!(cancel_timer(id), "periodic timers should stay pending");
+        let ticks = TICKS.load(Ordering::SeqCst);
+        assert!(ticks >= 3);
+
+        wait_until(Duration::from_millis(5), || false);
+        assert_eq!(
+            TICKS.load(Ordering::SeqCst),
+            ticks,
+            "cancelled timers shouldn't fire"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Trace exporters. [`chrome_json`] emits the Chrome trace event format,
+//! which `chrome://tracing` and Perfetto open directly: traps show as
+//! spans on each hart's track, and processes being scheduled and their
+//! system calls as async spans per process. A process blocking in a
+//! system call leaves the hart with its span still open, so those can't
+//! nest on the hart's track.
+
+use core::fmt::Write;
+
+use super::{Event, NO_PID, Record};
+use crate::{
+    io::{self, console, uart::Uart},
+    timer,
+};
+
+/// Lines around the trace in [`dump_chrome_json`]'s output, to cut it
+/// out of a serial log with.
+pub const BEGIN_MARKER: &str = "--- BEGIN CHROME TRACE ---";
+pub const END_MARKER: &str = "--- END CHROME TRACE ---";
+
+/// Span phase, async span phase, or instant event.
+fn phase(event: Event) -> &'static str {
+    match event {
+        Event::TrapEnter => "B",
+        Event::TrapExit => "E",
+        Event::SchedIn | Event::SyscallEnter => "b",
+        Event::SchedOut | Event::SyscallExit => "e",
+        Event::Switch | Event::SchedRound => "i",
+    }
+}
+
+/// Category, shared by the events beginning and ending a span: async
+/// ones are matched up by it and their id.
+fn category(event: Event) -> &'static str {
+    match event {
+        Event::SchedIn | Event::SchedOut => "sched",
+        Event::TrapEnter | Event::TrapExit => "trap",
+        Event::SyscallEnter | Event::SyscallExit => "syscall",
+        event => event.name(),
+    }
+}
+
+/// Which async span an event begins or ends: that of the process.
+fn async_id(record: &Record) -> Option<u64> {
+    match record.event {
+        Event::SchedIn | Event::SchedOut => Some(record.args[0]),
+        Event::SyscallEnter | Event::SyscallExit => Some(record.pid as u64),
+        _ => None,
+    }
+}
+
+/// Name of the span or instant event.
+fn write_name(w: &mut impl Write, record: &Record) -> core::fmt::Result {
+    match record.event {
+        Event::SchedIn | Event::SchedOut => write!(w, "pid {}", record.args[0]),
+        Event::TrapEnter | Event::TrapExit => w.write_str("trap"),
+        Event::SyscallEnter | Event::SyscallExit => write!(w, "syscall {}", record.args[0]),
+        event => w.write_str(event.name()),
+    }
+}
+
+fn write_record(w: &mut impl Write, record: &Record) -> core::fmt::Result {
+    let ts = timer::cycles_to_duration(record.timestamp);
+    w.write_str("{\"name\":\"")?;
+    write_name(w, record)?;
+    write!(
+        w,
+        "\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{}.{:03},\"pid\":0,\"tid\":{}",
+        category(record.event),
+        phase(record.event),
+        ts.as_micros(),
+        ts.subsec_nanos() % 1000,
+        record.hart
+    )?;
+    if phase(record.event) == "i" {
+        w.write_str(",\"s\":\"t\"")?;
+    }
+    if let Some(id) = async_id(record) {
+        write!(w, ",\"id\":{}", id)?;
+    }
+    w.write_str(",\"args\":{")?;
+    if record.pid != NO_PID {
+        write!(w, "\"pid\":{},", record.pid)?;
+    }
+    write!(
+        w,
+        "\"arg0\":{},\"arg1\":{}}}}}",
+        record.args[0], record.args[1]
+    )
+}
+
+/// Writes `records` as a Chrome trace event JSON object.
+pub fn chrome_json<'a>(
+    w: &mut impl Write,
+    records: impl Iterator<Item = &'a Record>,
+) -> core::fmt::Result {
+    w.write_str("{\"traceEvents\":[")?;
+    for (i, record) in records.enumerate() {
+        if i > 0 {
+            w.write_str(",\n")?;
+        }
+        write_record(w, record)?;
+    }
+    w.write_str("],\"displayTimeUnit\":\"ns\"}\n")
+}
+
+/// Writes this hart's trace straight to the UART, between
+/// [`BEGIN_MARKER`] and [`END_MARKER`] lines. Stops tracing first, so
+/// the dump doesn't trace itself.
+pub fn dump_chrome_json() {
+    super::stop();
+    // Queued output first, then nothing else until the end marker
+    let _guard = io::lock_print();
+    console::flush();
+    let mut uart = Uart;
+    uart.write_str(BEGIN_MARKER);
+    uart.write_char(b'\n');
+    super::with_records(|records| {
+        let _ = chrome_json(&mut uart, records);
+    });
+    uart.write_str(END_MARKER);
+    uart.write_char(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::kmsg::SliceWriter, utils::collections::ArrayVec};
+
+    /// Checks the output is balanced, and counts events.
+    struct JsonChecker {
+        depth: i32,
+        events: usize,
+        text: [u8; 32],
+        len: usize,
+    }
+
+    impl Write for JsonChecker {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for c in s.bytes() {
+                match c {
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' => self.depth -= 1,
+                    _ => {}
+                }
+                if c == b'{' && self.depth == 2 {
+                    self.events += 1;
+                }
+                if self.len < self.text.len() {
+                    self.text[self.len] = c;
+                    self.len += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    pub fn chrome_json_export() {
+        let records = [
+            Record {
+                timestamp: 10,
+                event: Event::SyscallEnter,
+                hart: 0,
+                pid: 1,
+                args: [4, 0],
+            },
+            Record {
+                timestamp: 20,
+                event: Event::SyscallExit,
+                hart: 0,
+                pid: 1,
+                args: [4, 0],
+            },
+        ];
+        let mut checker = JsonChecker {
+            depth: 0,
+            events: 0,
+            text: [0; 32],
+            len: 0,
+        };
+        chrome_json(&mut checker, records.iter()).unwrap();
+        assert_eq!(checker.depth, 0, "brackets should balance");
+        assert_eq!(checker.events, 2);
+        assert!(
+            checker
+                .text
+                .starts_with(b"{\"traceEvents\":[{\"name\":\"sysc")
+        );
+    }
+
+    /// Value of `key` in an event, up to the next `"` or `,`.
+    fn field<'a>(event: &'a str, key: &str) -> &'a str {
+        let start = event.find(key).unwrap() + key.len();
+        let rest = event[start..].trim_start_matches('"');
+        &rest[..rest.find(['"', ',']).unwrap()]
+    }
+
+    fn record(timestamp: u64, event: Event, pid: u32, arg0: u64) -> Record {
+        Record {
+            timestamp,
+            event,
+            hart: 0,
+            pid,
+            args: [arg0, 0],
+        }
+    }
+
+    #[test_case]
+    pub fn chrome_json_blocking_syscall() {
+        // Process 1 blocks in a system call while process 2 makes one
+        let records = [
+            record(10, Event::SchedIn, NO_PID, 1),
+            record(20, Event::SyscallEnter, 1, 5),
+            record(30, Event::SchedOut, NO_PID, 1),
+            record(40, Event::SchedIn, NO_PID, 2),
+            record(50, Event::SyscallEnter, 2, 4),
+            record(60, Event::SyscallExit, 2, 4),
+            record(70, Event::SchedOut, NO_PID, 2),
+            record(80, Event::SchedIn, NO_PID, 1),
+            record(90, Event::SyscallExit, 1, 5),
+            record(100, Event::SchedOut, NO_PID, 1),
+        ];
+        let mut text = [0; 2048];
+        let mut buffer = SliceWriter::new(&mut text);
+        chrome_json(&mut buffer, records.iter()).unwrap();
+        let len = buffer.len();
+        let json = core::str::from_utf8(&text[..len]).unwrap();
+
+        // Spans still open, keyed by track, or by category and id if async
+        let mut open: ArrayVec<((&str, &str), &str), 8> = ArrayVec::new();
+        for event in json.split(",\n") {
+            let name = field(event, "\"name\":");
+            let (key, begins) = match field(event, "\"ph\":") {
+                "B" => (("tid", field(event, "\"tid\":")), true),
+                "E" => (("tid", field(event, "\"tid\":")), false),
+                "b" => ((field(event, "\"cat\":"), field(event, "\"id\":")), true),
+                "e" => ((field(event, "\"cat\":"), field(event, "\"id\":")), false),
+                _ => continue,
+            };
+            if begins {
+                open.push((key, name));
+                continue;
+            }
+            let index = open
+                .iter()
+                .rposition(|(open_key, _)| *open_key == key)
+                .expect("a span should only end once begun");
+            assert_eq!(open.remove(index).1, name, "spans should end in order");
+        }
+        assert!(open.is_empty(), "every span should end");
+    }
+}
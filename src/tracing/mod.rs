@@ -0,0 +1,191 @@
+//! Binary event tracing, in the spirit of ftrace: tracepoints in the
+//! scheduler, trap and system call paths append compact fixed-size
+//! records to a per-hart ring buffer, cheap enough to leave in. See
+//! [`export`] to get them out for visualization on the host.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    cpu::{self, MAX_HARTS},
+    proc, timer,
+    utils::cells::{IrqCell, PerCpu},
+};
+
+pub mod export;
+
+/// Records kept per hart: older ones are overwritten.
+pub const TRACE_RECORDS: usize = 512;
+/// [`Record::pid`] outside of processes.
+pub const NO_PID: u32 = u32::MAX;
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Context switch, with the addresses of the contexts switched from
+    /// and to.
+    Switch = 1,
+    /// Scheduler round, with the number of runnable processes.
+    SchedRound,
+    /// Process chosen to run, with its PID.
+    SchedIn,
+    /// Process handed back to the scheduler, with its PID.
+    SchedOut,
+    /// Trap taken, with `mcause` and `mepc`.
+    TrapEnter,
+    TrapExit,
+    /// System call made, with its number and first argument.
+    SyscallEnter,
+    /// System call returning, with its number and raw result.
+    SyscallExit,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Switch => "switch",
+            Self::SchedRound => "sched_round",
+            Self::SchedIn => "sched_in",
+            Self::SchedOut => "sched_out",
+            Self::TrapEnter => "trap_enter",
+            Self::TrapExit => "trap_exit",
+            Self::SyscallEnter => "syscall_enter",
+            Self::SyscallExit => "syscall_exit",
+        }
+    }
+}
+
+/// 32 bytes, so that a buffer is a power of two.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// `mtime` cycles.
+    pub timestamp: u64,
+    pub event: Event,
+    pub hart: u16,
+    pub pid: u32,
+    pub args: [u64; 2],
+}
+
+#[derive(Debug)]
+struct TraceBuffer {
+    records: [Option<Record>; TRACE_RECORDS],
+    /// Records written since cleared; the next goes at this modulo the size.
+    written: usize,
+}
+
+impl TraceBuffer {
+    const fn new() -> Self {
+        Self {
+            records: [None; TRACE_RECORDS],
+            written: 0,
+        }
+    }
+
+    /// Records, oldest first.
+    fn iter(&self) -> impl Iterator<Item = &Record> {
+        let start = self.written.saturating_sub(TRACE_RECORDS);
+        (start..self.written).filter_map(|index| self.records[index % TRACE_RECORDS].as_ref())
+    }
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+static BUFFERS: PerCpu<IrqCell<TraceBuffer>> =
+    PerCpu::new([const { IrqCell::new(TraceBuffer::new()) }; MAX_HARTS]);
+
+pub fn start() {
+    TRACING.store(true, Ordering::Release);
+}
+
+pub fn stop() {
+    TRACING.store(false, Ordering::Release);
+}
+
+pub fn is_tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Drops this hart's records.
+pub fn clear() {
+    BUFFERS.with(|buffer| buffer.with(|buffer| *buffer = TraceBuffer::new()));
+}
+
+/// Records `event` if tracing. Costs a load and a branch otherwise.
+#[inline]
+pub fn tracepoint(event: Event, args: [u64; 2]) {
+    if is_tracing() {
+        record(event, args);
+    }
+}
+
+#[cold]
+fn record(event: Event, args: [u64; 2]) {
+    let record = Record {
+        timestamp: timer::current_time(),
+        event,
+        hart: cpu::id() as u16,
+        pid: proc::try_current_pid().map_or(NO_PID, |pid| pid as u32),
+        args,
+    };
+    BUFFERS.with(|buffer| {
+        buffer.with(|buffer| {
+            buffer.records[buffer.written % TRACE_RECORDS] = Some(record);
+            buffer.written += 1;
+        })
+    });
+}
+
+/// Runs `f` on this hart's records, oldest first.
+pub fn with_records<R>(f: impl FnOnce(&mut dyn Iterator<Item = &Record>) -> R) -> R {
+    BUFFERS.with(|buffer| buffer.with(|buffer| f(&mut buffer.iter())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{syscall::SYS_UPTIME, ulib};
+
+    #[test_case]
+    pub fn tracing_records_syscalls() {
+        clear();
+        ulib::uptime();
+        assert_eq!(
+            with_records(|records| records.count()),
+            0,
+            "not tracing yet"
+        );
+
+        start();
+        ulib::uptime();
+        stop();
+        with_records(|records| {
+            let mut syscalls = records
+                .filter(|record| matches!(record.event, Event::SyscallEnter | Event::SyscallExit));
+            let enter = syscalls.next().expect("syscall entry should be traced");
+            let exit = syscalls.next().expect("syscall exit should be traced");
+            assert_eq!(enter.event, Event::SyscallEnter);
+            assert_eq!(exit.event, Event::SyscallExit);
+            assert_eq!((enter.args[0], exit.args[0]), (SYS_UPTIME, SYS_UPTIME));
+            assert!(exit.timestamp >= enter.timestamp);
+            assert_eq!(enter.pid, NO_PID);
+        });
+        clear();
+    }
+
+    #[test_case]
+    pub fn trace_buffer_wraps() {
+        let mut buffer = TraceBuffer::new();
+        for i in 0..TRACE_RECORDS as u64 + 3 {
+            buffer.records[buffer.written % TRACE_RECORDS] = Some(Record {
+                timestamp: i,
+                event: Event::SchedRound,
+                hart: 0,
+                pid: NO_PID,
+                args: [0; 2],
+            });
+            buffer.written += 1;
+        }
+        let mut timestamps = buffer.iter().map(|record| record.timestamp);
+        assert_eq!(timestamps.next(), Some(3), "the oldest are overwritten");
+        assert_eq!(timestamps.last(), Some(TRACE_RECORDS as u64 + 2));
+    }
+}
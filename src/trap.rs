@@ -0,0 +1,159 @@
+use core::arch::naked_asm;
+
+use crate::{
+    cpu, irq, proc, syscall, timer, trace,
+    tracing::{self, Event},
+};
+
+/// Registers saved by [`_trapvec`], laid out as on its stack.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub ra: u64,
+    pub gp: u64,
+    pub t: [u64; 7],
+    pub a: [u64; 8],
+    pub s: [u64; 12],
+    pub mepc: u64,
+    pub mstatus: u64,
+    /// Keeps the stack 16-byte aligned.
+    _padding: u64,
+}
+
+impl TrapFrame {
+    /// Were interrupts enabled where the trap was taken?
+    pub fn interrupts_were_enabled(&self) -> bool {
+        self.mstatus & MSTATUS_MPIE != 0
+    }
+}
+
+#[unsafe(no_mangle)]
+#[unsafe(naked)]
+pub unsafe extern "C" fn _trapvec() {
+    naked_asm!(
+        // Save user registers on current stack, except sp & tp (which is hart-local)
+        "addi sp, sp, -32*8",
+        "sd  ra,  0*8(sp)",
+        "sd  gp,  1*8(sp)",
+        "sd  t0,  2*8(sp)",
+        "sd  t1,  3*8(sp)",
+        "sd  t2,  4*8(sp)",
+        "sd  t3,  5*8(sp)",
+        "sd  t4,  6*8(sp)",
+        "sd  t5,  7*8(sp)",
+        "sd  t6,  8*8(sp)",
+        "sd  a0,  9*8(sp)",
+        "sd  a1, 10*8(sp)",
+        "sd  a2, 11*8(sp)",
+        "sd  a3, 12*8(sp)",
+        "sd  a4, 13*8(sp)",
+        "sd  a5, 14*8(sp)",
+        "sd  a6, 15*8(sp)",
+        "sd  a7, 16*8(sp)",
+        "sd  s0, 17*8(sp)",
+        "sd  s1, 18*8(sp)",
+        "sd  s2, 19*8(sp)",
+        "sd  s3, 20*8(sp)",
+        "sd  s4, 21*8(sp)",
+        "sd  s5, 22*8(sp)",
+        "sd  s6, 23*8(sp)",
+        "sd  s7, 24*8(sp)",
+        "sd  s8, 25*8(sp)",
+        "sd  s9, 26*8(sp)",
+        "sd s10, 27*8(sp)",
+        "sd s11, 28*8(sp)",
+        // Save mepc & mstatus, as the handler may switch to
+        // another process, which could trap in the meantime
+        "csrr t0, mepc",
+        "sd t0, 29*8(sp)",
+        "csrr t0, mstatus",
+        "sd t0, 30*8(sp)",
+        // Call trapvec
+        "mv a0, sp",
+        "csrr a1, mcause",
+        "csrr a2, mtval",
+        "call trapvec",
+        // Restore mstatus & mepc
+        "ld t0, 30*8(sp)",
+        "csrw mstatus, t0",
+        "ld t0, 29*8(sp)",
+        "csrw mepc, t0",
+        // Restore registers from current stack, except sp & tp
+        "ld  ra,  0*8(sp)",
+        "ld  gp,  1*8(sp)",
+        "ld  t0,  2*8(sp)",
+        "ld  t1,  3*8(sp)",
+        "ld  t2,  4*8(sp)",
+        "ld  t3,  5*8(sp)",
+        "ld  t4,  6*8(sp)",
+        "ld  t5,  7*8(sp)",
+        "ld  t6,  8*8(sp)",
+        "ld  a0,  9*8(sp)",
+        "ld  a1, 10*8(sp)",
+        "ld  a2, 11*8(sp)",
+        "ld  a3, 12*8(sp)",
+        "ld  a4, 13*8(sp)",
+        "ld  a5, 14*8(sp)",
+        "ld  a6, 15*8(sp)",
+        "ld  a7, 16*8(sp)",
+        "ld  s0, 17*8(sp)",
+        "ld  s1, 18*8(sp)",
+        "ld  s2, 19*8(sp)",
+        "ld  s3, 20*8(sp)",
+        "ld  s4, 21*8(sp)",
+        "ld  s5, 22*8(sp)",
+        "ld  s6, 23*8(sp)",
+        "ld  s7, 24*8(sp)",
+        "ld  s8, 25*8(sp)",
+        "ld  s9, 26*8(sp)",
+        "ld s10, 27*8(sp)",
+        "ld s11, 28*8(sp)",
+        "addi sp, sp, 32*8",
+        // Return from irq
+        "mret"
+    )
+}
+
+const MCAUSE_MTI: u64 = 7 | 1 << 63;
+const MCAUSE_MEI: u64 = 11 | 1 << 63;
+const MCAUSE_ECALL_M: u64 = 11;
+const MSTATUS_MPIE: u64 = 1 << 7;
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn trapvec(frame: &mut TrapFrame, mcause: u64, mtval: u64) {
+    trace!("trap (mcause: {:#X}, mepc: {:#X})", mcause, frame.mepc);
+    if mcause == MCAUSE_ECALL_M {
+        // Not interrupt context: the process is just asking, so it
+        // may block, and should stay preemptible if it was before
+        frame.mepc += 4;
+        if frame.interrupts_were_enabled() {
+            irq::enable();
+        }
+        syscall::dispatch(frame);
+        irq::disable();
+        // Interrupts are delivered on the way back, like signals
+        if proc::take_interrupt() {
+            proc::exit();
+        }
+        return;
+    }
+    tracing::tracepoint(Event::TrapEnter, [mcause, frame.mepc]);
+    let cpu = cpu::current();
+    cpu.enter_trap();
+    match mcause {
+        MCAUSE_MTI => timer::run_expired(),
+        MCAUSE_MEI => irq::handle_external(),
+        _ => panic!(
+            "unhandled irq (mepc: {:#016X}; mcause: {:#016X}; mtval: {:#016X})",
+            frame.mepc, mcause, mtval
+        ),
+    }
+    // The quantum may also expire while the scheduler has interrupts open
+    let preempt = cpu.take_need_resched() && proc::try_current_pid().is_some();
+    cpu.leave_trap();
+    tracing::tracepoint(Event::TrapExit, [mcause, 0]);
+    // Not interrupt context anymore once we're handed to another process
+    if preempt {
+        proc::yield_self();
+    }
+}
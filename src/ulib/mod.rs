@@ -0,0 +1,86 @@
+//! Library for code running in processes. It only reaches the kernel
+//! through system calls, as processes will once they're isolated from it.
+
+use core::{arch::naked_asm, sync::atomic::AtomicU32, time::Duration};
+
+use crate::syscall::{
+    self, Errno, SYS_CLOCK_GETTIME, SYS_DMESG, SYS_FUTEX_WAIT, SYS_FUTEX_WAKE, SYS_READ,
+    SYS_SETTIMEOFDAY, SYS_UPTIME, SYS_WRITE, SyscallResult,
+};
+pub use crate::timer::clock::ClockId;
+
+pub mod sync;
+
+/// Traps into the kernel: arguments are already where the ABI wants them.
+#[unsafe(naked)]
+unsafe extern "C" fn ecall(
+    a0: u64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    a4: u64,
+    a5: u64,
+    a6: u64,
+    number: u64,
+) -> u64 {
+    naked_asm!("ecall", "ret")
+}
+
+fn syscall3(number: u64, a0: u64, a1: u64, a2: u64) -> SyscallResult {
+    syscall::decode(unsafe { ecall(a0, a1, a2, 0, 0, 0, 0, number) })
+}
+
+/// Sleeps while `futex` holds `expected`, until woken by [`futex_wake`]
+/// or `timeout` elapses.
+pub fn futex_wait(
+    futex: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+) -> Result<(), Errno> {
+    syscall3(
+        SYS_FUTEX_WAIT,
+        futex.as_ptr() as u64,
+        expected as u64,
+        syscall::timeout_to_raw(timeout),
+    )
+    .map(|_| ())
+}
+
+/// Wakes up to `count` processes sleeping on `futex`, returning how many were.
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> usize {
+    syscall3(SYS_FUTEX_WAKE, futex.as_ptr() as u64, count as u64, 0)
+        .expect("futex_wake shouldn't fail") as usize
+}
+
+pub fn clock_gettime(clock: ClockId) -> Duration {
+    let nanos = syscall3(SYS_CLOCK_GETTIME, clock as u64, 0, 0)
+        .expect("clock_gettime shouldn't fail for a valid clock");
+    Duration::from_nanos(nanos)
+}
+
+/// Time since boot.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(syscall3(SYS_UPTIME, 0, 0, 0).expect("uptime shouldn't fail"))
+}
+
+/// Sets the realtime clock to `time` since the Unix epoch.
+pub fn settimeofday(time: Duration) -> Result<(), Errno> {
+    let nanos = time.as_nanos().try_into().map_err(|_| Errno::Inval)?;
+    syscall3(SYS_SETTIMEOFDAY, nanos, 0, 0).map(|_| ())
+}
+
+/// Reads from `fd` into `buf`, returning how many bytes were (0 at end
+/// of file). The console gives at most a line at a time.
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall3(SYS_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64).map(|len| len as usize)
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    syscall3(SYS_WRITE, fd, buf.as_ptr() as u64, buf.len() as u64).map(|len| len as usize)
+}
+
+/// Reads kernel log lines numbered `from` on into `buf`, each as
+/// `<seq> <text>\n`, returning how many bytes were.
+pub fn dmesg(from: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    syscall3(SYS_DMESG, from, buf.as_mut_ptr() as u64, buf.len() as u64).map(|len| len as usize)
+}
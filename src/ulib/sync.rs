@@ -0,0 +1,239 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use super::{futex_wait, futex_wake};
+use crate::syscall::Errno;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and somebody may be sleeping on it.
+const CONTENDED: u32 = 2;
+
+/// Mutual exclusion lock that only enters the kernel under contention,
+/// as in Drepper's "Futexes Are Tricky".
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        // We can't tell whether others are sleeping anymore, so assume they are
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            match futex_wait(&self.state, CONTENDED, None) {
+                Ok(()) | Err(Errno::Again) => {}
+                Err(error) => panic!("unexpected futex_wait error: {:?}", error),
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.write_fmt(format_args!("Mutex({:?})", *guard)),
+            None => f.write_str("Mutex(<locked>)"),
+        }
+    }
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'mutex, T> {
+    mutex: &'mutex Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Condition variable for [`Mutex`]-protected state. Waiters sleep on
+/// a sequence number that every notification bumps, so a notification
+/// between unlocking and sleeping isn't lost.
+pub struct Condvar {
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Releases `guard`'s mutex and sleeps until notified, then
+    /// reacquires it. Wakeups may be spurious; re-check the condition.
+    pub fn wait<'mutex, T>(&self, guard: MutexGuard<'mutex, T>) -> MutexGuard<'mutex, T> {
+        self.wait_timeout(guard, None).0
+    }
+
+    /// [`Condvar::wait`], giving up after `timeout`. Also returns
+    /// whether it timed out.
+    pub fn wait_timeout<'mutex, T>(
+        &self,
+        guard: MutexGuard<'mutex, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'mutex, T>, bool) {
+        let mutex = guard.mutex;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        drop(guard);
+        let timed_out = match futex_wait(&self.sequence, sequence, timeout) {
+            Ok(()) | Err(Errno::Again) => false,
+            Err(Errno::TimedOut) => true,
+            Err(error) => panic!("unexpected futex_wait error: {:?}", error),
+        };
+        (mutex.lock(), timed_out)
+    }
+
+    /// Waits for as long as `condition` holds.
+    pub fn wait_while<'mutex, T>(
+        &self,
+        mut guard: MutexGuard<'mutex, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'mutex, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.sequence.fetch_add(1, Ordering::Release);
+        futex_wake(&self.sequence, 1) > 0
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.sequence.fetch_add(1, Ordering::Release);
+        futex_wake(&self.sequence, usize::MAX)
+    }
+}
+
+impl Debug for Condvar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "Condvar(#{})",
+            self.sequence.load(Ordering::Relaxed)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{
+        proc::{self, PROCESSES},
+        test::TEST_QUANTA,
+    };
+
+    const INCREMENTS: usize = 5;
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+
+    fn increment_racily() {
+        for _ in 0..INCREMENTS {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            proc::yield_self();
+            *counter = value + 1;
+        }
+    }
+
+    #[test_case]
+    pub fn ulib_mutex_exclusion() {
+        for _ in 0..3 {
+            PROCESSES.create(increment_racily);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(
+            *COUNTER.lock(),
+            3 * INCREMENTS,
+            "no increment should be lost"
+        );
+    }
+
+    static READY: Mutex<bool> = Mutex::new(false);
+    static READY_CONDVAR: Condvar = Condvar::new();
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn wait_ready() {
+        let ready = READY_CONDVAR.wait_while(READY.lock(), |ready| !*ready);
+        assert!(*ready);
+        WOKEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn set_ready() {
+        proc::yield_self();
+        *READY.lock() = true;
+        READY_CONDVAR.notify_all();
+    }
+
+    #[test_case]
+    pub fn ulib_condvar_notify_all() {
+        PROCESSES.create(wait_ready);
+        PROCESSES.create(wait_ready);
+        PROCESSES.create(set_ready);
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 2);
+    }
+
+    fn wait_unnotified() {
+        let (_, timed_out) =
+            READY_CONDVAR.wait_timeout(READY.lock(), Some(Duration::from_micros(100)));
+        assert!(timed_out, "nobody should notify");
+    }
+
+    #[test_case]
+    pub fn ulib_condvar_timeout() {
+        PROCESSES.create(wait_unnotified);
+        proc::run_until_exit(TEST_QUANTA);
+    }
+}
@@ -1,21 +1,323 @@
-use core::cell::UnsafeCell;
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt::Debug,
+    hint::spin_loop,
+    mem::MaybeUninit,
+    ops::Deref,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
 
-use crate::println;
+use crate::{
+    cpu::{self, MAX_HARTS},
+    irq,
+};
 
-/// Behold: the "I Don't Care-cell", god of undefined behavior.
-/// For all intents and purposes, treat this as an unsafe block.
-pub struct Idc<T>(UnsafeCell<T>);
+const ONCE_INCOMPLETE: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_COMPLETE: u8 = 2;
+const NO_HART: usize = usize::MAX;
 
-impl<T> Idc<T> {
-    pub const fn new(inner: T) -> Self {
-        Self(UnsafeCell::new(inner))
+/// Write-once cell, for globals initialized at runtime. Harts racing
+/// to initialize it spin until the winner is done.
+pub struct Once<T> {
+    state: AtomicU8,
+    /// Hart running the initializer, to catch recursive initialization.
+    /// Interrupts are off meanwhile, so nothing else runs on that hart.
+    initializer: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_INCOMPLETE),
+            initializer: AtomicUsize::new(NO_HART),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ONCE_COMPLETE
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            // SAFETY: initialized once complete, and never written again
+            Some(unsafe { (*self.data.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Runs `init` if nobody did yet, then returns the value. `init`
+    /// runs with interrupts off, so it must not sleep.
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        match self.state.compare_exchange(
+            ONCE_INCOMPLETE,
+            ONCE_RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                irq::push_off();
+                self.initializer.store(cpu::id(), Ordering::Relaxed);
+                // SAFETY: only the hart that moved us to RUNNING gets here
+                unsafe { (*self.data.get()).write(init()) };
+                self.initializer.store(NO_HART, Ordering::Relaxed);
+                irq::pop_off();
+                self.state.store(ONCE_COMPLETE, Ordering::Release);
+            }
+            Err(ONCE_RUNNING) => {
+                if self.initializer.load(Ordering::Relaxed) == cpu::id() {
+                    panic!("Once initialized recursively");
+                }
+                while !self.is_completed() {
+                    spin_loop();
+                }
+            }
+            Err(_) => {}
+        }
+        self.get().expect("Once should be complete")
+    }
+
+    /// Initializes with `value`, or hands it back if already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.call_once(|| value.take().expect("initializer runs once"));
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+}
+
+impl<T: Debug> Debug for Once<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.get() {
+            Some(value) => f.write_fmt(format_args!("Once({:?})", value)),
+            None => f.write_str("Once(<uninit>)"),
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if self.is_completed() {
+            unsafe { self.data.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+/// Value computed on first access.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // SAFETY: `Once` only lets one hart in here, once
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initializer already ran")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Debug, F> Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.once.get() {
+            Some(value) => f.write_fmt(format_args!("Lazy({:?})", value)),
+            None => f.write_str("Lazy(<uninit>)"),
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+/// One `T` per hart. Each hart may only touch its own, with
+/// interrupts disabled so that it can't be preempted (and later
+/// resumed elsewhere) midway; `Sync` values can be shared freely.
+pub struct PerCpu<T> {
+    values: [T; MAX_HARTS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_HARTS]) -> Self {
+        Self { values }
+    }
+
+    /// Runs `f` on this hart's value, with interrupts disabled.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        irq::push_off();
+        let result = f(&self.values[cpu::id()]);
+        irq::pop_off();
+        result
+    }
+
+    /// This hart's value. Only for `Sync` values: we may be running
+    /// on another hart by the time it's used.
+    pub fn current(&self) -> &T
+    where
+        T: Sync,
+    {
+        &self.values[cpu::id()]
+    }
+
+    pub fn get(&self, hart: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.values[hart]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.values.iter()
+    }
+}
+
+impl<T: Debug> Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.with(|value| f.write_fmt(format_args!("PerCpu(#{}: {:?})", cpu::id(), value)))
+    }
+}
+
+// SAFETY: a hart can only reach other harts' values through `&T`
+// when `T: Sync`; otherwise it only touches its own, with interrupts
+// disabled, so nothing else can observe it meanwhile.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+/// Cell that may only be accessed with interrupts disabled, e.g.
+/// for state shared between a hart and its trap handlers. Not `Sync`:
+/// share it between harts through [`PerCpu`].
+pub struct IrqCell<T> {
+    borrowed: Cell<bool>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> IrqCell<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            borrowed: Cell::new(false),
+            data: UnsafeCell::new(data),
+        }
     }
 
-    #[inline]
-    pub fn get(&self) -> &mut T {
-        println!("Now this is unsafe!");
-        unsafe { &mut *self.0.get() }
+    /// Panics if interrupts are enabled, or when re-entered from `f`.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        assert!(
+            !irq::is_enabled(),
+            "IrqCell accessed with interrupts enabled"
+        );
+        assert!(!self.borrowed.replace(true), "IrqCell already borrowed");
+        // SAFETY: interrupts are off and we're not re-entrant, so
+        // this is the only reference
+        let result = f(unsafe { &mut *self.data.get() });
+        self.borrowed.set(false);
+        result
+    }
+
+    /// [`IrqCell::with`], disabling interrupts for the duration of `f`.
+    pub fn with_irqs_off<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        irq::push_off();
+        let result = self.with(f);
+        irq::pop_off();
+        result
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
     }
 }
 
-unsafe impl<T> Sync for Idc<T> {}
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test_case]
+    pub fn once_initializes_once() {
+        let once = Once::new();
+        assert_eq!(once.get(), None);
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1, "later initializers shouldn't run");
+        assert_eq!(once.set(3), Err(3));
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test_case]
+    pub fn once_initializes_with_irqs_off() {
+        let once = Once::new();
+        irq::enable();
+        once.call_once(irq::is_enabled);
+        assert!(irq::is_enabled(), "interrupts should be restored");
+        irq::disable();
+        assert_eq!(
+            once.get(),
+            Some(&false),
+            "a preempted initializer would look recursive"
+        );
+    }
+
+    static LAZY_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static LAZY: Lazy<usize> = Lazy::new(|| {
+        LAZY_CALLS.fetch_add(1, Ordering::Relaxed);
+        42
+    });
+
+    #[test_case]
+    pub fn lazy_initializes_on_first_access() {
+        assert_eq!(LAZY_CALLS.load(Ordering::Relaxed), 0);
+        assert_eq!(*LAZY, 42);
+        assert_eq!(*LAZY, 42);
+        assert_eq!(LAZY_CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    pub fn percpu_is_hart_local() {
+        let per_cpu = PerCpu::new([const { IrqCell::new(0) }; MAX_HARTS]);
+        per_cpu.with(|cell| cell.with(|value| *value += 1));
+        per_cpu.with(|cell| cell.with(|value| assert_eq!(*value, 1)));
+
+        let counters = PerCpu::new([const { AtomicUsize::new(0) }; MAX_HARTS]);
+        counters.current().fetch_add(1, Ordering::Relaxed);
+        let total: usize = counters.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+        assert_eq!(total, 1);
+        assert_eq!(counters.get(cpu::id()).load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    pub fn irqcell_disables_irqs() {
+        let cell = IrqCell::new(0);
+        irq::enable();
+        cell.with_irqs_off(|value| {
+            assert!(!irq::is_enabled());
+            *value = 1;
+        });
+        assert!(irq::is_enabled(), "interrupts should be restored");
+        irq::disable();
+        assert_eq!(cell.with(|value| *value), 1);
+    }
+}
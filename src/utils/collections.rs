@@ -38,6 +38,37 @@ impl<T, const CAPACITY: usize> ArrayVec<T, CAPACITY> {
         self.buffer[self.len] = MaybeUninit::new(value);
         self.len += 1;
     }
+
+    /// Pushes `value`, or hands it back if full.
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        if self.len == CAPACITY {
+            return Err(value);
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        // SAFETY: the slot was initialized, and is now out of bounds
+        Some(unsafe { self.buffer[self.len].assume_init_read() })
+    }
+
+    /// Removes the element at `index`, shifting the ones after it.
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "ArrayVec index out of bounds");
+        // SAFETY: in bounds, and the hole is closed right after
+        unsafe {
+            let ptr = self.as_mut_ptr().add(index);
+            let value = ptr.read();
+            core::ptr::copy(ptr.add(1), ptr, self.len - index - 1);
+            self.len -= 1;
+            value
+        }
+    }
 }
 
 impl<T, const CAPACITY: usize> Drop for ArrayVec<T, CAPACITY> {
@@ -73,3 +104,110 @@ impl<T, I: SliceIndex<[T]>, const CAPACITY: usize> IndexMut<I> for ArrayVec<T, C
         IndexMut::index_mut(&mut **self, index)
     }
 }
+
+/// Fixed-capacity ring buffer.
+#[derive(Debug)]
+pub struct ArrayDeque<T, const CAPACITY: usize> {
+    buffer: [MaybeUninit<T>; CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl<T, const CAPACITY: usize> ArrayDeque<T, CAPACITY> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [const { MaybeUninit::uninit() }; CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == CAPACITY
+    }
+
+    fn slot(&self, index: usize) -> usize {
+        (self.head + index) % CAPACITY
+    }
+
+    pub fn push_back(&mut self, value: T) {
+        assert!(!self.is_full(), "ArrayDeque is full");
+        let slot = self.slot(self.len);
+        self.buffer[slot] = MaybeUninit::new(value);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        // SAFETY: slots in [head, head + len) are initialized
+        let value = unsafe { self.buffer[self.head].assume_init_read() };
+        self.head = self.slot(1);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        let slot = self.slot(self.len);
+        // SAFETY: slot was the last initialized one
+        Some(unsafe { self.buffer[slot].assume_init_read() })
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None;
+        }
+        // SAFETY: slots in [head, head + len) are initialized
+        Some(unsafe { self.buffer[self.slot(index)].assume_init_ref() })
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    pub fn back(&self) -> Option<&T> {
+        self.len.checked_sub(1).and_then(|index| self.get(index))
+    }
+
+    /// Removes the element at `index`, shifting the ones after it.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+        // SAFETY: slot is initialized, and is overwritten/forgotten below
+        let value = unsafe { self.buffer[self.slot(index)].assume_init_read() };
+        for i in index..self.len - 1 {
+            let (to, from) = (self.slot(i), self.slot(i + 1));
+            self.buffer[to] = MaybeUninit::new(unsafe { self.buffer[from].assume_init_read() });
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).filter_map(|index| self.get(index))
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+}
+
+impl<T, const CAPACITY: usize> Drop for ArrayDeque<T, CAPACITY> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
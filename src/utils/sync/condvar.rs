@@ -0,0 +1,102 @@
+use crate::proc;
+
+use super::{MutexGuard, SpinLock, WaitQueue};
+
+/// Condition variable for [`super::Mutex`]-protected state.
+pub struct Condvar {
+    waiters: SpinLock<WaitQueue>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new("CONDVAR", WaitQueue::new()),
+        }
+    }
+
+    /// Releases `guard`'s mutex and sleeps until notified, then
+    /// reacquires it. Wakeups may be spurious; re-check the condition.
+    pub fn wait<'mutex, T>(&self, guard: MutexGuard<'mutex, T>) -> MutexGuard<'mutex, T> {
+        let mutex = guard.mutex();
+        let me = proc::current_pid();
+        let mut waiters = self.waiters.lock();
+        waiters.push(me);
+        // Unlocking under `waiters` means a notify can't slip in before we block
+        drop(guard);
+        loop {
+            proc::block(waiters);
+            waiters = self.waiters.lock();
+            if !waiters.contains(me) {
+                break;
+            }
+        }
+        drop(waiters);
+        mutex.lock()
+    }
+
+    /// Waits for as long as `condition` holds.
+    pub fn wait_while<'mutex, T>(
+        &self,
+        mut guard: MutexGuard<'mutex, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'mutex, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.lock().wake_one().is_some()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.lock().wake_all()
+    }
+}
+
+impl core::fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "Condvar({} waiting)",
+            self.waiters.lock().len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA, utils::sync::Mutex};
+
+    static READY: Mutex<bool> = Mutex::new("TEST_READY", false);
+    static READY_CHANGED: Condvar = Condvar::new();
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn ready_waiter() {
+        let ready = READY.lock();
+        let _ready = READY_CHANGED.wait_while(ready, |ready| !*ready);
+        WOKEN.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn ready_notifier() {
+        // Make sure everyone's waiting already
+        for _ in 0..3 {
+            proc::yield_self();
+        }
+        *READY.lock() = true;
+        READY_CHANGED.notify_all();
+    }
+
+    #[test_case]
+    pub fn condvar_notify_all() {
+        for _ in 0..3 {
+            PROCESSES.create(ready_waiter);
+        }
+        PROCESSES.create(ready_notifier);
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(WOKEN.load(Ordering::Acquire), 3, "every waiter should wake");
+    }
+}
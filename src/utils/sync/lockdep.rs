@@ -0,0 +1,547 @@
+//! Lock dependency validator, after Linux's lockdep. Every lock tag is
+//! a lock class; acquiring class `B` while holding class `A` records
+//! the dependency `A -> B`. Observing `B -> A` later (directly or
+//! through a chain) means two harts could deadlock, even if they never
+//! actually did. Only enabled with `debug_assertions`.
+//!
+//! Spinlocks are held by a hart, sleeping locks by a process, across
+//! context switches: a lock taken depends on both.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    hint::spin_loop,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::{self, MAX_HARTS},
+    irq, println,
+    proc::{self, MAX_PROCESSES, PID},
+    utils::{
+        cells::{IrqCell, PerCpu},
+        collections::ArrayVec,
+    },
+};
+
+const MAX_CLASSES: usize = 128;
+const MAX_EDGES: usize = 256;
+const MAX_HELD: usize = 16;
+const MAX_CHAIN: usize = 8;
+
+type Class = usize;
+type Site = &'static Location<'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Acquire {
+    /// May wait for the lock, so it must respect the lock order.
+    Blocking,
+    /// Can't deadlock, only tracked as held.
+    Try,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    tag: &'static str,
+    site: Site,
+}
+
+type Chain = ArrayVec<Held, MAX_CHAIN>;
+type HeldStack = ArrayVec<Held, MAX_HELD>;
+
+struct ClassInfo {
+    tag: &'static str,
+    /// First acquisition inside a trap handler.
+    in_irq: Option<Site>,
+    /// First acquisition with interrupts enabled, by a lock that doesn't disable them.
+    irqs_on: Option<Site>,
+    irq_reported: bool,
+}
+
+struct Edge {
+    from: Class,
+    to: Class,
+    /// Held locks when the dependency was first seen, ending with `to`.
+    chain: Chain,
+}
+
+struct Graph {
+    classes: ArrayVec<ClassInfo, MAX_CLASSES>,
+    /// Bit `j` of `successors[i]` is set if edge `i -> j` exists.
+    successors: [u128; MAX_CLASSES],
+    /// Bit `j` of `reported[i]` is set if `i -> j` was reported as an inversion.
+    reported: [u128; MAX_CLASSES],
+    edges: ArrayVec<Edge, MAX_EDGES>,
+    /// Sleeping locks held by each process, then by the kernel outside
+    /// of any.
+    sleeping: [HeldStack; MAX_PROCESSES + 1],
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Self {
+            classes: ArrayVec::new(),
+            successors: [0; MAX_CLASSES],
+            reported: [0; MAX_CLASSES],
+            edges: ArrayVec::new(),
+            sleeping: [const { ArrayVec::new() }; MAX_PROCESSES + 1],
+        }
+    }
+
+    fn class_of(&mut self, tag: &'static str) -> Option<Class> {
+        if let Some(class) = self.classes.iter().position(|info| info.tag == tag) {
+            return Some(class);
+        }
+        if self.classes.len() == MAX_CLASSES {
+            return None;
+        }
+        self.classes.push(ClassInfo {
+            tag,
+            in_irq: None,
+            irqs_on: None,
+            irq_reported: false,
+        });
+        Some(self.classes.len() - 1)
+    }
+
+    /// Shortest path `from ->* to`, as classes in reverse order (`to` first).
+    fn path(&self, from: Class, to: Class) -> Option<ArrayVec<Class, MAX_CLASSES>> {
+        // Classes fit in a byte; keep the search small on process stacks.
+        let mut parents = [0u8; MAX_CLASSES];
+        let mut queue = ArrayVec::<u8, MAX_CLASSES>::new();
+        let mut visited = 1u128 << from;
+        queue.push(from as u8);
+        let mut next = 0;
+        while next < queue.len() {
+            let class = queue[next] as Class;
+            next += 1;
+            if class == to {
+                let mut path = ArrayVec::new();
+                let mut class = to;
+                while class != from {
+                    path.push(class);
+                    class = parents[class] as Class;
+                }
+                path.push(from);
+                return Some(path);
+            }
+            for successor in 0..self.classes.len() {
+                if self.successors[class] & (1 << successor) != 0 && visited & (1 << successor) == 0
+                {
+                    visited |= 1 << successor;
+                    parents[successor] = class as u8;
+                    queue.push(successor as u8);
+                }
+            }
+        }
+        None
+    }
+
+    fn edge(&self, from: Class, to: Class) -> Option<&Edge> {
+        self.edges
+            .iter()
+            .find(|edge| edge.from == from && edge.to == to)
+    }
+}
+
+struct GraphCell {
+    locked: AtomicBool,
+    graph: UnsafeCell<Graph>,
+}
+
+// SAFETY: `graph` is only accessed with `locked` held
+unsafe impl Sync for GraphCell {}
+
+impl GraphCell {
+    /// Can't use our own locks here, or we'd be validating ourselves.
+    fn with<R>(&self, f: impl FnOnce(&mut Graph) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let result = f(unsafe { &mut *self.graph.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+struct HartState {
+    /// Set while inside lockdep, so the locks it uses (e.g. for
+    /// printing) don't recurse into it.
+    busy: Cell<bool>,
+    held: IrqCell<HeldStack>,
+}
+
+static GRAPH: GraphCell = GraphCell {
+    locked: AtomicBool::new(false),
+    graph: UnsafeCell::new(Graph::new()),
+};
+static HARTS: PerCpu<HartState> = PerCpu::new(
+    [const {
+        HartState {
+            busy: Cell::new(false),
+            held: IrqCell::new(ArrayVec::new()),
+        }
+    }; MAX_HARTS],
+);
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+static INVERSIONS: AtomicUsize = AtomicUsize::new(0);
+static IRQ_UNSAFE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of lock order inversions reported so far.
+pub fn inversions_reported() -> usize {
+    INVERSIONS.load(Ordering::Relaxed)
+}
+
+/// Number of lock classes reported as used unsafely with interrupts.
+pub fn irq_unsafe_reported() -> usize {
+    IRQ_UNSAFE.load(Ordering::Relaxed)
+}
+
+fn turn_off(reason: &str) {
+    ENABLED.store(false, Ordering::Relaxed);
+    println!("lockdep: {}, turning off", reason);
+}
+
+/// Runs `f` on this hart's held spinlocks and the graph, unless lockdep
+/// is off or already running. Also passes whose sleeping locks apply,
+/// as an index into [`Graph::sleeping`]: the running process's, or the
+/// kernel's. Not in traps, which don't run on behalf of the process
+/// they interrupted: theirs are kept with the spinlocks.
+fn with_held(f: impl FnOnce(&mut HeldStack, &mut Graph, Option<usize>)) {
+    if !is_enabled() {
+        return;
+    }
+    HARTS.with(|hart| {
+        if !hart.busy.replace(true) {
+            // Locks taken from here on skip lockdep
+            let owner = match cpu::current().in_trap() {
+                true => None,
+                false => Some(proc::try_current_pid().map_or(MAX_PROCESSES, |pid| pid as usize)),
+            };
+            hart.held
+                .with(|held| GRAPH.with(|graph| f(held, graph, owner)));
+            hart.busy.set(false);
+        }
+    });
+}
+
+/// Records the acquisition of spinlock `tag`. Blocking acquisitions
+/// should call this before waiting, so inversions are reported before
+/// they hang. `irq_safe` locks are the ones that disable interrupts
+/// while held.
+pub(super) fn acquire(tag: &'static str, site: Site, how: Acquire, irq_safe: bool) {
+    let irqs_on = irq::is_enabled() && !irq_safe;
+    record_acquire(tag, site, how, irqs_on, false);
+}
+
+/// [`acquire`], for a lock that may be held across context switches.
+pub(super) fn acquire_sleeping(tag: &'static str, site: Site, how: Acquire) {
+    record_acquire(tag, site, how, irq::is_enabled(), true);
+}
+
+fn record_acquire(tag: &'static str, site: Site, how: Acquire, irqs_on: bool, sleeping: bool) {
+    let in_irq = cpu::current().in_trap();
+    with_held(|held, graph, owner| {
+        let new = Held { tag, site };
+        let Some(class) = graph.class_of(tag) else {
+            return turn_off("lock class table full");
+        };
+        check_irq_usage(graph, class, site, in_irq, irqs_on);
+        // Sleeping locks come first, as nothing sleeps holding a spinlock
+        let mut all = ArrayVec::<Held, { 2 * MAX_HELD }>::new();
+        let sleeping_held = owner.map_or(&[][..], |owner| &graph.sleeping[owner][..]);
+        for lock in sleeping_held.iter().chain(held.iter()) {
+            all.push(*lock);
+        }
+        if how == Acquire::Blocking {
+            for &previous in all.iter() {
+                add_dependency(graph, &all, previous, new);
+            }
+        }
+        let stack = match (sleeping, owner) {
+            (true, Some(owner)) => &mut graph.sleeping[owner],
+            _ => held,
+        };
+        if stack.len() == MAX_HELD {
+            return turn_off("held lock stack overflow");
+        }
+        stack.push(new);
+    });
+}
+
+fn remove_last(held: &mut HeldStack, tag: &'static str) {
+    // Locks aren't necessarily released in order
+    if let Some(index) = held.iter().rposition(|held| held.tag == tag) {
+        held.remove(index);
+    }
+}
+
+pub(super) fn release(tag: &'static str) {
+    with_held(|held, _, _| remove_last(held, tag));
+}
+
+/// [`release`], for a lock taken with [`acquire_sleeping`].
+pub(super) fn release_sleeping(tag: &'static str) {
+    with_held(|held, graph, owner| match owner {
+        Some(owner) => remove_last(&mut graph.sleeping[owner], tag),
+        None => remove_last(held, tag),
+    });
+}
+
+/// Forgets the sleeping locks recorded for `pid`, e.g. if it exited
+/// holding some, before the PID is reused.
+pub fn forget_process(pid: PID) {
+    // The graph's lock doesn't keep interrupts out by itself
+    irq::push_off();
+    GRAPH.with(|graph| graph.sleeping[pid as usize] = ArrayVec::new());
+    irq::pop_off();
+}
+
+fn check_irq_usage(graph: &mut Graph, class: Class, site: Site, in_irq: bool, irqs_on: bool) {
+    let info = &mut graph.classes[class];
+    if in_irq && info.in_irq.is_none() {
+        info.in_irq = Some(site);
+    }
+    if irqs_on && info.irqs_on.is_none() {
+        info.irqs_on = Some(site);
+    }
+    if let (Some(in_irq), Some(irqs_on), false) = (info.in_irq, info.irqs_on, info.irq_reported) {
+        info.irq_reported = true;
+        IRQ_UNSAFE.fetch_add(1, Ordering::Relaxed);
+        println!("==================================================");
+        println!("lockdep: inconsistent irq usage of lock #{}", info.tag);
+        println!("taken in interrupt context at:");
+        println!("  {}", in_irq);
+        println!("but also taken with interrupts enabled at:");
+        println!("  {}", irqs_on);
+        println!("an interrupt arriving while it's held will deadlock");
+        println!("==================================================");
+    }
+}
+
+fn add_dependency(graph: &mut Graph, held: &[Held], previous: Held, new: Held) {
+    let (Some(from), Some(to)) = (graph.class_of(previous.tag), graph.class_of(new.tag)) else {
+        return turn_off("lock class table full");
+    };
+    // Instances of one class may nest (e.g. two processes' states)
+    if from == to || graph.successors[from] & (1 << to) != 0 {
+        return;
+    }
+    if let Some(path) = graph.path(to, from) {
+        // Leave the edge out, so the graph stays acyclic
+        if graph.reported[from] & (1 << to) == 0 {
+            graph.reported[from] |= 1 << to;
+            INVERSIONS.fetch_add(1, Ordering::Relaxed);
+            report_inversion(graph, held, new, &path);
+        }
+        return;
+    }
+    if graph.edges.len() == MAX_EDGES {
+        return turn_off("lock dependency table full");
+    }
+    let mut chain = Chain::new();
+    let skip = (held.len() + 1).saturating_sub(MAX_CHAIN);
+    for held in held.iter().skip(skip) {
+        chain.push(*held);
+    }
+    chain.push(new);
+    graph.successors[from] |= 1 << to;
+    graph.edges.push(Edge { from, to, chain });
+}
+
+fn print_chain(chain: &[Held]) {
+    for (i, held) in chain.iter().enumerate() {
+        println!("  #{} {} at {}", i, held.tag, held.site);
+    }
+}
+
+/// `path` goes backwards from the held class to the new one.
+fn report_inversion(graph: &Graph, held: &[Held], new: Held, path: &[Class]) {
+    println!("==================================================");
+    println!("lockdep: possible circular locking dependency");
+    println!("acquiring #{} at {} while holding:", new.tag, new.site);
+    print_chain(held);
+    println!("inverts the existing dependency chain:");
+    for pair in path.windows(2).rev() {
+        let (from, to) = (pair[1], pair[0]);
+        println!(
+            "#{} -> #{}, first seen as:",
+            graph.classes[from].tag, graph.classes[to].tag
+        );
+        if let Some(edge) = graph.edge(from, to) {
+            print_chain(&edge.chain);
+        }
+    }
+    println!("==================================================");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sync::{Mutex, RWCell, SpinLock};
+
+    #[test_case]
+    pub fn lockdep_abba() {
+        if !is_enabled() {
+            return;
+        }
+        let a = SpinLock::new("TEST_LOCKDEP_A", ());
+        let b = SpinLock::new("TEST_LOCKDEP_B", ());
+        let reported = inversions_reported();
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        assert_eq!(inversions_reported(), reported, "A -> B alone is fine");
+
+        {
+            let _b = b.lock();
+            let _a = a.lock();
+        }
+        assert_eq!(inversions_reported(), reported + 1, "B -> A inverts A -> B");
+
+        {
+            let _b = b.lock();
+            let _a = a.lock();
+        }
+        assert_eq!(
+            inversions_reported(),
+            reported + 1,
+            "an inversion should only be reported once"
+        );
+    }
+
+    #[test_case]
+    pub fn lockdep_transitive_inversion() {
+        if !is_enabled() {
+            return;
+        }
+        let a = SpinLock::new("TEST_LOCKDEP_CHAIN_A", ());
+        let b = SpinLock::new("TEST_LOCKDEP_CHAIN_B", ());
+        let c = SpinLock::new("TEST_LOCKDEP_CHAIN_C", ());
+        let reported = inversions_reported();
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        {
+            let _b = b.lock();
+            let _c = c.lock();
+        }
+        {
+            let _c = c.lock();
+            let _a = a.lock();
+        }
+        assert_eq!(
+            inversions_reported(),
+            reported + 1,
+            "C -> A inverts A -> B -> C"
+        );
+    }
+
+    #[test_case]
+    pub fn lockdep_orders_against_every_held_lock() {
+        if !is_enabled() {
+            return;
+        }
+        let a = SpinLock::new("TEST_LOCKDEP_ALL_A", ());
+        let b = SpinLock::new("TEST_LOCKDEP_ALL_B", ());
+        let c = SpinLock::new("TEST_LOCKDEP_ALL_C", ());
+        let reported = inversions_reported();
+
+        {
+            let _a = a.lock();
+            // Leaves no A -> B to go through
+            let _b = b.try_lock().expect("B should be free");
+            let _c = c.lock();
+        }
+        {
+            let _c = c.lock();
+            let _a = a.lock();
+        }
+        assert_eq!(
+            inversions_reported(),
+            reported + 1,
+            "C -> A inverts A -> C, though B was taken in between"
+        );
+    }
+
+    #[test_case]
+    pub fn lockdep_mutex_classes() {
+        if !is_enabled() {
+            return;
+        }
+        let a = Mutex::new("TEST_LOCKDEP_MUTEX_A", ());
+        let b = Mutex::new("TEST_LOCKDEP_MUTEX_B", ());
+        let reported = inversions_reported();
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        assert_eq!(inversions_reported(), reported);
+        {
+            let _b = b.lock();
+            let _a = a.lock();
+        }
+        assert_eq!(
+            inversions_reported(),
+            reported + 1,
+            "each mutex should be a class of its own"
+        );
+    }
+
+    #[test_case]
+    pub fn lockdep_try_lock_ignores_order() {
+        if !is_enabled() {
+            return;
+        }
+        let a = SpinLock::new("TEST_LOCKDEP_TRY_A", ());
+        let b = SpinLock::new("TEST_LOCKDEP_TRY_B", ());
+        let reported = inversions_reported();
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        {
+            let _b = b.lock();
+            let _a = a.try_lock().expect("A should be free");
+        }
+        assert_eq!(inversions_reported(), reported, "try-locks can't deadlock");
+    }
+
+    #[test_case]
+    pub fn lockdep_irq_unsafe() {
+        if !is_enabled() {
+            return;
+        }
+        let cell = RWCell::new("TEST_LOCKDEP_IRQ", ());
+        let reported = irq_unsafe_reported();
+
+        irq::enable();
+        drop(cell.read());
+        irq::disable();
+        assert_eq!(irq_unsafe_reported(), reported);
+
+        let cpu = cpu::current();
+        cpu.enter_trap();
+        drop(cell.read());
+        cpu.leave_trap();
+        assert_eq!(
+            irq_unsafe_reported(),
+            reported + 1,
+            "a lock taken both in a trap and with interrupts on should be reported"
+        );
+    }
+}
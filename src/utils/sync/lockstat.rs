@@ -0,0 +1,275 @@
+//! Per-tag lock contention and hold time statistics, in `mcycle`
+//! cycles. Only recorded with the `lock-stat` feature; otherwise the
+//! hooks compile down to nothing.
+
+#[cfg(feature = "lock-stat")]
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(feature = "lock-stat")]
+use crate::{cpu, irq, println, utils::collections::ArrayVec};
+
+#[cfg(feature = "lock-stat")]
+const MAX_CLASSES: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockStat {
+    pub tag: &'static str,
+    pub acquisitions: u64,
+    pub contentions: u64,
+    pub wait_total: u64,
+    pub wait_max: u64,
+    pub hold_total: u64,
+    pub hold_max: u64,
+}
+
+#[cfg(feature = "lock-stat")]
+struct Table {
+    locked: AtomicBool,
+    stats: UnsafeCell<ArrayVec<LockStat, MAX_CLASSES>>,
+}
+
+// SAFETY: `stats` is only accessed with `locked` held
+#[cfg(feature = "lock-stat")]
+unsafe impl Sync for Table {}
+
+#[cfg(feature = "lock-stat")]
+impl Table {
+    /// Can't use our own locks here, or we'd be measuring ourselves.
+    fn with<R>(&self, f: impl FnOnce(&mut ArrayVec<LockStat, MAX_CLASSES>) -> R) -> R {
+        irq::push_off();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let result = f(unsafe { &mut *self.stats.get() });
+        self.locked.store(false, Ordering::Release);
+        irq::pop_off();
+        result
+    }
+
+    fn update(&self, tag: &'static str, f: impl FnOnce(&mut LockStat)) {
+        self.with(|stats| {
+            let index = match stats.iter().position(|stat| stat.tag == tag) {
+                Some(index) => index,
+                // Classes past the table's capacity just go unrecorded
+                None if stats.len() == MAX_CLASSES => return,
+                None => {
+                    stats.push(LockStat {
+                        tag,
+                        ..Default::default()
+                    });
+                    stats.len() - 1
+                }
+            };
+            f(&mut stats[index]);
+        })
+    }
+}
+
+#[cfg(feature = "lock-stat")]
+static TABLE: Table = Table {
+    locked: AtomicBool::new(false),
+    stats: UnsafeCell::new(ArrayVec::new()),
+};
+
+/// Started right before trying to acquire a lock.
+pub(super) struct Wait {
+    #[cfg(feature = "lock-stat")]
+    since: u64,
+}
+
+impl Wait {
+    #[inline]
+    pub(super) fn start() -> Self {
+        Self {
+            #[cfg(feature = "lock-stat")]
+            since: cpu::cycles(),
+        }
+    }
+
+    /// Records an acquisition, and starts timing how long it's held.
+    #[inline]
+    pub(super) fn acquired(self, tag: &'static str, contended: bool) -> Hold {
+        #[cfg(feature = "lock-stat")]
+        {
+            let now = cpu::cycles();
+            let waited = now.wrapping_sub(self.since);
+            TABLE.update(tag, |stat| {
+                stat.acquisitions += 1;
+                if contended {
+                    stat.contentions += 1;
+                    stat.wait_total += waited;
+                    stat.wait_max = stat.wait_max.max(waited);
+                }
+            });
+            Hold { tag, since: now }
+        }
+        #[cfg(not(feature = "lock-stat"))]
+        {
+            let _ = (tag, contended);
+            Hold {}
+        }
+    }
+}
+
+/// Kept in lock guards, for the hold time.
+pub(super) struct Hold {
+    #[cfg(feature = "lock-stat")]
+    tag: &'static str,
+    #[cfg(feature = "lock-stat")]
+    since: u64,
+}
+
+impl Hold {
+    /// Uncontended acquisition, e.g. from a `try_*`.
+    #[inline]
+    pub(super) fn start(tag: &'static str) -> Self {
+        Wait::start().acquired(tag, false)
+    }
+
+    #[inline]
+    pub(super) fn release(&self) {
+        #[cfg(feature = "lock-stat")]
+        {
+            let held = cpu::cycles().wrapping_sub(self.since);
+            TABLE.update(self.tag, |stat| {
+                stat.hold_total += held;
+                stat.hold_max = stat.hold_max.max(held);
+            });
+        }
+    }
+}
+
+/// Copies out the statistics recorded so far. Always empty without
+/// the `lock-stat` feature.
+pub fn snapshot(f: impl FnOnce(&[LockStat])) {
+    #[cfg(feature = "lock-stat")]
+    {
+        let mut copy = ArrayVec::<LockStat, MAX_CLASSES>::new();
+        TABLE.with(|stats| {
+            for stat in stats.iter() {
+                copy.push(*stat);
+            }
+        });
+        f(&copy)
+    }
+    #[cfg(not(feature = "lock-stat"))]
+    f(&[])
+}
+
+pub fn reset() {
+    #[cfg(feature = "lock-stat")]
+    TABLE.with(|stats| while stats.pop().is_some() {});
+}
+
+/// Prints the statistics in the spirit of Linux's `/proc/lock_stat`,
+/// most contended first.
+pub fn report() {
+    #[cfg(feature = "lock-stat")]
+    snapshot(|stats| {
+        let mut sorted = ArrayVec::<LockStat, MAX_CLASSES>::new();
+        for stat in stats {
+            sorted.push(*stat);
+        }
+        sorted.sort_unstable_by(|a, b| {
+            b.contentions
+                .cmp(&a.contentions)
+                .then(b.acquisitions.cmp(&a.acquisitions))
+        });
+
+        println!("lock_stat version 0.4 (times in cycles)");
+        println!("{:-<130}", "");
+        println!(
+            "{:>24} {:>13} {:>14} {:>14} {:>14} {:>13} {:>14} {:>14} {:>14}",
+            "class name",
+            "contentions",
+            "waittime-max",
+            "waittime-total",
+            "waittime-avg",
+            "acquisitions",
+            "holdtime-max",
+            "holdtime-total",
+            "holdtime-avg"
+        );
+        println!("{:-<130}", "");
+        for stat in sorted.iter() {
+            println!(
+                "{:>23}: {:>13} {:>14} {:>14} {:>14} {:>13} {:>14} {:>14} {:>14}",
+                stat.tag,
+                stat.contentions,
+                stat.wait_max,
+                stat.wait_total,
+                stat.wait_total.checked_div(stat.contentions).unwrap_or(0),
+                stat.acquisitions,
+                stat.hold_max,
+                stat.hold_total,
+                stat.hold_total.checked_div(stat.acquisitions).unwrap_or(0),
+            );
+        }
+        println!("{:-<130}", "");
+    });
+}
+
+#[cfg(all(test, feature = "lock-stat"))]
+mod tests {
+    use super::*;
+    use crate::{
+        proc::{self, PROCESSES},
+        test::TEST_QUANTA,
+        utils::sync::{Mutex, SpinLock},
+    };
+
+    fn stat(tag: &'static str) -> LockStat {
+        let mut found = LockStat::default();
+        snapshot(|stats| {
+            if let Some(stat) = stats.iter().find(|stat| stat.tag == tag) {
+                found = *stat;
+            }
+        });
+        found
+    }
+
+    #[test_case]
+    pub fn lockstat_counts_acquisitions() {
+        let lock = SpinLock::new("TEST_LOCKSTAT", ());
+        for _ in 0..3 {
+            drop(lock.lock());
+        }
+        drop(lock.try_lock());
+
+        let stat = stat("TEST_LOCKSTAT");
+        assert_eq!(stat.acquisitions, 4);
+        assert_eq!(stat.contentions, 0, "nothing else was holding it");
+        assert!(stat.hold_max <= stat.hold_total);
+    }
+
+    static CONTENDED: Mutex<()> = Mutex::new("TEST_LOCKSTAT_MUTEX", ());
+
+    fn hold_contended() {
+        let _guard = CONTENDED.lock();
+        proc::yield_self();
+    }
+
+    #[test_case]
+    pub fn lockstat_counts_mutex_contention() {
+        for _ in 0..2 {
+            PROCESSES.create(hold_contended);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+
+        let mutex = stat("TEST_LOCKSTAT_MUTEX");
+        assert_eq!(mutex.acquisitions, 2);
+        assert_eq!(mutex.contentions, 1, "the second process had to sleep");
+        assert!(
+            stat("MUTEX_STATE").acquisitions > 0,
+            "the inner lock should have its own tag"
+        );
+    }
+}
@@ -0,0 +1,882 @@
+use core::{
+    any::type_name,
+    cell::UnsafeCell,
+    fmt::Debug,
+    hint::spin_loop,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{proc, timer::Instant};
+
+mod condvar;
+pub mod lockdep;
+pub mod lockstat;
+mod mutex;
+pub mod rcu;
+mod semaphore;
+mod seqlock;
+mod spin;
+mod wait;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use seqlock::{SeqLock, SeqLockWriter};
+pub use spin::{SpinLock, SpinLockGuard, SpinRwLock, SpinRwLockReader, SpinRwLockWriter};
+pub use wait::WaitQueue;
+
+const BACKOFF_MAX_STEP: u32 = 10;
+
+/// Exponential backoff for spin loops, so contending harts
+/// don't hammer the same cache line.
+struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    fn spin(&mut self) {
+        for _ in 0..1 << self.step {
+            spin_loop();
+        }
+        if self.step < BACKOFF_MAX_STEP {
+            self.step += 1;
+        }
+    }
+}
+
+/// Primitive for tracking read-write locks. Doesn't
+/// actually protect anything.
+pub struct RWLock {
+    tag: &'static str,
+    state: AtomicUsize,
+    /// Writers waiting for the lock; fair readers hold off while non-zero.
+    writers_waiting: AtomicUsize,
+    /// Whether one of the readers may upgrade to a writer.
+    upgradable: AtomicBool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryLockError {
+    HasWriter,
+    HasReaders(usize),
+    HasUpgradableReader,
+}
+
+pub type TryLockResult<T> = Result<T, TryLockError>;
+
+pub const RWLOCK_MAX_READERS: usize = RWLOCK_WRITER_ONLY - 1;
+const RWLOCK_FREE: usize = 0;
+const RWLOCK_WRITER_ONLY: usize = usize::MAX;
+
+impl RWLock {
+    pub const fn new(tag: &'static str) -> Self {
+        Self {
+            tag,
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            upgradable: AtomicBool::new(false),
+        }
+    }
+
+    pub fn try_read(&self) -> TryLockResult<(&Self, usize)> {
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            if state == RWLOCK_WRITER_ONLY {
+                return Err(TryLockError::HasWriter);
+            }
+            if state == RWLOCK_MAX_READERS {
+                return Err(TryLockError::HasReaders(state));
+            }
+            if self
+                .state
+                .compare_exchange(state, state + 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok((self, state + 1));
+            }
+        }
+    }
+
+    pub fn release_read(&self) {
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            if state == RWLOCK_WRITER_ONLY {
+                panic!("Tried to release a reader while a writer exists");
+            }
+            if state == 0 {
+                panic!("Tried to release a reader while none exists");
+            }
+            if self
+                .state
+                .compare_exchange(state, state - 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> TryLockResult<&Self> {
+        match self.state.compare_exchange(
+            RWLOCK_FREE,
+            RWLOCK_WRITER_ONLY,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Ok(self),
+            Err(state) if state == RWLOCK_WRITER_ONLY => Err(TryLockError::HasWriter),
+            Err(readers) => Err(TryLockError::HasReaders(readers)),
+        }
+    }
+
+    pub fn release_write(&self) {
+        match self.state.compare_exchange(
+            RWLOCK_WRITER_ONLY,
+            RWLOCK_FREE,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {}
+            Err(state) if state == 0 => panic!("Tried to release a writer while none exists"),
+            Err(readers) => panic!("Tried to release a writer while {} readers exists", readers),
+        }
+    }
+
+    /// Like [`RWLock::try_read`], but yields to waiting writers.
+    pub fn try_read_fair(&self) -> TryLockResult<(&Self, usize)> {
+        if self.writers_waiting() > 0 {
+            return Err(TryLockError::HasWriter);
+        }
+        self.try_read()
+    }
+
+    /// A reader that can later be upgraded into a writer. There can
+    /// be at most one, alongside any number of plain readers.
+    pub fn try_upgradable_read(&self) -> TryLockResult<&Self> {
+        if self.writers_waiting() > 0 {
+            return Err(TryLockError::HasWriter);
+        }
+        if self.upgradable.swap(true, Ordering::Acquire) {
+            return Err(TryLockError::HasUpgradableReader);
+        }
+        match self.try_read() {
+            Ok(_) => Ok(self),
+            Err(error) => {
+                self.upgradable.store(false, Ordering::Release);
+                Err(error)
+            }
+        }
+    }
+
+    pub fn release_upgradable_read(&self) {
+        self.upgradable.store(false, Ordering::Release);
+        self.release_read();
+    }
+
+    /// Turns the upgradable reader into the writer, once it's the only reader left.
+    pub fn try_upgrade(&self) -> TryLockResult<&Self> {
+        assert!(
+            self.upgradable.load(Ordering::Acquire),
+            "Tried to upgrade without an upgradable reader"
+        );
+        match self.state.compare_exchange(
+            1,
+            RWLOCK_WRITER_ONLY,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                self.upgradable.store(false, Ordering::Release);
+                Ok(self)
+            }
+            Err(readers) => Err(TryLockError::HasReaders(readers)),
+        }
+    }
+
+    pub fn writers_waiting(&self) -> usize {
+        self.writers_waiting.load(Ordering::Acquire)
+    }
+
+    /// Announces a writer waiting for the lock, holding off fair readers.
+    pub fn begin_write_wait(&self) {
+        self.writers_waiting.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn end_write_wait(&self) {
+        self.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+}
+
+impl Debug for RWLock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("RWLock(#")?;
+        f.write_str(self.tag())?;
+        f.write_str(":")?;
+        match self.state.load(Ordering::Acquire) {
+            0 => f.write_str("*"),
+            RWLOCK_WRITER_ONLY => f.write_str("W"),
+            readers => f.write_fmt(format_args!("{}R", readers)),
+        }?;
+        f.write_str(")")
+    }
+}
+
+/// Multithreaded version of RefCell. Blocking accessors sleep
+/// through the scheduler when called from a process, or spin otherwise;
+/// waiting writers take precedence over new readers.
+pub struct RWCell<T> {
+    data: UnsafeCell<T>,
+    lock: RWLock,
+    waiters: SpinLock<WaitQueue>,
+    /// Processes queued (or about to be) in `waiters`.
+    sleepers: AtomicUsize,
+}
+
+impl<T> RWCell<T> {
+    pub const fn new(tag: &'static str, data: T) -> Self {
+        Self {
+            data: UnsafeCell::new(data),
+            lock: RWLock::new(tag),
+            waiters: SpinLock::new("RWCELL_WAITERS", WaitQueue::new()),
+            sleepers: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.lock.tag()
+    }
+
+    fn reader(&self, hold: lockstat::Hold) -> RWCellReader<'_, T> {
+        RWCellReader { cell: self, hold }
+    }
+
+    fn writer(&self, hold: lockstat::Hold) -> RWCellWriter<'_, T> {
+        RWCellWriter { cell: self, hold }
+    }
+
+    /// Retries `attempt` until it succeeds.
+    fn wait_for<R>(&self, mut attempt: impl FnMut() -> TryLockResult<R>) -> (R, bool) {
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        loop {
+            if let Ok(result) = attempt() {
+                return (result, contended);
+            }
+            contended = true;
+            let Some(me) = proc::blockable_pid() else {
+                backoff.spin();
+                continue;
+            };
+            let mut waiters = self.waiters.lock();
+            // Pairs with `wake_sleepers`: either they see us, or we see their release
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            if let Ok(result) = attempt() {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return (result, contended);
+            }
+            waiters.push(me);
+            proc::block(waiters);
+            self.waiters.lock().remove(me);
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Retries `attempt` until it succeeds or `timeout` elapses. Processes
+    /// yield between attempts instead of sleeping, to notice the deadline.
+    fn wait_for_timeout<R>(
+        &self,
+        timeout: Duration,
+        mut attempt: impl FnMut() -> TryLockResult<R>,
+    ) -> TryLockResult<(R, bool)> {
+        let deadline = Instant::now().saturating_add(timeout);
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        loop {
+            match attempt() {
+                Ok(result) => return Ok((result, contended)),
+                Err(error) if Instant::now() >= deadline => return Err(error),
+                Err(_) => contended = true,
+            }
+            match proc::blockable_pid() {
+                Some(_) => proc::yield_self(),
+                None => backoff.spin(),
+            }
+        }
+    }
+
+    fn wake_sleepers(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            self.waiters.lock().wake_all();
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read_with_readers(&self) -> TryLockResult<(RWCellReader<'_, T>, usize)> {
+        let site = Location::caller();
+        self.lock.try_read().map(|(_, readers)| {
+            lockdep::acquire(self.tag(), site, lockdep::Acquire::Try, false);
+            (self.reader(lockstat::Hold::start(self.tag())), readers)
+        })
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> TryLockResult<RWCellReader<'_, T>> {
+        self.try_read_with_readers().map(|(guard, _)| guard)
+    }
+
+    /// Waits for any writer, including ones queued after us, to finish.
+    #[track_caller]
+    pub fn read(&self) -> RWCellReader<'_, T> {
+        lockdep::acquire(
+            self.tag(),
+            Location::caller(),
+            lockdep::Acquire::Blocking,
+            false,
+        );
+        let wait = lockstat::Wait::start();
+        let (_, contended) = self.wait_for(|| self.lock.try_read_fair());
+        self.reader(wait.acquired(self.tag(), contended))
+    }
+
+    #[track_caller]
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<RWCellReader<'_, T>> {
+        lockdep::acquire(
+            self.tag(),
+            Location::caller(),
+            lockdep::Acquire::Blocking,
+            false,
+        );
+        let wait = lockstat::Wait::start();
+        match self.wait_for_timeout(timeout, || self.lock.try_read_fair()) {
+            Ok((_, contended)) => Ok(self.reader(wait.acquired(self.tag(), contended))),
+            Err(error) => {
+                lockdep::release(self.tag());
+                Err(error)
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn get(&self) -> T
+    where
+        T: Copy,
+    {
+        *self.read()
+    }
+
+    #[track_caller]
+    pub fn get_ptr(&self) -> *const T {
+        self.read().as_ptr()
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> TryLockResult<RWCellWriter<'_, T>> {
+        let site = Location::caller();
+        self.lock.try_write().map(|_| {
+            lockdep::acquire(self.tag(), site, lockdep::Acquire::Try, false);
+            self.writer(lockstat::Hold::start(self.tag()))
+        })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RWCellWriter<'_, T> {
+        lockdep::acquire(
+            self.tag(),
+            Location::caller(),
+            lockdep::Acquire::Blocking,
+            false,
+        );
+        let wait = lockstat::Wait::start();
+        self.lock.begin_write_wait();
+        let (_, contended) = self.wait_for(|| self.lock.try_write());
+        self.lock.end_write_wait();
+        self.writer(wait.acquired(self.tag(), contended))
+    }
+
+    #[track_caller]
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<RWCellWriter<'_, T>> {
+        lockdep::acquire(
+            self.tag(),
+            Location::caller(),
+            lockdep::Acquire::Blocking,
+            false,
+        );
+        let wait = lockstat::Wait::start();
+        self.lock.begin_write_wait();
+        let result = self.wait_for_timeout(timeout, || self.lock.try_write());
+        self.lock.end_write_wait();
+        match result {
+            Ok((_, contended)) => Ok(self.writer(wait.acquired(self.tag(), contended))),
+            Err(error) => {
+                // Readers may have been holding off for us
+                self.wake_sleepers();
+                lockdep::release(self.tag());
+                Err(error)
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn set(&self, new_data: T) {
+        *self.write() = new_data;
+    }
+
+    #[track_caller]
+    pub fn get_mut_ptr(&self) -> *mut T {
+        self.write().as_ptr()
+    }
+
+    #[track_caller]
+    pub fn try_upgradable_read(&self) -> TryLockResult<RWCellUpgradableReader<'_, T>> {
+        let site = Location::caller();
+        self.lock.try_upgradable_read().map(|_| {
+            lockdep::acquire(self.tag(), site, lockdep::Acquire::Try, false);
+            RWCellUpgradableReader {
+                cell: self,
+                hold: lockstat::Hold::start(self.tag()),
+            }
+        })
+    }
+
+    /// A reader that can be atomically turned into a writer with
+    /// [`RWCellUpgradableReader::upgrade`], without letting another
+    /// writer in between. Only one may exist at a time.
+    #[track_caller]
+    pub fn upgradable_read(&self) -> RWCellUpgradableReader<'_, T> {
+        lockdep::acquire(
+            self.tag(),
+            Location::caller(),
+            lockdep::Acquire::Blocking,
+            false,
+        );
+        let wait = lockstat::Wait::start();
+        let (_, contended) = self.wait_for(|| self.lock.try_upgradable_read());
+        RWCellUpgradableReader {
+            cell: self,
+            hold: wait.acquired(self.tag(), contended),
+        }
+    }
+}
+
+impl<T: Debug> Debug for RWCell<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("RWCell(#")?;
+        f.write_str(self.tag())?;
+        f.write_str(", ")?;
+        match self.try_read_with_readers() {
+            Ok((guard, 1)) => f.write_fmt(format_args!("*, {:?}", *guard)),
+            Ok((guard, readers)) => f.write_fmt(format_args!("{}R, {:?}", readers, *guard)),
+            Err(TryLockError::HasWriter) => f.write_str("W, .."),
+            Err(TryLockError::HasReaders(_)) => f.write_str("<MAX>R, .."),
+            Err(TryLockError::HasUpgradableReader) => f.write_str("U, .."),
+        }?;
+        f.write_str(")")
+    }
+}
+
+unsafe impl<T> Sync for RWCell<T> {}
+
+pub struct RWCellReader<'rwcell, T> {
+    cell: &'rwcell RWCell<T>,
+    hold: lockstat::Hold,
+}
+
+impl<T> RWCellReader<'_, T> {
+    pub fn tag(&self) -> &'static str {
+        self.cell.tag()
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.cell.data.get()
+    }
+}
+
+impl<T: Debug> Debug for RWCellReader<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.as_ref()
+        ))
+    }
+}
+
+impl<T> AsRef<T> for RWCellReader<'_, T> {
+    fn as_ref(&self) -> &T {
+        // SAFETY: no writer should exist while a reader does
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> Deref for RWCellReader<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+impl<T> Drop for RWCellReader<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.cell.lock.release_read();
+        lockdep::release(self.tag());
+        self.cell.wake_sleepers();
+    }
+}
+
+pub struct RWCellUpgradableReader<'rwcell, T> {
+    cell: &'rwcell RWCell<T>,
+    hold: lockstat::Hold,
+}
+
+impl<'rwcell, T> RWCellUpgradableReader<'rwcell, T> {
+    pub fn tag(&self) -> &'static str {
+        self.cell.tag()
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.cell.data.get()
+    }
+
+    /// Waits for the other readers to leave, then becomes the writer.
+    pub fn upgrade(self) -> RWCellWriter<'rwcell, T> {
+        let this = ManuallyDrop::new(self);
+        let cell = this.cell;
+        // SAFETY: `this` is never used nor dropped again
+        let hold = unsafe { core::ptr::read(&this.hold) };
+        cell.lock.begin_write_wait();
+        cell.wait_for(|| cell.lock.try_upgrade());
+        cell.lock.end_write_wait();
+        cell.writer(hold)
+    }
+
+    pub fn try_upgrade(self) -> Result<RWCellWriter<'rwcell, T>, Self> {
+        match self.cell.lock.try_upgrade() {
+            Ok(_) => {
+                let this = ManuallyDrop::new(self);
+                // SAFETY: `this` is never used nor dropped again
+                let hold = unsafe { core::ptr::read(&this.hold) };
+                Ok(this.cell.writer(hold))
+            }
+            Err(_) => Err(self),
+        }
+    }
+}
+
+impl<T: Debug> Debug for RWCellUpgradableReader<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.as_ref()
+        ))
+    }
+}
+
+impl<T> AsRef<T> for RWCellUpgradableReader<'_, T> {
+    fn as_ref(&self) -> &T {
+        // SAFETY: no writer should exist while a reader does
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> Deref for RWCellUpgradableReader<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+impl<T> Drop for RWCellUpgradableReader<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.cell.lock.release_upgradable_read();
+        lockdep::release(self.tag());
+        self.cell.wake_sleepers();
+    }
+}
+
+pub struct RWCellWriter<'rwcell, T> {
+    cell: &'rwcell RWCell<T>,
+    hold: lockstat::Hold,
+}
+
+impl<T> RWCellWriter<'_, T> {
+    pub fn tag(&self) -> &'static str {
+        self.cell.tag()
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.cell.data.get()
+    }
+}
+
+impl<T: Debug> Debug for RWCellWriter<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.as_ref()
+        ))
+    }
+}
+
+impl<T> AsRef<T> for RWCellWriter<'_, T> {
+    fn as_ref(&self) -> &T {
+        // SAFETY: no other lock can exist while a writer does
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> AsMut<T> for RWCellWriter<'_, T> {
+    fn as_mut(&mut self) -> &mut T {
+        // SAFETY: no other lock can exist while a writer does
+        unsafe { &mut *self.as_ptr() }
+    }
+}
+
+impl<T> Deref for RWCellWriter<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+impl<T> DerefMut for RWCellWriter<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut()
+    }
+}
+
+impl<T> Drop for RWCellWriter<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.cell.lock.release_write();
+        lockdep::release(self.tag());
+        self.cell.wake_sleepers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA, utils::collections::ArrayVec};
+
+    #[test_case]
+    pub fn rwlock_reader() {
+        let rwlock = RWLock::new("DUMMY");
+        const READER_COUNT: usize = 10;
+
+        for i in 0..READER_COUNT {
+            let (_, readers) = rwlock.try_read().expect("reader should be acquirable");
+            assert_eq!(readers, i + 1);
+            let writer_error = rwlock
+                .try_write()
+                .expect_err("acquiring a writer shouldn't be possible while readers exists");
+            assert_eq!(writer_error, TryLockError::HasReaders(i + 1));
+        }
+        for _ in 0..READER_COUNT {
+            rwlock.release_read();
+        }
+
+        let (_, readers) = rwlock
+            .try_read()
+            .expect("reader should be acquirable again");
+        assert_eq!(
+            readers, 1,
+            "only 1 reader should be left (the one we just created)"
+        );
+        rwlock.release_read();
+
+        let _ = rwlock
+            .try_write()
+            .expect("writer should be acquirable since releasing the last reader");
+    }
+
+    #[test_case]
+    pub fn rwlock_max_readers() {
+        //! originally, this was part of [`rwlock_reader`],
+        //! but creating [`RWLOCK_MAX_READERS`] readers takes
+        //! a LONG while, so we're hacking it by directly changing
+        //! the state.
+        let rwlock = RWLock::new("DUMMY");
+        rwlock.state.store(RWLOCK_MAX_READERS, Ordering::Release);
+
+        let error = rwlock
+            .try_read()
+            .expect_err("reader shouldn't be acquirable when at max count");
+        assert_eq!(error, TryLockError::HasReaders(RWLOCK_MAX_READERS));
+    }
+
+    #[test_case]
+    pub fn rwlock_writer() {
+        let rwlock = RWLock::new("DUMMY");
+
+        let _ = rwlock.try_write().expect("writer should be acquirable");
+        let writer_error = rwlock
+            .try_write()
+            .expect_err("acquiring two writers shouldn't be possible");
+        assert_eq!(writer_error, TryLockError::HasWriter);
+        let reader_error = rwlock
+            .try_read()
+            .expect_err("acquiring a reader while a writer exists shouldn't be possible");
+        assert_eq!(reader_error, TryLockError::HasWriter);
+
+        let _ = rwlock.release_write();
+        let _ = rwlock
+            .try_write()
+            .expect("writer should be acquirable when last was freed");
+
+        let _ = rwlock.release_write();
+        let (_, readers) = rwlock
+            .try_read()
+            .expect("reader should be acquirable since writer was freed");
+        assert_eq!(readers, 1, "only 1 reader should have been allocated");
+    }
+
+    #[test_case]
+    pub fn rwcell_guard_tracking() {
+        let rwcell = RWCell::new("DUMMY", "DUMMY");
+
+        {
+            let _reader_0 = rwcell.try_read().expect("1 reader should be acquirable");
+            let _reader_1 = rwcell.try_read().expect("2 readers should be acquirable");
+            let (_reader_2, count) = rwcell
+                .try_read_with_readers()
+                .expect("3 readers should be acquirable");
+
+            assert_eq!(count, 3, "3 readers should exist at this point");
+
+            let error = rwcell
+                .try_write()
+                .expect_err("a reader shouldn't be acquirable while readers exist");
+            assert_eq!(error, TryLockError::HasReaders(3));
+        }
+
+        {
+            let _writer = rwcell
+                .try_write()
+                .expect("1 writer should be acquirable since other locks've been freed");
+
+            let error = rwcell.try_write().expect_err("2 writers shouldn't exist");
+            assert_eq!(error, TryLockError::HasWriter);
+            let error = rwcell
+                .try_read()
+                .expect_err("a reader shouldn't be acquirable while a writer does");
+            assert_eq!(error, TryLockError::HasWriter);
+        }
+
+        {
+            let _reader_0 = rwcell
+                .try_read()
+                .expect("1 reader should be acquirable since writer's been freed");
+        }
+    }
+
+    static PREFERENCE_CELL: RWCell<()> = RWCell::new("TEST_PREFERENCE", ());
+    static ORDER: SpinLock<ArrayVec<&str, 3>> = SpinLock::new("TEST_ORDER", ArrayVec::new());
+
+    fn early_reader() {
+        let _reader = PREFERENCE_CELL.read();
+        ORDER.lock().push("early reader");
+        // Let the writer, then the late reader, queue up
+        for _ in 0..3 {
+            proc::yield_self();
+        }
+    }
+
+    fn writer() {
+        let _writer = PREFERENCE_CELL.write();
+        ORDER.lock().push("writer");
+    }
+
+    fn late_reader() {
+        proc::yield_self();
+        let _reader = PREFERENCE_CELL.read();
+        ORDER.lock().push("late reader");
+    }
+
+    #[test_case]
+    pub fn rwcell_prefers_writers() {
+        PROCESSES.create(early_reader);
+        PROCESSES.create(writer);
+        PROCESSES.create(late_reader);
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(
+            ORDER.lock().as_slice(),
+            &["early reader", "writer", "late reader"],
+            "a waiting writer should go before readers arriving after it"
+        );
+    }
+
+    #[test_case]
+    pub fn rwcell_upgradable_read() {
+        let rwcell = RWCell::new("DUMMY", 0);
+
+        let upgradable = rwcell.upgradable_read();
+        {
+            let _reader = rwcell
+                .try_read()
+                .expect("readers should coexist with an upgradable reader");
+            let error = rwcell
+                .try_upgradable_read()
+                .expect_err("2 upgradable readers shouldn't exist");
+            assert_eq!(error, TryLockError::HasUpgradableReader);
+            let error = rwcell
+                .try_write()
+                .expect_err("a writer shouldn't be acquirable while readers exist");
+            assert_eq!(error, TryLockError::HasReaders(2));
+        }
+
+        let mut writer = upgradable.upgrade();
+        *writer = 1;
+        let error = rwcell
+            .try_read()
+            .expect_err("a reader shouldn't be acquirable after upgrading");
+        assert_eq!(error, TryLockError::HasWriter);
+        drop(writer);
+
+        assert_eq!(rwcell.get(), 1);
+        let _upgradable = rwcell
+            .try_upgradable_read()
+            .expect("upgradable reader should be acquirable after upgrading");
+    }
+
+    #[test_case]
+    pub fn rwcell_timeout() {
+        let rwcell = RWCell::new("DUMMY", ());
+        let timeout = Duration::from_micros(100);
+
+        let _reader = rwcell.read();
+        let start = Instant::now();
+        let error = rwcell
+            .try_write_for(timeout)
+            .expect_err("writer shouldn't be acquirable while a reader exists");
+        assert_eq!(error, TryLockError::HasReaders(1));
+        assert!(
+            start.elapsed() >= timeout,
+            "should have waited for the whole timeout"
+        );
+        rwcell
+            .try_read_for(timeout)
+            .expect("readers shouldn't wait for a writer that gave up");
+    }
+}
@@ -0,0 +1,260 @@
+use core::{
+    any::type_name,
+    cell::UnsafeCell,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    panic::Location,
+};
+
+use crate::proc::{self, PID};
+
+use super::{
+    SpinLock, TryLockError, TryLockResult, WaitQueue,
+    lockdep::{self, Acquire},
+    lockstat::{Hold, Wait},
+};
+
+struct MutexState {
+    locked: bool,
+    /// [`None`] while unlocked or when held outside of a process.
+    owner: Option<PID>,
+    waiters: WaitQueue,
+}
+
+/// Mutual exclusion lock that puts contending processes to sleep
+/// instead of spinning, for long critical sections. Ownership is
+/// handed to waiters in the order they arrived.
+///
+/// For lockdep, each tag is a class of its own, held by the process
+/// rather than the hart; the inner [`SpinLock`] is a leaf shared by all.
+pub struct Mutex<T> {
+    tag: &'static str,
+    state: SpinLock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(tag: &'static str, data: T) -> Self {
+        Self {
+            tag,
+            state: SpinLock::new(
+                "MUTEX_STATE",
+                MutexState {
+                    locked: false,
+                    owner: None,
+                    waiters: WaitQueue::new(),
+                },
+            ),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    /// Is the lock held by the current process?
+    pub fn holding(&self) -> bool {
+        let state = self.state.lock();
+        state.locked && state.owner.is_some() && state.owner == proc::try_current_pid()
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return Err(TryLockError::HasWriter);
+        }
+        state.locked = true;
+        state.owner = proc::try_current_pid();
+        drop(state);
+        lockdep::acquire_sleeping(self.tag, Location::caller(), Acquire::Try);
+        Ok(MutexGuard {
+            mutex: self,
+            hold: Hold::start(self.tag),
+        })
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        lockdep::acquire_sleeping(self.tag, Location::caller(), Acquire::Blocking);
+        let wait = Wait::start();
+        let me = proc::try_current_pid();
+        let mut state = self.state.lock();
+        if !state.locked {
+            state.locked = true;
+            state.owner = me;
+            return MutexGuard {
+                mutex: self,
+                hold: wait.acquired(self.tag, false),
+            };
+        }
+
+        let me = me.unwrap_or_else(|| panic!("contended mutex #{} outside a process", self.tag));
+        if state.owner == Some(me) {
+            panic!("recursive acquisition of mutex #{} by PID {}", self.tag, me);
+        }
+        state.waiters.push(me);
+        loop {
+            proc::block(state);
+            state = self.state.lock();
+            // Only `unlock` dequeues us, after handing us the lock
+            if !state.waiters.contains(me) {
+                break;
+            }
+        }
+        debug_assert_eq!(state.owner, Some(me));
+        MutexGuard {
+            mutex: self,
+            hold: wait.acquired(self.tag, true),
+        }
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.wake_one() {
+            Some(pid) => state.owner = Some(pid),
+            None => {
+                state.locked = false;
+                state.owner = None;
+            }
+        }
+    }
+}
+
+impl<T: Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = self.state.lock();
+        f.write_str("Mutex(#")?;
+        f.write_str(self.tag())?;
+        f.write_str(", ")?;
+        match (state.locked, state.owner) {
+            (false, _) => f.write_str("*"),
+            (true, Some(owner)) => f.write_fmt(format_args!("PID {}", owner)),
+            (true, None) => f.write_str("kernel"),
+        }?;
+        f.write_fmt(format_args!(", {} waiting)", state.waiters.len()))
+    }
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'mutex, T> {
+    mutex: &'mutex Mutex<T>,
+    hold: Hold,
+}
+
+impl<'mutex, T> MutexGuard<'mutex, T> {
+    pub fn tag(&self) -> &'static str {
+        self.mutex.tag()
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.mutex.data.get()
+    }
+
+    pub(super) fn mutex(&self) -> &'mutex Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T: Debug> Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.deref()
+        ))
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.as_ptr() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        lockdep::release_sleeping(self.mutex.tag);
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA, utils::collections::ArrayVec};
+
+    const INCREMENTS: usize = 5;
+    static COUNTER: Mutex<usize> = Mutex::new("TEST_COUNTER", 0);
+
+    fn increment_racily() {
+        for _ in 0..INCREMENTS {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            proc::yield_self();
+            *counter = value + 1;
+        }
+    }
+
+    #[test_case]
+    pub fn mutex_exclusion() {
+        for _ in 0..3 {
+            PROCESSES.create(increment_racily);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(
+            *COUNTER.lock(),
+            3 * INCREMENTS,
+            "no increment should be lost"
+        );
+    }
+
+    static FIFO_MUTEX: Mutex<()> = Mutex::new("TEST_FIFO", ());
+    static ACQUISITIONS: SpinLock<ArrayVec<PID, 4>> =
+        SpinLock::new("TEST_ACQUISITIONS", ArrayVec::new());
+
+    fn fifo_holder() {
+        let _guard = FIFO_MUTEX.lock();
+        ACQUISITIONS.lock().push(proc::current_pid());
+        // Give every waiter a chance to queue up
+        for _ in 0..3 {
+            proc::yield_self();
+        }
+    }
+
+    fn fifo_waiter() {
+        let _guard = FIFO_MUTEX.lock();
+        ACQUISITIONS.lock().push(proc::current_pid());
+    }
+
+    #[test_case]
+    pub fn mutex_fifo_handoff() {
+        let expected = [
+            PROCESSES.create(fifo_holder),
+            PROCESSES.create(fifo_waiter),
+            PROCESSES.create(fifo_waiter),
+            PROCESSES.create(fifo_waiter),
+        ];
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(
+            ACQUISITIONS.lock().as_slice(),
+            &expected,
+            "waiters should get the lock in arrival order"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Read-copy-update, for pointer-published data that's read far more
+//! often than updated. Readers run inside [`rcu_read_lock`] sections,
+//! which keep interrupts (and thus preemption) off. So once every hart
+//! has either context switched or left its read section after an
+//! update, no reader can still see the old version: that's a grace
+//! period. [`synchronize_rcu`] waits for one, while [`call_rcu`] defers
+//! a callback (e.g. returning the old version to its pool) past one.
+
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{Backoff, SpinLock};
+use crate::{
+    cpu::{self, MAX_HARTS},
+    irq, proc,
+    utils::{cells::PerCpu, collections::ArrayVec},
+};
+
+const MAX_CALLBACKS: usize = 32;
+
+struct HartState {
+    /// Depth of nested read sections.
+    nesting: AtomicUsize,
+    /// Last grace period started before this hart's latest quiescent state.
+    quiescent: AtomicU64,
+}
+
+static HARTS: PerCpu<HartState> = PerCpu::new(
+    [const {
+        HartState {
+            nesting: AtomicUsize::new(0),
+            quiescent: AtomicU64::new(0),
+        }
+    }; MAX_HARTS],
+);
+/// Last grace period started.
+static STARTED: AtomicU64 = AtomicU64::new(0);
+/// Last grace period known to have elapsed.
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+/// Grace period started by [`poll`] and not yet elapsed, or 0.
+static POLLING: AtomicU64 = AtomicU64::new(0);
+
+struct Callback {
+    /// Last grace period started when queued; it must not count.
+    after: u64,
+    callback: fn(usize),
+    arg: usize,
+}
+
+static CALLBACKS: SpinLock<ArrayVec<Callback, MAX_CALLBACKS>> =
+    SpinLock::new("RCU_CALLBACKS", ArrayVec::new());
+
+/// Enters a read section, which lasts until the guard is dropped.
+/// Sections nest, and mustn't sleep or yield.
+pub fn rcu_read_lock() -> RcuReadGuard {
+    irq::push_off();
+    HARTS.current().nesting.fetch_add(1, Ordering::SeqCst);
+    RcuReadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+pub struct RcuReadGuard {
+    /// Read sections belong to the hart that entered them.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        if HARTS.current().nesting.fetch_sub(1, Ordering::SeqCst) == 1 {
+            quiescent_state();
+        }
+        irq::pop_off();
+    }
+}
+
+/// Reports that this hart holds no references to RCU-protected data.
+/// Called by [`proc`] on every context switch.
+pub(crate) fn quiescent_state() {
+    let hart = HARTS.current();
+    assert_eq!(
+        hart.nesting.load(Ordering::SeqCst),
+        0,
+        "context switch inside an RCU read section"
+    );
+    hart.quiescent
+        .store(STARTED.load(Ordering::SeqCst), Ordering::SeqCst);
+}
+
+fn start_grace_period() -> u64 {
+    STARTED.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Has every other hart been quiescent since grace period `target`
+/// started? Ours is, since we aren't in a read section.
+fn has_elapsed(target: u64) -> bool {
+    let me = cpu::id();
+    HARTS.iter().enumerate().all(|(hart, state)| {
+        hart == me
+            || state.nesting.load(Ordering::SeqCst) == 0
+            || state.quiescent.load(Ordering::SeqCst) >= target
+    })
+}
+
+fn complete_grace_period(target: u64) {
+    COMPLETED.fetch_max(target, Ordering::SeqCst);
+}
+
+/// Waits until every reader that may have seen data unpublished
+/// before the call is done with it. Yields when called from a process.
+pub fn synchronize_rcu() {
+    assert_eq!(
+        HARTS.current().nesting.load(Ordering::SeqCst),
+        0,
+        "synchronize_rcu inside an RCU read section"
+    );
+    let target = start_grace_period();
+    let mut backoff = Backoff::new();
+    while !has_elapsed(target) {
+        match proc::blockable_pid() {
+            Some(_) => proc::yield_self(),
+            None => backoff.spin(),
+        }
+    }
+    complete_grace_period(target);
+    run_callbacks();
+}
+
+/// Runs `callback(arg)` once a grace period has elapsed, from
+/// whoever notices it first: [`synchronize_rcu`] or the scheduler.
+pub fn call_rcu(callback: fn(usize), arg: usize) {
+    let entry = Callback {
+        after: STARTED.load(Ordering::SeqCst),
+        callback,
+        arg,
+    };
+    let mut callbacks = CALLBACKS.lock();
+    if callbacks.len() < MAX_CALLBACKS {
+        callbacks.push(entry);
+        return;
+    }
+    drop(callbacks);
+    // Nowhere to defer it to, so wait it out ourselves
+    synchronize_rcu();
+    callback(arg);
+}
+
+/// Advances grace periods without waiting, running the callbacks
+/// that are due. Called by the scheduler between processes.
+pub fn poll() {
+    if CALLBACKS.lock().is_empty() {
+        return;
+    }
+    let mut target = POLLING.load(Ordering::SeqCst);
+    if target == 0 {
+        target = start_grace_period();
+        POLLING.store(target, Ordering::SeqCst);
+    }
+    if has_elapsed(target) {
+        complete_grace_period(target);
+        let _ = POLLING.compare_exchange(target, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    run_callbacks();
+}
+
+fn run_callbacks() {
+    let completed = COMPLETED.load(Ordering::SeqCst);
+    let mut ready = ArrayVec::<Callback, MAX_CALLBACKS>::new();
+    {
+        let mut callbacks = CALLBACKS.lock();
+        let mut index = 0;
+        while index < callbacks.len() {
+            if callbacks[index].after < completed {
+                ready.push(callbacks.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+    }
+    // Outside the lock, since callbacks may queue more
+    for entry in ready.iter() {
+        (entry.callback)(entry.arg);
+    }
+}
+
+/// Pointer to RCU-protected data. There's no allocator, so versions
+/// are `'static`s (e.g. from a pool), which may only be reused once
+/// a grace period has elapsed since they were replaced.
+pub struct RcuPtr<T: 'static> {
+    ptr: AtomicPtr<T>,
+    _data: PhantomData<&'static T>,
+}
+
+impl<T: 'static> RcuPtr<T> {
+    pub const fn new(initial: &'static T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(initial as *const T as *mut T),
+            _data: PhantomData,
+        }
+    }
+
+    pub fn read<'guard>(&self, _guard: &'guard RcuReadGuard) -> &'guard T {
+        // SAFETY: versions outlive any read section they're visible to
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Publishes `new`, returning the previous version. Readers may
+    /// keep using that until the next grace period elapses.
+    pub fn replace(&self, new: &'static T) -> &'static T {
+        let old = self.ptr.swap(new as *const T as *mut T, Ordering::AcqRel);
+        // SAFETY: only ever set from `&'static T`s
+        unsafe { &*old }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_call(times: usize) {
+        CALLS.fetch_add(times, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    pub fn rcu_read_lock_nests() {
+        irq::enable();
+        {
+            let _outer = rcu_read_lock();
+            assert!(!irq::is_enabled(), "read sections shouldn't be preemptible");
+            {
+                let _inner = rcu_read_lock();
+            }
+            assert!(!irq::is_enabled(), "outer read section should still hold");
+        }
+        assert!(irq::is_enabled(), "interrupts should be restored");
+        irq::disable();
+    }
+
+    #[test_case]
+    pub fn rcu_ptr_replace() {
+        static OLD: usize = 1;
+        static NEW: usize = 2;
+        let ptr = RcuPtr::new(&OLD);
+
+        let guard = rcu_read_lock();
+        let seen = ptr.read(&guard);
+        assert_eq!(*ptr.replace(&NEW), 1);
+        assert_eq!(*seen, 1, "readers keep the version they saw");
+        assert_eq!(*ptr.read(&guard), 2, "later reads see the new version");
+    }
+
+    #[test_case]
+    pub fn synchronize_rcu_runs_callbacks() {
+        let before = CALLS.load(Ordering::SeqCst);
+        call_rcu(count_call, 1);
+        assert_eq!(
+            CALLS.load(Ordering::SeqCst),
+            before,
+            "callbacks should wait for a grace period"
+        );
+        synchronize_rcu();
+        assert_eq!(CALLS.load(Ordering::SeqCst), before + 1);
+    }
+
+    fn defer_and_yield() {
+        let before = CALLS.load(Ordering::SeqCst);
+        call_rcu(count_call, 10);
+        // Each trip through the scheduler is a quiescent state
+        for _ in 0..2 {
+            proc::yield_self();
+        }
+        assert_eq!(
+            CALLS.load(Ordering::SeqCst),
+            before + 10,
+            "the scheduler should have run the callback"
+        );
+    }
+
+    #[test_case]
+    pub fn scheduler_runs_rcu_callbacks() {
+        PROCESSES.create(defer_and_yield);
+        proc::run_until_exit(TEST_QUANTA);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::proc;
+
+use super::{SpinLock, WaitQueue, lockstat::Wait};
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitQueue,
+}
+
+/// Counting semaphore. Processes that find no permits left sleep
+/// until one is released, in FIFO order.
+pub struct Semaphore {
+    tag: &'static str,
+    state: SpinLock<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(tag: &'static str, permits: usize) -> Self {
+        Self {
+            tag,
+            state: SpinLock::new(
+                "SEMAPHORE_STATE",
+                SemaphoreState {
+                    permits,
+                    waiters: WaitQueue::new(),
+                },
+            ),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    pub fn permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        // Don't jump ahead of sleeping processes
+        if state.permits == 0 || !state.waiters.is_empty() {
+            return false;
+        }
+        state.permits -= 1;
+        true
+    }
+
+    /// Only contention is recorded for lock-stat: permits aren't held
+    /// by anyone in particular, so there's no hold time to pair up.
+    pub fn acquire(&self) {
+        let wait = Wait::start();
+        let mut state = self.state.lock();
+        if state.permits > 0 && state.waiters.is_empty() {
+            state.permits -= 1;
+            drop(state);
+            wait.acquired(self.tag, false);
+            return;
+        }
+
+        let me = proc::current_pid();
+        state.waiters.push(me);
+        loop {
+            proc::block(state);
+            state = self.state.lock();
+            // Only `release` dequeues us, after handing us its permit
+            if !state.waiters.contains(me) {
+                break;
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        if state.waiters.wake_one().is_none() {
+            state.permits += 1;
+        }
+    }
+}
+
+impl core::fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = self.state.lock();
+        f.write_fmt(format_args!(
+            "Semaphore(#{}, {} permits, {} waiting)",
+            self.tag,
+            state.permits,
+            state.waiters.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA};
+
+    const MAX_CONCURRENT: usize = 2;
+    static SLOTS: Semaphore = Semaphore::new("TEST_SLOTS", MAX_CONCURRENT);
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn bounded_worker() {
+        SLOTS.acquire();
+        let inside = INSIDE.fetch_add(1, Ordering::AcqRel) + 1;
+        MAX_INSIDE.fetch_max(inside, Ordering::AcqRel);
+        for _ in 0..3 {
+            proc::yield_self();
+        }
+        INSIDE.fetch_sub(1, Ordering::AcqRel);
+        SLOTS.release();
+        DONE.fetch_add(1, Ordering::AcqRel);
+    }
+
+    #[test_case]
+    pub fn semaphore_bounds_concurrency() {
+        for _ in 0..5 {
+            PROCESSES.create(bounded_worker);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(
+            DONE.load(Ordering::Acquire),
+            5,
+            "every worker should finish"
+        );
+        assert_eq!(
+            MAX_INSIDE.load(Ordering::Acquire),
+            MAX_CONCURRENT,
+            "no more than {} workers should hold a permit at once",
+            MAX_CONCURRENT
+        );
+        assert_eq!(
+            SLOTS.permits(),
+            MAX_CONCURRENT,
+            "every permit should be back"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Debug,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering, fence},
+};
+
+use super::{SpinLock, SpinLockGuard};
+
+/// Sequence lock, for small `Copy` data that's read far more often
+/// than written (e.g. timekeeping). Readers never block writers: they
+/// copy the data out optimistically, and retry if a writer ran meanwhile.
+/// Writers are serialized by a [`SpinLock`], so they also keep
+/// interrupts off and can't be interrupted by a reader on their own hart.
+pub struct SeqLock<T: Copy> {
+    /// Odd while a write is in progress.
+    sequence: AtomicUsize,
+    writer: SpinLock<()>,
+    data: UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(tag: &'static str, data: T) -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            writer: SpinLock::new(tag, ()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.writer.tag()
+    }
+
+    /// Starts a read, waiting out any write in progress. Pass the
+    /// result to [`SeqLock::read_retry`] once done with the data.
+    pub fn read_begin(&self) -> usize {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence & 1 == 0 {
+                return sequence;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Did a writer run since [`SeqLock::read_begin`] returned `sequence`?
+    pub fn read_retry(&self, sequence: usize) -> bool {
+        fence(Ordering::Acquire);
+        self.sequence.load(Ordering::Relaxed) != sequence
+    }
+
+    /// Copies out a consistent snapshot of the data.
+    pub fn read(&self) -> T {
+        loop {
+            let sequence = self.read_begin();
+            // SAFETY: may race with a writer, in which case the copy is
+            // thrown away below; `T: Copy` means there's nothing to drop
+            let data = unsafe { ptr::read_volatile(self.data.get()) };
+            if !self.read_retry(sequence) {
+                return data;
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> SeqLockWriter<'_, T> {
+        let guard = self.writer.lock();
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        SeqLockWriter {
+            seqlock: self,
+            _guard: guard,
+        }
+    }
+
+    #[track_caller]
+    pub fn set(&self, data: T) {
+        *self.write() = data;
+    }
+}
+
+impl<T: Copy + Debug> Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("SeqLock(#{}, {:?})", self.tag(), self.read()))
+    }
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+pub struct SeqLockWriter<'seqlock, T: Copy> {
+    seqlock: &'seqlock SeqLock<T>,
+    _guard: SpinLockGuard<'seqlock, ()>,
+}
+
+impl<T: Copy> Deref for SeqLockWriter<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: writers are serialized, and readers only copy
+        unsafe { &*self.seqlock.data.get() }
+    }
+}
+
+impl<T: Copy> DerefMut for SeqLockWriter<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: writers are serialized, and readers only copy
+        unsafe { &mut *self.seqlock.data.get() }
+    }
+}
+
+impl<T: Copy> Drop for SeqLockWriter<'_, T> {
+    fn drop(&mut self) {
+        // Runs before `_guard` is dropped, so still serialized
+        self.seqlock.sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irq;
+
+    #[test_case]
+    pub fn seqlock_read_write() {
+        let seqlock = SeqLock::new("TEST_SEQLOCK", (0u64, 0u64));
+        assert_eq!(seqlock.read(), (0, 0));
+
+        let sequence = seqlock.read_begin();
+        {
+            let mut writer = seqlock.write();
+            assert!(!irq::is_enabled(), "writers should disable interrupts");
+            writer.0 = 1;
+            writer.1 = 2;
+        }
+        assert!(
+            seqlock.read_retry(sequence),
+            "readers should notice a write during their read"
+        );
+
+        let sequence = seqlock.read_begin();
+        assert_eq!(seqlock.read(), (1, 2));
+        assert!(
+            !seqlock.read_retry(sequence),
+            "reads shouldn't invalidate reads"
+        );
+    }
+}
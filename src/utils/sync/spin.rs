@@ -0,0 +1,632 @@
+use core::{
+    any::type_name,
+    cell::UnsafeCell,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::{self, MAX_HARTS},
+    irq,
+};
+
+use super::{
+    Backoff, RWLock, TryLockError, TryLockResult,
+    lockdep::{self, Acquire},
+    lockstat::{Hold, Wait},
+};
+
+const NO_OWNER: usize = usize::MAX;
+
+/// Hart and call site of the current exclusive holder of a lock.
+struct Owner {
+    hart: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl Owner {
+    const fn new() -> Self {
+        Self {
+            hart: AtomicUsize::new(NO_OWNER),
+            site: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    fn is_current_hart(&self) -> bool {
+        self.hart.load(Ordering::Relaxed) == cpu::id()
+    }
+
+    fn set(&self, site: &'static Location<'static>) {
+        self.hart.store(cpu::id(), Ordering::Relaxed);
+        self.site.store(
+            site as *const Location<'static> as *mut Location<'static>,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn clear(&self) {
+        self.site.store(null_mut(), Ordering::Relaxed);
+        self.hart.store(NO_OWNER, Ordering::Relaxed);
+    }
+
+    fn hart(&self) -> Option<usize> {
+        match self.hart.load(Ordering::Relaxed) {
+            NO_OWNER => None,
+            hart => Some(hart),
+        }
+    }
+
+    fn site(&self) -> Option<&'static Location<'static>> {
+        // SAFETY: only ever set from a `&'static Location`
+        unsafe { self.site.load(Ordering::Relaxed).as_ref() }
+    }
+}
+
+/// Mutual exclusion lock that busy-waits. Interrupts are disabled
+/// on the current hart while the lock is held, so it is safe to
+/// share with trap handlers. Acquiring it twice from the same hart
+/// panics instead of deadlocking.
+pub struct SpinLock<T> {
+    tag: &'static str,
+    locked: AtomicBool,
+    owner: Owner,
+    data: UnsafeCell<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(tag: &'static str, data: T) -> Self {
+        Self {
+            tag,
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    /// Is the lock currently held by this hart?
+    pub fn holding(&self) -> bool {
+        self.locked.load(Ordering::Relaxed) && self.owner.is_current_hart()
+    }
+
+    pub fn owner_hart(&self) -> Option<usize> {
+        self.owner.hart()
+    }
+
+    pub fn owner_site(&self) -> Option<&'static Location<'static>> {
+        self.owner.site()
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<SpinLockGuard<'_, T>> {
+        irq::push_off();
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                self.owner.set(Location::caller());
+                lockdep::acquire(self.tag, Location::caller(), Acquire::Try, true);
+                Ok(SpinLockGuard {
+                    lock: self,
+                    hold: Hold::start(self.tag),
+                })
+            }
+            Err(_) => {
+                irq::pop_off();
+                Err(TryLockError::HasWriter)
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        irq::push_off();
+        if self.holding() {
+            panic!(
+                "recursive acquisition of spinlock #{} at {} (held on hart {} since {})",
+                self.tag,
+                Location::caller(),
+                cpu::id(),
+                DisplaySite(self.owner.site())
+            );
+        }
+        lockdep::acquire(self.tag, Location::caller(), Acquire::Blocking, true);
+        let wait = Wait::start();
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            contended = true;
+            while self.locked.load(Ordering::Relaxed) {
+                backoff.spin();
+            }
+        }
+        self.owner.set(Location::caller());
+        SpinLockGuard {
+            lock: self,
+            hold: wait.acquired(self.tag, contended),
+        }
+    }
+
+    #[track_caller]
+    pub fn get(&self) -> T
+    where
+        T: Copy,
+    {
+        *self.lock()
+    }
+
+    #[track_caller]
+    pub fn set(&self, new_data: T) {
+        *self.lock() = new_data;
+    }
+
+    #[track_caller]
+    pub fn get_ptr(&self) -> *const T {
+        self.lock().as_ptr()
+    }
+
+    #[track_caller]
+    pub fn get_mut_ptr(&self) -> *mut T {
+        self.lock().as_ptr()
+    }
+
+    fn unlock(&self) {
+        self.owner.clear();
+        self.locked.store(false, Ordering::Release);
+        lockdep::release(self.tag);
+        irq::pop_off();
+    }
+}
+
+impl<T: Debug> Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SpinLock(#")?;
+        f.write_str(self.tag())?;
+        f.write_str(", ")?;
+        if self.holding() {
+            return f.write_str("held by self, ..)");
+        }
+        match self.try_lock() {
+            Ok(guard) => f.write_fmt(format_args!("*, {:?}", *guard)),
+            Err(_) => f.write_fmt(format_args!(
+                "hart {:?} at {}, ..",
+                self.owner.hart(),
+                DisplaySite(self.owner.site())
+            )),
+        }?;
+        f.write_str(")")
+    }
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'lock, T> {
+    lock: &'lock SpinLock<T>,
+    hold: Hold,
+}
+
+impl<T> SpinLockGuard<'_, T> {
+    pub fn tag(&self) -> &'static str {
+        self.lock.tag()
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.lock.data.get()
+    }
+}
+
+impl<T: Debug> Debug for SpinLockGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.deref()
+        ))
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.as_ptr() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.lock.unlock();
+    }
+}
+
+/// Spinning counterpart of [`super::RWCell`]: same interface, but
+/// contention waits instead of panicking. Interrupts are disabled
+/// while any guard is alive.
+pub struct SpinRwLock<T> {
+    lock: RWLock,
+    writer: Owner,
+    /// Readers held by each hart, to catch read-then-write deadlocks.
+    readers: [AtomicU16; MAX_HARTS],
+    data: UnsafeCell<T>,
+}
+
+impl<T> SpinRwLock<T> {
+    pub const fn new(tag: &'static str, data: T) -> Self {
+        Self {
+            lock: RWLock::new(tag),
+            writer: Owner::new(),
+            readers: [const { AtomicU16::new(0) }; MAX_HARTS],
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.lock.tag()
+    }
+
+    pub fn writer_hart(&self) -> Option<usize> {
+        self.writer.hart()
+    }
+
+    pub fn writer_site(&self) -> Option<&'static Location<'static>> {
+        self.writer.site()
+    }
+
+    fn hart_readers(&self) -> &AtomicU16 {
+        &self.readers[cpu::id()]
+    }
+
+    #[track_caller]
+    fn check_recursion(&self, kind: &str) {
+        if self.writer.is_current_hart() {
+            panic!(
+                "recursive {} acquisition of rwlock #{} at {} (written on hart {} since {})",
+                kind,
+                self.tag(),
+                Location::caller(),
+                cpu::id(),
+                DisplaySite(self.writer.site())
+            );
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> TryLockResult<SpinRwLockReader<'_, T>> {
+        irq::push_off();
+        match self.lock.try_read() {
+            Ok(_) => {
+                self.hart_readers().fetch_add(1, Ordering::Relaxed);
+                lockdep::acquire(self.tag(), Location::caller(), Acquire::Try, true);
+                Ok(SpinRwLockReader {
+                    lock: self,
+                    hold: Hold::start(self.tag()),
+                })
+            }
+            Err(error) => {
+                irq::pop_off();
+                Err(error)
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> SpinRwLockReader<'_, T> {
+        irq::push_off();
+        self.check_recursion("read");
+        lockdep::acquire(self.tag(), Location::caller(), Acquire::Blocking, true);
+        let wait = Wait::start();
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        while self.lock.try_read().is_err() {
+            contended = true;
+            backoff.spin();
+        }
+        self.hart_readers().fetch_add(1, Ordering::Relaxed);
+        SpinRwLockReader {
+            lock: self,
+            hold: wait.acquired(self.tag(), contended),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> TryLockResult<SpinRwLockWriter<'_, T>> {
+        irq::push_off();
+        match self.lock.try_write() {
+            Ok(_) => {
+                self.writer.set(Location::caller());
+                lockdep::acquire(self.tag(), Location::caller(), Acquire::Try, true);
+                Ok(SpinRwLockWriter {
+                    lock: self,
+                    hold: Hold::start(self.tag()),
+                })
+            }
+            Err(error) => {
+                irq::pop_off();
+                Err(error)
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> SpinRwLockWriter<'_, T> {
+        irq::push_off();
+        self.check_recursion("write");
+        let readers = self.hart_readers().load(Ordering::Relaxed);
+        if readers > 0 {
+            panic!(
+                "write acquisition of rwlock #{} at {} while hart {} holds {} reader(s)",
+                self.tag(),
+                Location::caller(),
+                cpu::id(),
+                readers
+            );
+        }
+        lockdep::acquire(self.tag(), Location::caller(), Acquire::Blocking, true);
+        let wait = Wait::start();
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        while self.lock.try_write().is_err() {
+            contended = true;
+            backoff.spin();
+        }
+        self.writer.set(Location::caller());
+        SpinRwLockWriter {
+            lock: self,
+            hold: wait.acquired(self.tag(), contended),
+        }
+    }
+
+    #[track_caller]
+    pub fn get(&self) -> T
+    where
+        T: Copy,
+    {
+        *self.read()
+    }
+
+    #[track_caller]
+    pub fn set(&self, new_data: T) {
+        *self.write() = new_data;
+    }
+
+    #[track_caller]
+    pub fn get_ptr(&self) -> *const T {
+        self.read().as_ptr()
+    }
+
+    #[track_caller]
+    pub fn get_mut_ptr(&self) -> *mut T {
+        self.write().as_ptr()
+    }
+}
+
+impl<T: Debug> Debug for SpinRwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SpinRwLock(#")?;
+        f.write_str(self.tag())?;
+        f.write_str(", ")?;
+        match self.try_read() {
+            Ok(guard) => f.write_fmt(format_args!("{:?}", *guard)),
+            Err(TryLockError::HasWriter) => f.write_fmt(format_args!(
+                "W hart {:?} at {}, ..",
+                self.writer.hart(),
+                DisplaySite(self.writer.site())
+            )),
+            Err(TryLockError::HasReaders(_)) => f.write_str("<MAX>R, .."),
+            Err(TryLockError::HasUpgradableReader) => f.write_str("U, .."),
+        }?;
+        f.write_str(")")
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for SpinRwLock<T> {}
+unsafe impl<T: Send> Send for SpinRwLock<T> {}
+
+pub struct SpinRwLockReader<'lock, T> {
+    lock: &'lock SpinRwLock<T>,
+    hold: Hold,
+}
+
+impl<T> SpinRwLockReader<'_, T> {
+    pub fn tag(&self) -> &'static str {
+        self.lock.tag()
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.lock.data.get()
+    }
+}
+
+impl<T: Debug> Debug for SpinRwLockReader<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.deref()
+        ))
+    }
+}
+
+impl<T> Deref for SpinRwLockReader<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: no writer can exist while a reader does
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> Drop for SpinRwLockReader<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.lock.hart_readers().fetch_sub(1, Ordering::Relaxed);
+        self.lock.lock.release_read();
+        lockdep::release(self.tag());
+        irq::pop_off();
+    }
+}
+
+pub struct SpinRwLockWriter<'lock, T> {
+    lock: &'lock SpinRwLock<T>,
+    hold: Hold,
+}
+
+impl<T> SpinRwLockWriter<'_, T> {
+    pub fn tag(&self) -> &'static str {
+        self.lock.tag()
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.lock.data.get()
+    }
+}
+
+impl<T: Debug> Debug for SpinRwLockWriter<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}(#{}, {:?})",
+            type_name::<Self>(),
+            self.tag(),
+            self.deref()
+        ))
+    }
+}
+
+impl<T> Deref for SpinRwLockWriter<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: no other guard can exist while a writer does
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for SpinRwLockWriter<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: no other guard can exist while a writer does
+        unsafe { &mut *self.as_ptr() }
+    }
+}
+
+impl<T> Drop for SpinRwLockWriter<'_, T> {
+    fn drop(&mut self) {
+        self.hold.release();
+        self.lock.writer.clear();
+        self.lock.lock.release_write();
+        lockdep::release(self.tag());
+        irq::pop_off();
+    }
+}
+
+struct DisplaySite(Option<&'static Location<'static>>);
+
+impl core::fmt::Display for DisplaySite {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(site) => core::fmt::Display::fmt(site, f),
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    pub fn spinlock_exclusion() {
+        let lock = SpinLock::new("DUMMY", 0);
+
+        {
+            let mut guard = lock.try_lock().expect("lock should be acquirable");
+            *guard += 1;
+            assert!(lock.holding(), "lock should be held by this hart");
+            assert_eq!(lock.owner_hart(), Some(cpu::id()));
+            assert!(
+                lock.owner_site().is_some(),
+                "acquire site should be tracked"
+            );
+            let error = lock
+                .try_lock()
+                .expect_err("lock shouldn't be acquirable twice");
+            assert_eq!(error, TryLockError::HasWriter);
+        }
+
+        assert!(!lock.holding(), "lock should be released with its guard");
+        assert_eq!(lock.owner_hart(), None);
+        assert_eq!(lock.get(), 1);
+    }
+
+    #[test_case]
+    pub fn spinlock_disables_irqs() {
+        let lock = SpinLock::new("DUMMY", ());
+        irq::enable();
+
+        {
+            let _outer = lock.lock();
+            assert!(!irq::is_enabled(), "interrupts should be off while held");
+            let inner = SpinLock::new("DUMMY_INNER", ());
+            drop(inner.lock());
+            assert!(
+                !irq::is_enabled(),
+                "releasing a nested lock shouldn't re-enable interrupts"
+            );
+        }
+
+        assert!(
+            irq::is_enabled(),
+            "interrupts should be restored on release"
+        );
+        irq::disable();
+    }
+
+    #[test_case]
+    pub fn spinrwlock_readers_and_writer() {
+        let lock = SpinRwLock::new("DUMMY", 0);
+
+        {
+            let _reader_0 = lock.read();
+            let _reader_1 = lock.try_read().expect("readers should be shareable");
+            let error = lock
+                .try_write()
+                .expect_err("a writer shouldn't be acquirable while readers exist");
+            assert_eq!(error, TryLockError::HasReaders(2));
+        }
+
+        {
+            let mut writer = lock.write();
+            *writer = 42;
+            assert_eq!(lock.writer_hart(), Some(cpu::id()));
+            let error = lock
+                .try_read()
+                .expect_err("a reader shouldn't be acquirable while a writer exists");
+            assert_eq!(error, TryLockError::HasWriter);
+        }
+
+        assert_eq!(lock.writer_hart(), None);
+        assert_eq!(lock.get(), 42);
+    }
+}
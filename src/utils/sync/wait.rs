@@ -0,0 +1,58 @@
+use crate::{
+    proc::{self, MAX_PROCESSES, PID},
+    utils::collections::ArrayDeque,
+};
+
+/// FIFO of processes waiting for some event. Meant to live inside
+/// the [`super::SpinLock`] that guards the event, so that enqueueing
+/// and blocking (see [`proc::block`]) happen atomically.
+#[derive(Debug)]
+pub struct WaitQueue {
+    pids: ArrayDeque<PID, MAX_PROCESSES>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            pids: ArrayDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pids.is_empty()
+    }
+
+    pub fn contains(&self, pid: PID) -> bool {
+        self.pids.iter().any(|&waiter| waiter == pid)
+    }
+
+    pub fn push(&mut self, pid: PID) {
+        self.pids.push_back(pid);
+    }
+
+    /// Dequeues `pid` without waking it. Returns whether it was queued.
+    pub fn remove(&mut self, pid: PID) -> bool {
+        let index = self.pids.iter().position(|&waiter| waiter == pid);
+        index.is_some_and(|index| self.pids.remove(index).is_some())
+    }
+
+    /// Wakes the longest waiting process, returning its PID.
+    pub fn wake_one(&mut self) -> Option<PID> {
+        let pid = self.pids.pop_front()?;
+        proc::wake(pid);
+        Some(pid)
+    }
+
+    /// Wakes every waiting process, returning how many there were.
+    pub fn wake_all(&mut self) -> usize {
+        let mut count = 0;
+        while self.wake_one().is_some() {
+            count += 1;
+        }
+        count
+    }
+}
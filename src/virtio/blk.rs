@@ -0,0 +1,377 @@
+//! Virtio block device, e.g. a disk image given to QEMU with
+//!
+//! ```text
+//! -drive file=fs.img,if=none,format=raw,id=disk -device virtio-blk-device,drive=disk
+//! ```
+//!
+//! Requests are queued on the device, and their callers sleep until the
+//! completion interrupt (or poll, when they can't sleep).
+
+use core::hint::spin_loop;
+
+use super::{
+    DeviceId,
+    mmio::Transport,
+    queue::{Buffer, VirtQueue},
+};
+use crate::{
+    dev::{self, Device, DeviceClass, Driver, ProbeError},
+    fdt::Node,
+    info, irq,
+    proc::{self, PID},
+    utils::sync::{SpinLock, SpinLockGuard},
+};
+
+pub const SECTOR_SIZE: usize = 512;
+/// Unit the file system works in.
+pub const BLOCK_SIZE: usize = 4096;
+pub const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+/// Descriptors in the request queue: each request takes 2 or 3.
+const QUEUE_SIZE: usize = 8;
+
+// Feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Offset of the capacity, in sectors, in the configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// Request statuses
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkError {
+    NoDevice,
+    /// Past the end of the disk, or not a whole number of sectors.
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    Io,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Request the device may still be working on: what it reads and
+/// writes besides the data has to stay put meanwhile.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    header: RequestHeader,
+    status: u8,
+    busy: bool,
+}
+
+struct Disk {
+    transport: Option<Transport>,
+    queue: VirtQueue<QUEUE_SIZE>,
+    slots: [Slot; QUEUE_SIZE],
+    features: u64,
+    /// In sectors.
+    capacity: u64,
+}
+
+static DISK: SpinLock<Disk> = SpinLock::new(
+    "VIRTIO_BLK",
+    Disk {
+        transport: None,
+        queue: VirtQueue::new(),
+        slots: [Slot {
+            header: RequestHeader {
+                kind: 0,
+                reserved: 0,
+                sector: 0,
+            },
+            status: 0,
+            busy: false,
+        }; QUEUE_SIZE],
+        features: 0,
+        capacity: 0,
+    },
+);
+
+pub struct BlkDriver;
+
+pub static DRIVER: BlkDriver = BlkDriver;
+
+impl Driver for BlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        super::COMPATIBLE
+    }
+
+    fn class(&self) -> DeviceClass {
+        DeviceClass::Block {
+            major: dev::MAJOR_VIRTIO_BLK,
+        }
+    }
+
+    /// Only takes the first disk.
+    fn probe(&self, node: &Node<'static>) -> Result<(), ProbeError> {
+        let regs = node.reg().next().ok_or(ProbeError::MissingRegs)?;
+        let irq = node.interrupts().next().ok_or(ProbeError::MissingIrq)?;
+        let transport = Transport::new(regs)?;
+        if transport.device_id() != Some(DeviceId::Block) {
+            return Err(ProbeError::NotPresent);
+        }
+        let mut disk = DISK.lock();
+        if disk.transport.is_some() {
+            return Err(ProbeError::Unsupported);
+        }
+
+        let features = transport.begin_init(F_RO | F_FLUSH)?;
+        if let Err(error) = transport.setup_queue(0, &disk.queue) {
+            transport.fail();
+            return Err(error);
+        }
+        disk.features = features;
+        disk.capacity = transport.read_config_u64(CONFIG_CAPACITY);
+        disk.transport = Some(transport);
+        irq::register(irq, handle_irq);
+        transport.driver_ok();
+        info!(
+            "virtio-blk: {} sectors ({}KiB){}",
+            disk.capacity,
+            disk.capacity * SECTOR_SIZE as u64 / 1024,
+            if features & F_RO != 0 {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut disk = DISK.lock();
+        if let Some(transport) = disk.transport.take() {
+            transport.reset();
+        }
+        if let Some(irq) = device.irq {
+            irq::unregister(irq);
+        }
+        disk.capacity = 0;
+    }
+}
+
+fn handle_irq() {
+    let mut disk = DISK.lock();
+    if let Some(transport) = disk.transport {
+        transport.ack_interrupt();
+    }
+    disk.queue.collect_used();
+}
+
+/// Waits for the device to use something, by sleeping until its interrupt
+/// if `blockable` (see [`proc::blockable_pid`]), else by polling the queue.
+fn wait(
+    mut disk: SpinLockGuard<'static, Disk>,
+    blockable: Option<PID>,
+) -> SpinLockGuard<'static, Disk> {
+    match blockable {
+        Some(me) => {
+            disk.queue.waiters.push(me);
+            proc::block(disk);
+            let mut disk = DISK.lock();
+            disk.queue.waiters.remove(me);
+            disk
+        }
+        None => {
+            while disk.queue.collect_used() == 0 {
+                spin_loop();
+            }
+            disk
+        }
+    }
+}
+
+/// Runs a request through the device, with `data` as its only buffer.
+fn request(kind: u32, sector: u64, data: Option<Buffer>) -> Result<(), BlkError> {
+    let blockable = proc::blockable_pid();
+    let mut disk = DISK.lock();
+    let transport = disk.transport.ok_or(BlkError::NoDevice)?;
+    let descriptors = if data.is_some() { 3 } else { 2 };
+    let slot = loop {
+        let free = disk.slots.iter().position(|slot| !slot.busy);
+        match free {
+            Some(slot) if disk.queue.num_free() >= descriptors => break slot,
+            _ => disk = wait(disk, blockable),
+        }
+    };
+
+    let disk_ref = &mut *disk;
+    let slot_ref = &mut disk_ref.slots[slot];
+    *slot_ref = Slot {
+        header: RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        },
+        // Overwritten by the device
+        status: u8::MAX,
+        busy: true,
+    };
+    let header = Buffer::readable(unsafe {
+        core::slice::from_raw_parts(
+            &raw const slot_ref.header as *const u8,
+            size_of::<RequestHeader>(),
+        )
+    });
+    let status = Buffer::writable(core::slice::from_mut(&mut slot_ref.status));
+    let head = match data {
+        Some(data) => disk_ref.queue.add(&[header, data, status]),
+        None => disk_ref.queue.add(&[header, status]),
+    }
+    .expect("descriptors should be free");
+    transport.notify(0);
+
+    while disk.queue.take_done(head).is_none() {
+        disk = wait(disk, blockable);
+    }
+    let slot = &mut disk.slots[slot];
+    slot.busy = false;
+    match slot.status {
+        S_OK => Ok(()),
+        S_UNSUPP => Err(BlkError::Unsupported),
+        _ => Err(BlkError::Io),
+    }
+}
+
+/// Checks that `len` bytes from `sector` are whole sectors on the disk.
+fn check_range(sector: u64, len: usize) -> Result<(), BlkError> {
+    let capacity = capacity().ok_or(BlkError::NoDevice)?;
+    let sectors = (len / SECTOR_SIZE) as u64;
+    if !len.is_multiple_of(SECTOR_SIZE)
+        || sector.checked_add(sectors).is_none_or(|end| end > capacity)
+    {
+        return Err(BlkError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Size of the disk in sectors, if there's one.
+pub fn capacity() -> Option<u64> {
+    let disk = DISK.lock();
+    disk.transport.map(|_| disk.capacity)
+}
+
+pub fn is_read_only() -> bool {
+    DISK.lock().features & F_RO != 0
+}
+
+/// Reads whole sectors from `sector` on into `buf`.
+pub fn read_sectors(sector: u64, buf: &mut [u8]) -> Result<(), BlkError> {
+    check_range(sector, buf.len())?;
+    request(T_IN, sector, Some(Buffer::writable(buf)))
+}
+
+/// Writes `buf`, whole sectors, from `sector` on.
+pub fn write_sectors(sector: u64, buf: &[u8]) -> Result<(), BlkError> {
+    check_range(sector, buf.len())?;
+    if is_read_only() {
+        return Err(BlkError::ReadOnly);
+    }
+    request(T_OUT, sector, Some(Buffer::readable(buf)))
+}
+
+pub fn read_block(block: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), BlkError> {
+    read_sectors(block * SECTORS_PER_BLOCK, buf)
+}
+
+pub fn write_block(block: u64, buf: &[u8; BLOCK_SIZE]) -> Result<(), BlkError> {
+    write_sectors(block * SECTORS_PER_BLOCK, buf)
+}
+
+/// Makes sure writes so far reach the disk, rather than just its cache.
+/// Devices without a cache to flush write through anyway.
+pub fn flush() -> Result<(), BlkError> {
+    let features = DISK.lock().features;
+    match features & F_FLUSH {
+        0 => capacity().map(|_| ()).ok_or(BlkError::NoDevice),
+        _ => request(T_FLUSH, 0, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proc::PROCESSES, test::TEST_QUANTA, utils::sync::Mutex};
+
+    // Blocks don't fit on process stacks, nor comfortably on the boot one
+    static SAVED: SpinLock<[u8; BLOCK_SIZE]> = SpinLock::new("TEST_BLK_SAVED", [0; BLOCK_SIZE]);
+    static DATA: SpinLock<[u8; BLOCK_SIZE]> = SpinLock::new("TEST_BLK_DATA", [0; BLOCK_SIZE]);
+    static READBACK: SpinLock<[u8; BLOCK_SIZE]> =
+        SpinLock::new("TEST_BLK_READBACK", [0; BLOCK_SIZE]);
+
+    /// Last block, out of the way of anything else on the disk.
+    fn scratch_block() -> u64 {
+        capacity().expect("the test runner should attach a disk") / SECTORS_PER_BLOCK - 1
+    }
+
+    #[test_case]
+    pub fn virtio_blk_read_write() {
+        let block = scratch_block();
+        let mut saved = SAVED.lock();
+        read_block(block, &mut saved).unwrap();
+
+        let mut data = DATA.lock();
+        data.iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = (i % 251) as u8);
+        write_block(block, &data).unwrap();
+        flush().unwrap();
+        let mut readback = READBACK.lock();
+        read_block(block, &mut readback).unwrap();
+        assert!(*readback == *data);
+        // Sector by sector too
+        for (i, expected) in data.chunks(SECTOR_SIZE).enumerate() {
+            let sector = &mut readback[..SECTOR_SIZE];
+            sector.fill(0);
+            read_sectors(block * SECTORS_PER_BLOCK + i as u64, sector).unwrap();
+            assert!(sector == expected);
+        }
+
+        assert_eq!(
+            read_sectors(block * SECTORS_PER_BLOCK, &mut readback[..100]),
+            Err(BlkError::OutOfRange)
+        );
+        assert_eq!(read_block(block + 1, &mut data), Err(BlkError::OutOfRange));
+        write_block(block, &saved).unwrap();
+    }
+
+    const READERS: usize = 4;
+    /// One per reader, which may sleep holding it.
+    static READ_BUFFERS: [Mutex<[u8; BLOCK_SIZE]>; READERS] =
+        [const { Mutex::new("TEST_BLK_READ", [0; BLOCK_SIZE]) }; READERS];
+
+    fn reader() {
+        let mut buf = READ_BUFFERS
+            .iter()
+            .find_map(|buf| buf.try_lock().ok())
+            .unwrap();
+        read_block(scratch_block(), &mut buf).unwrap();
+    }
+
+    #[test_case]
+    pub fn virtio_blk_concurrent_requests() {
+        for _ in 0..READERS {
+            PROCESSES.create(reader);
+        }
+        proc::run_until_exit(TEST_QUANTA);
+        assert_eq!(DISK.lock().queue.num_free(), QUEUE_SIZE);
+        assert!(DISK.lock().slots.iter().all(|slot| !slot.busy));
+    }
+}
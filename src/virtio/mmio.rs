@@ -0,0 +1,267 @@
+//! Virtio MMIO transport, both the legacy (version 1) one QEMU defaults
+//! to and the modern (version 2) one it offers with
+//! `-global virtio-mmio.force-legacy=false`.
+
+use super::{
+    DeviceId, F_VERSION_1, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED,
+    STATUS_FEATURES_OK, queue::VirtQueue,
+};
+use crate::{dev::ProbeError, fdt::Region};
+
+/// "virt", little-endian.
+const MAGIC: u32 = 0x7472_6976;
+const VERSION_LEGACY: u32 = 1;
+const VERSION_MODERN: u32 = 2;
+/// Page size legacy devices find queues in.
+const LEGACY_PAGE_SIZE: u32 = 4096;
+
+// Register offsets. Legacy devices only have the ones not marked modern.
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const LEGACY_GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const LEGACY_QUEUE_ALIGN: usize = 0x03c;
+const LEGACY_QUEUE_PFN: usize = 0x040;
+/// Modern.
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+// Modern, as low then high 32 bits
+const QUEUE_DESC: usize = 0x080;
+const QUEUE_DRIVER: usize = 0x090;
+const QUEUE_DEVICE: usize = 0x0a0;
+/// Modern: changes whenever the device changes its configuration.
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// Interrupt status bits.
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/// A device's MMIO registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    base: usize,
+    version: u32,
+}
+
+impl Transport {
+    /// Checks for a device at `regs`, failing with
+    /// [`ProbeError::NotPresent`] for an empty slot.
+    pub fn new(regs: Region) -> Result<Self, ProbeError> {
+        let transport = Self {
+            base: regs.base,
+            version: 0,
+        };
+        if transport.read(MAGIC_VALUE) != MAGIC {
+            return Err(ProbeError::Unsupported);
+        }
+        let version = transport.read(VERSION);
+        if version != VERSION_LEGACY && version != VERSION_MODERN {
+            return Err(ProbeError::Unsupported);
+        }
+        if transport.read(DEVICE_ID) == 0 {
+            return Err(ProbeError::NotPresent);
+        }
+        Ok(Self {
+            version,
+            ..transport
+        })
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.reg(offset).write_volatile(value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == VERSION_LEGACY
+    }
+
+    /// `None` for device types without a [`DeviceId`] yet.
+    pub fn device_id(&self) -> Option<DeviceId> {
+        DeviceId::from_raw(self.read(DEVICE_ID))
+    }
+
+    pub fn status(&self) -> u32 {
+        self.read(STATUS)
+    }
+
+    fn add_status(&self, bits: u32) {
+        self.write(STATUS, self.status() | bits);
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES);
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    /// Resets the device and negotiates features: those in `supported`
+    /// the device offers, plus [`F_VERSION_1`] for modern devices.
+    /// Returns the features agreed on; queues are set up next, then
+    /// [`Transport::driver_ok`] starts the device.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, ProbeError> {
+        self.write(STATUS, 0);
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let offered = self.device_features();
+        let supported = match self.is_legacy() {
+            true => supported & !F_VERSION_1,
+            false => supported | F_VERSION_1,
+        };
+        let features = offered & supported;
+        if !self.is_legacy() && features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err(ProbeError::Unsupported);
+        }
+        self.set_driver_features(features);
+
+        if self.is_legacy() {
+            self.write(LEGACY_GUEST_PAGE_SIZE, LEGACY_PAGE_SIZE);
+        } else {
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(ProbeError::Unsupported);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Hands queue `index` to the device, which keeps using its address:
+    /// `queue` must be in a static.
+    pub fn setup_queue<const SIZE: usize>(
+        &self,
+        index: u32,
+        queue: &VirtQueue<SIZE>,
+    ) -> Result<(), ProbeError> {
+        self.write(QUEUE_SEL, index);
+        let in_use = match self.is_legacy() {
+            true => self.read(LEGACY_QUEUE_PFN) != 0,
+            false => self.read(QUEUE_READY) != 0,
+        };
+        // A maximum of 0 means there's no such queue
+        if in_use || (self.read(QUEUE_NUM_MAX) as usize) < SIZE {
+            return Err(ProbeError::Unsupported);
+        }
+        self.write(QUEUE_NUM, SIZE as u32);
+
+        if self.is_legacy() {
+            self.write(LEGACY_QUEUE_ALIGN, LEGACY_PAGE_SIZE);
+            let pfn = queue.desc_addr() / LEGACY_PAGE_SIZE as usize;
+            self.write(LEGACY_QUEUE_PFN, pfn as u32);
+        } else {
+            self.write_u64(QUEUE_DESC, queue.desc_addr() as u64);
+            self.write_u64(QUEUE_DRIVER, queue.avail_addr() as u64);
+            self.write_u64(QUEUE_DEVICE, queue.used_addr() as u64);
+            self.write(QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    /// Lets the device go, once its queues are set up.
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device its driver gave up on it.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Stops the device, e.g. when it's removed.
+    pub fn reset(&self) {
+        self.write(STATUS, 0);
+    }
+
+    /// Tells the device queue `index` has new buffers.
+    pub fn notify(&self, index: u32) {
+        self.write(QUEUE_NOTIFY, index);
+    }
+
+    /// Takes the pending interrupt's `INTERRUPT_*` bits.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.is_legacy() {
+            true => 0,
+            false => self.read(CONFIG_GENERATION),
+        }
+    }
+
+    /// Reads the 32-bit device-specific configuration field at `offset`.
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    /// Reads the 64-bit device-specific configuration field at `offset`,
+    /// retrying if the device changed it halfway through.
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read(CONFIG + offset);
+            let high = self.read(CONFIG + offset + 4);
+            if self.config_generation() == generation {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fdt, virtio::COMPATIBLE};
+
+    #[test_case]
+    pub fn virtio_mmio_slots() {
+        let mut slots = 0;
+        fdt::get().unwrap().for_each_node(|node| {
+            if !COMPATIBLE.iter().any(|name| node.is_compatible(name)) {
+                return;
+            }
+            slots += 1;
+            match Transport::new(node.reg().next().unwrap()) {
+                Ok(transport) => assert!(transport.status() & STATUS_FAILED == 0),
+                Err(error) => assert_eq!(error, ProbeError::NotPresent),
+            }
+        });
+        assert_eq!(slots, 8, "QEMU virt has 8 virtio-mmio slots");
+    }
+}
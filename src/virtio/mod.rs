@@ -0,0 +1,49 @@
+//! Virtio devices over MMIO, as on QEMU virt: eight transport slots from
+//! `0x10001000`, one page apart, listed in the device tree as
+//! `virtio,mmio` nodes whether or not a device is plugged in.
+//!
+//! Device drivers bind to `virtio,mmio` nodes, and tell theirs apart by
+//! [`DeviceId`], probing the rest as absent. Each sets its device up
+//! through [`mmio::Transport`], then exchanges buffers with it over
+//! [`queue::VirtQueue`]s.
+
+pub mod blk;
+pub mod mmio;
+pub mod queue;
+
+pub const COMPATIBLE: &[&str] = &["virtio,mmio"];
+
+/// Kind of device behind a transport.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceId {
+    Network = 1,
+    Block = 2,
+    Console = 3,
+    Entropy = 4,
+}
+
+impl DeviceId {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(Self::Network),
+            2 => Some(Self::Block),
+            3 => Some(Self::Console),
+            4 => Some(Self::Entropy),
+            _ => None,
+        }
+    }
+}
+
+// Device status bits, set in order as initialization goes.
+pub const STATUS_ACKNOWLEDGE: u32 = 1 << 0;
+pub const STATUS_DRIVER: u32 = 1 << 1;
+pub const STATUS_DRIVER_OK: u32 = 1 << 2;
+pub const STATUS_FEATURES_OK: u32 = 1 << 3;
+pub const STATUS_FAILED: u32 = 1 << 7;
+
+/// Feature bits every device type has.
+pub const F_INDIRECT_DESC: u64 = 1 << 28;
+pub const F_EVENT_IDX: u64 = 1 << 29;
+/// Non-legacy device, required by non-legacy transports.
+pub const F_VERSION_1: u64 = 1 << 32;
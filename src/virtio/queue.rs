@@ -0,0 +1,350 @@
+//! Split virtqueue: a descriptor table of buffers, a ring the driver
+//! makes chains of them available in, and one the device returns them
+//! used in. The whole queue lives in a static, as the device keeps its
+//! address; its layout suits both legacy and modern transports.
+
+use core::{
+    ptr,
+    sync::atomic::{Ordering, fence},
+};
+
+use crate::utils::sync::WaitQueue;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+/// Written by the device, rather than read.
+const DESC_F_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Descriptor {
+    const EMPTY: Self = Self {
+        addr: 0,
+        len: 0,
+        flags: 0,
+        next: 0,
+    };
+}
+
+#[repr(C)]
+struct AvailRing<const SIZE: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [u16; SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    /// Head of the chain used.
+    id: u32,
+    /// Bytes written into the chain.
+    len: u32,
+}
+
+/// Page-aligned, which puts it where legacy devices expect it: on the
+/// page after the available ring.
+#[repr(C, align(4096))]
+struct UsedRing<const SIZE: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; SIZE],
+    avail_event: u16,
+}
+
+#[repr(C, align(4096))]
+struct Rings<const SIZE: usize> {
+    desc: [Descriptor; SIZE],
+    avail: AvailRing<SIZE>,
+    used: UsedRing<SIZE>,
+}
+
+/// Buffer in a chain, which must stay put until the chain is used.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    addr: usize,
+    len: u32,
+    device_writable: bool,
+}
+
+impl Buffer {
+    /// Buffer the device reads.
+    pub fn readable(buffer: &[u8]) -> Self {
+        Self {
+            addr: buffer.as_ptr() as usize,
+            len: buffer.len() as u32,
+            device_writable: false,
+        }
+    }
+
+    /// Buffer the device writes into.
+    pub fn writable(buffer: &mut [u8]) -> Self {
+        Self {
+            addr: buffer.as_mut_ptr() as usize,
+            len: buffer.len() as u32,
+            device_writable: true,
+        }
+    }
+}
+
+/// Split virtqueue of `SIZE` descriptors, a power of two.
+pub struct VirtQueue<const SIZE: usize> {
+    rings: Rings<SIZE>,
+    /// Free descriptors, chained through `next`.
+    free_head: u16,
+    num_free: usize,
+    /// Used ring entries seen so far.
+    last_used: u16,
+    /// Bytes written by the device into each used chain, by head, until
+    /// taken by whoever is waiting for it. The chain stays allocated
+    /// until then, so its head can't be handed out to anyone else.
+    done: [Option<u32>; SIZE],
+    /// Processes waiting for a chain to be used, or descriptors to free.
+    pub waiters: WaitQueue,
+}
+
+impl<const SIZE: usize> VirtQueue<SIZE> {
+    pub const fn new() -> Self {
+        assert!(SIZE.is_power_of_two() && SIZE <= u16::MAX as usize);
+        let mut desc = [Descriptor::EMPTY; SIZE];
+        let mut i = 0;
+        while i < SIZE {
+            desc[i].next = (i + 1) as u16;
+            i += 1;
+        }
+        Self {
+            rings: Rings {
+                desc,
+                avail: AvailRing {
+                    flags: 0,
+                    idx: 0,
+                    ring: [0; SIZE],
+                    used_event: 0,
+                },
+                used: UsedRing {
+                    flags: 0,
+                    idx: 0,
+                    ring: [UsedElem { id: 0, len: 0 }; SIZE],
+                    avail_event: 0,
+                },
+            },
+            free_head: 0,
+            num_free: SIZE,
+            last_used: 0,
+            done: [None; SIZE],
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn desc_addr(&self) -> usize {
+        &raw const self.rings.desc as usize
+    }
+
+    pub fn avail_addr(&self) -> usize {
+        &raw const self.rings.avail as usize
+    }
+
+    pub fn used_addr(&self) -> usize {
+        &raw const self.rings.used as usize
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    /// Makes `buffers` available to the device as one chain, returning
+    /// the head identifying it, or `None` if there aren't enough free
+    /// descriptors. The device only looks once notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free {
+            return None;
+        }
+        let head = self.free_head;
+        let mut last = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = &mut self.rings.desc[last as usize];
+            desc.addr = buffer.addr as u64;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+                last = desc.next;
+            }
+        }
+        self.free_head = self.rings.desc[last as usize].next;
+        self.num_free -= buffers.len();
+        self.done[head as usize] = None;
+
+        let avail = &raw mut self.rings.avail;
+        unsafe {
+            let idx = (&raw const (*avail).idx).read_volatile();
+            (&raw mut (*avail).ring[idx as usize % SIZE]).write_volatile(head);
+            // The device mustn't see the new index before the entry
+            fence(Ordering::SeqCst);
+            (&raw mut (*avail).idx).write_volatile(idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut desc = head;
+        loop {
+            self.num_free += 1;
+            let flags = self.rings.desc[desc as usize].flags;
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            desc = self.rings.desc[desc as usize].next;
+        }
+        self.rings.desc[desc as usize].next = self.free_head;
+        self.free_head = head;
+    }
+
+    /// Records the chains the device used since last time as done, and
+    /// wakes waiters, e.g. on a used buffer interrupt. Returns how many
+    /// there were.
+    pub fn collect_used(&mut self) -> usize {
+        let mut count = 0;
+        loop {
+            fence(Ordering::SeqCst);
+            let used_idx = unsafe { (&raw const self.rings.used.idx).read_volatile() };
+            if self.last_used == used_idx {
+                break;
+            }
+            let elem = unsafe {
+                ptr::read_volatile(&raw const self.rings.used.ring[self.last_used as usize % SIZE])
+            };
+            self.last_used = self.last_used.wrapping_add(1);
+            self.done[elem.id as usize] = Some(elem.len);
+            count += 1;
+        }
+        if count > 0 {
+            self.waiters.wake_all();
+        }
+        count
+    }
+
+    /// Takes the bytes written into the chain at `head`, if it was used,
+    /// freeing it and waking whoever waits for descriptors.
+    pub fn take_done(&mut self, head: u16) -> Option<u32> {
+        let len = self.done[head as usize].take()?;
+        self.free_chain(head);
+        self.waiters.wake_all();
+        Some(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sync::SpinLock;
+
+    static QUEUE: SpinLock<VirtQueue<4>> = SpinLock::new("TEST_VIRTQUEUE", VirtQueue::new());
+
+    /// Plays the device, using the chain at `head`.
+    fn use_chain(queue: &mut VirtQueue<4>, head: u16, len: u32) {
+        let used = &mut queue.rings.used;
+        used.ring[used.idx as usize % 4] = UsedElem {
+            id: head as u32,
+            len,
+        };
+        used.idx = used.idx.wrapping_add(1);
+    }
+
+    #[test_case]
+    pub fn virtqueue_chains() {
+        let mut queue = QUEUE.lock();
+        assert_eq!(queue.avail_addr() - queue.desc_addr(), 4 * 16);
+        assert_eq!(
+            queue.used_addr() % 4096,
+            0,
+            "legacy devices expect it aligned"
+        );
+
+        let header = [0u8; 16];
+        let mut data = [0u8; 512];
+        let mut status = [0u8; 1];
+        let chain = [
+            Buffer::readable(&header),
+            Buffer::writable(&mut data),
+            Buffer::writable(&mut status),
+        ];
+        let first = queue.add(&chain).unwrap();
+        assert_eq!(queue.num_free(), 1);
+        assert!(queue.add(&chain).is_none(), "only one descriptor is left");
+        let desc = queue.rings.desc;
+        assert_eq!(desc[first as usize].flags, DESC_F_NEXT);
+        let second = desc[first as usize].next as usize;
+        assert_eq!(desc[second].len, 512);
+        assert_eq!(desc[second].flags, DESC_F_NEXT | DESC_F_WRITE);
+        assert_eq!(desc[desc[second].next as usize].flags, DESC_F_WRITE);
+        assert_eq!(queue.rings.avail.idx, 1);
+        assert_eq!(queue.rings.avail.ring[0], first);
+
+        let single = queue.add(&chain[..1]).unwrap();
+        assert_eq!(queue.collect_used(), 0);
+        use_chain(&mut queue, first, 513);
+        assert_eq!(queue.collect_used(), 1);
+        assert_eq!(queue.num_free(), 0, "chains are freed once taken");
+        assert_eq!(queue.take_done(first), Some(513));
+        assert_eq!(queue.num_free(), 3);
+        assert_eq!(queue.take_done(first), None);
+        assert_eq!(queue.take_done(single), None);
+
+        use_chain(&mut queue, single, 0);
+        assert_eq!(queue.collect_used(), 1);
+        assert_eq!(queue.take_done(single), Some(0));
+        assert_eq!(queue.num_free(), 4);
+        let head = queue.add(&chain).expect("descriptors should be reused");
+        // Leaves the queue empty for the next test
+        use_chain(&mut queue, head, 0);
+        queue.collect_used();
+        queue.take_done(head);
+    }
+
+    #[test_case]
+    pub fn virtqueue_out_of_order() {
+        let mut queue = QUEUE.lock();
+        let header = [0u8; 16];
+        let mut status = [[0u8; 1]; 3];
+        let [a, b, c] = &mut status;
+        let first = queue
+            .add(&[Buffer::readable(&header), Buffer::writable(a)])
+            .unwrap();
+        let second = queue
+            .add(&[Buffer::readable(&header), Buffer::writable(b)])
+            .unwrap();
+
+        // The second request finishes first, and its requester takes it
+        use_chain(&mut queue, second, 1);
+        assert_eq!(queue.collect_used(), 1);
+        assert_eq!(queue.take_done(second), Some(1));
+
+        // The first finishes before its requester gets to look
+        use_chain(&mut queue, first, 2);
+        assert_eq!(queue.collect_used(), 1);
+        let third = queue
+            .add(&[Buffer::readable(&header), Buffer::writable(c)])
+            .unwrap();
+        assert_ne!(third, first, "a head should only be reused once taken");
+        assert_eq!(queue.take_done(first), Some(2));
+        assert_eq!(queue.take_done(third), None);
+
+        use_chain(&mut queue, third, 1);
+        queue.collect_used();
+        assert_eq!(queue.take_done(third), Some(1));
+        assert_eq!(queue.num_free(), 4);
+    }
+}